@@ -0,0 +1,237 @@
+// Procedural macros for nex-rs, re-exported by the nex crate with its macros feature
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, parse_quote, Error, FnArg, Ident, ItemTrait, LitInt, LitStr, ReturnType, TraitItem, TraitItemFn, Type};
+
+// Turns a trait into a typed NEX protocol definition:
+//
+//	#[nex_protocol(id = 0x0A)]
+//	pub trait TicketGranting: Send + Sync {
+//		#[method(1)]
+//		async fn login(&self, ctx: &RmcContext<'_>, username: String) -> NexResult<(QResult, Pid, Buffer, RvConnectionData, String)>;
+//	}
+//
+// Every method takes the RmcContext of the call after self, followed by the
+// request parameters, and returns its response values. Parameters and response
+// values are read and written with RvType, several response values as a tuple.
+// Besides the trait, a TicketGrantingProtocol<T> wrapper is generated, which
+// implements ServiceProtocol for any T implementing the trait and can be
+// registered on an endpoint. Its protocol_info describes the methods for a
+// ProtocolRegistry
+//
+// Verbose RMC names the protocol and methods. The protocol name defaults to the
+// name of the trait and method names to the method name in PascalCase, both can
+// be changed with #[nex_protocol(id = 0x0A, name = "...")] and #[method(1, name = "...")]
+#[proc_macro_attribute]
+pub fn nex_protocol(attr: TokenStream, item: TokenStream) -> TokenStream {
+	let mut protocol = parse_macro_input!(item as ItemTrait);
+	let mut protocol_id = None;
+	let mut protocol_name = None;
+	let parser = syn::meta::parser(|meta| {
+		if meta.path.is_ident("id") {
+			protocol_id = Some(meta.value()?.parse::<LitInt>()?);
+		} else if meta.path.is_ident("name") {
+			protocol_name = Some(meta.value()?.parse::<LitStr>()?);
+		} else {
+			return Err(meta.error("expected `id` or `name`"));
+		}
+		Ok(())
+	});
+	parse_macro_input!(attr with parser);
+
+	let Some(protocol_id) = protocol_id else {
+		return Error::new(Span::call_site(), "missing protocol `id`").to_compile_error().into();
+	};
+	match expand(&mut protocol, protocol_id, protocol_name) {
+		Ok(tokens) => tokens.into(),
+		Err(err) => err.to_compile_error().into(),
+	}
+}
+
+struct Method {
+	id: LitInt,
+	name: String,
+	ident: Ident,
+	is_async: bool,
+	parameters: Vec<(String, Type)>,
+	// T of a NexResult<T> or Result<T, E> return type
+	response: Option<Type>,
+}
+
+fn expand(protocol: &mut ItemTrait, protocol_id: LitInt, protocol_name: Option<LitStr>) -> syn::Result<TokenStream2> {
+	let mut methods: Vec<Method> = Vec::new();
+	for item in &mut protocol.items {
+		let TraitItem::Fn(function) = item else { continue };
+		let method = parse_method(function)?;
+		if methods.iter().any(|other| other.id.base10_digits() == method.id.base10_digits()) {
+			return Err(Error::new(method.id.span(), format!("method id {} is used twice", method.id)));
+		}
+		methods.push(method);
+	}
+
+	let vis = &protocol.vis;
+	let trait_ident = &protocol.ident;
+	let wrapper = format_ident!("{}Protocol", trait_ident);
+	let protocol_name = protocol_name.map(|name| name.value()).unwrap_or_else(|| trait_ident.to_string());
+
+	let method_consts = methods.iter().map(|method| {
+		let name = format_ident!("METHOD_{}", method.ident.to_string().to_uppercase());
+		let id = &method.id;
+		quote! { pub const #name: u32 = #id; }
+	});
+	let method_names = methods.iter().map(|method| {
+		let (id, name) = (&method.id, &method.name);
+		quote! { #name => ::std::option::Option::Some(#id), }
+	});
+	let method_infos = methods.iter().map(|method| {
+		let (id, name) = (&method.id, &method.name);
+		let names = method.parameters.iter().map(|(name, _)| name);
+		let types = method.parameters.iter().map(|(_, ty)| ty);
+		let response = method.response.as_ref().map(|ty| quote! { .response::<#ty>(&[]) });
+		quote! { .method(::nex::protocol_registry::MethodInfo::new(#id, #name).request::<(#(#types,)*)>(&[#(#names),*])#response) }
+	});
+	let method_ids: Vec<&LitInt> = methods.iter().map(|method| &method.id).collect();
+	let has_method = if method_ids.is_empty() { quote! { { let _ = method_id; false } } } else { quote! { matches!(method_id, #(#method_ids)|*) } };
+	let method_arms = methods.iter().map(|method| {
+		let (id, ident) = (&method.id, &method.ident);
+		// Parameters are bound to generated names, so they can not shadow the locals used here
+		let names: Vec<Ident> = (0..method.parameters.len()).map(|index| format_ident!("parameter_{}", index)).collect();
+		let types = method.parameters.iter().map(|(_, ty)| ty);
+		let call = if method.is_async { quote! { self.0.#ident(&ctx, #(#names),*).await } } else { quote! { self.0.#ident(&ctx, #(#names),*) } };
+		quote! {
+			#id => {
+				#(let #names: #types = ::nex::types::rv_type::RvType::read_rv(&mut input).map_err(|err| ctx.invalid_argument(err))?;)*
+				let response = #call?;
+				let mut out = ctx.byte_stream_out();
+				::nex::types::rv_type::RvType::write_rv(&response, &mut out);
+				out.bytes().to_vec()
+			}
+		}
+	});
+
+	Ok(quote! {
+		#protocol
+
+		#vis struct #wrapper<T>(pub T);
+
+		impl<T> #wrapper<T> {
+			pub const PROTOCOL_ID: u16 = #protocol_id;
+			#(#method_consts)*
+
+			pub fn new(protocol: T) -> Self { Self(protocol) }
+
+			// The names and parameter types of the protocol, for a ProtocolRegistry
+			pub fn protocol_info() -> ::nex::protocol_registry::ProtocolInfo {
+				::nex::protocol_registry::ProtocolInfo::new(#protocol_id, #protocol_name)#(#method_infos)*
+			}
+		}
+
+		impl<T: #trait_ident + ::std::marker::Send + ::std::marker::Sync> ::nex::service_protocol::ServiceProtocol for #wrapper<T> {
+			fn protocol_id(&self) -> u16 { #protocol_id }
+
+			fn protocol_name(&self) -> &str { #protocol_name }
+
+			fn method_id(&self, method_name: &str) -> ::std::option::Option<u32> {
+				match method_name {
+					#(#method_names)*
+					_ => ::std::option::Option::None,
+				}
+			}
+
+			fn has_method(&self, method_id: u32) -> bool {
+				#has_method
+			}
+
+			fn handle<'a>(&'a self, request: &'a ::nex::rmc_message::RmcMessage, connection: &'a ::std::sync::Arc<::nex::prudp::connection::PrudpConnection>) -> ::nex::service_protocol::ProtocolFuture<'a> {
+				::std::boxed::Box::pin(async move {
+					let ctx = ::nex::service_protocol::RmcContext::new(request, connection);
+					#[allow(unused_mut, unused_variables)]
+					let mut input = ctx.byte_stream_in(request.parameters.clone());
+					let parameters = match request.method_id {
+						#(#method_arms)*
+						_ => return ::std::result::Result::Err(::nex::service_protocol::not_implemented(request)),
+					};
+					::std::result::Result::Ok(::nex::rmc_message::RmcMessage::success(request, parameters))
+				})
+			}
+		}
+	})
+}
+
+// Reads and removes the #[method] attribute, and turns an async method into one
+// returning a Send future, which the ServiceProtocol glue needs
+fn parse_method(function: &mut TraitItemFn) -> syn::Result<Method> {
+	let position = function.attrs.iter().position(|attr| attr.path().is_ident("method"))
+		.ok_or_else(|| Error::new(function.sig.ident.span(), "protocol methods need a #[method(id)] attribute"))?;
+	let attr = function.attrs.remove(position);
+	// #[method(1)] and #[method(1, name = "...")]
+	let (id, name) = attr.parse_args_with(|input: syn::parse::ParseStream| {
+		let id = input.parse::<LitInt>()?;
+		let mut name = None;
+		if input.parse::<Option<syn::Token![,]>>()?.is_some() && !input.is_empty() {
+			let key: Ident = input.parse()?;
+			if key != "name" { return Err(Error::new(key.span(), "expected `name`")); }
+			input.parse::<syn::Token![=]>()?;
+			name = Some(input.parse::<LitStr>()?.value());
+		}
+		Ok((id, name))
+	})?;
+
+	let sig = &mut function.sig;
+	let mut inputs = sig.inputs.iter();
+	if !matches!(inputs.next(), Some(FnArg::Receiver(receiver)) if receiver.reference.is_some() && receiver.mutability.is_none()) {
+		return Err(Error::new(sig.span(), "protocol methods take &self"));
+	}
+	if !matches!(inputs.next(), Some(FnArg::Typed(_))) {
+		return Err(Error::new(sig.span(), "protocol methods take the RmcContext after &self"));
+	}
+	let mut parameters = Vec::new();
+	for input in inputs {
+		let FnArg::Typed(input) = input else { unreachable!() };
+		let name = match &*input.pat {
+			syn::Pat::Ident(pattern) => pattern.ident.to_string(),
+			_ => String::new(),
+		};
+		parameters.push((name, (*input.ty).clone()));
+	}
+
+	let response = match &sig.output {
+		ReturnType::Type(_, ty) => result_type(ty),
+		ReturnType::Default => None,
+	};
+	let is_async = sig.asyncness.take().is_some();
+	if is_async {
+		let output: Type = match &sig.output {
+			ReturnType::Default => parse_quote!(()),
+			ReturnType::Type(_, ty) => (**ty).clone(),
+		};
+		sig.output = parse_quote!(-> impl ::std::future::Future<Output = #output> + ::std::marker::Send);
+		if let Some(block) = &function.default {
+			function.default = Some(parse_quote!({ async move #block }));
+		}
+	}
+
+	let ident = sig.ident.clone();
+	let name = name.unwrap_or_else(|| pascal_case(&ident.to_string()));
+	Ok(Method { id, name, ident, is_async, parameters, response })
+}
+
+fn result_type(ty: &Type) -> Option<Type> {
+	let Type::Path(path) = ty else { return None };
+	let segment = path.path.segments.last()?;
+	if segment.ident != "NexResult" && segment.ident != "Result" { return None; }
+	let syn::PathArguments::AngleBracketed(arguments) = &segment.arguments else { return None };
+	arguments.args.iter().find_map(|argument| match argument {
+		syn::GenericArgument::Type(ty) => Some(ty.clone()),
+		_ => None,
+	})
+}
+
+fn pascal_case(name: &str) -> String {
+	name.split('_').filter(|part| !part.is_empty()).map(|part| {
+		let mut chars = part.chars();
+		chars.next().map(|first| first.to_uppercase().chain(chars).collect::<String>()).unwrap_or_default()
+	}).collect()
+}
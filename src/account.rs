@@ -0,0 +1,22 @@
+use crate::types::pid::Pid;
+
+// A game server account. These only exist on the game server and are separate
+// from Nintendo Accounts, NNIDs and the like. Account passwords are used as part
+// of the servers Kerberos authentication. Besides user accounts there are a few
+// special accounts, such as a guest account and ones representing the
+// authentication and secure servers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Account {
+	pub pid: Pid,
+	// For NEX user accounts this is the same as the PID
+	pub username: String,
+	pub password: String,
+	// Always false for special accounts or user accounts before the Switch
+	pub requires_token_auth: bool,
+}
+
+impl Account {
+	pub fn new(pid: Pid, username: impl Into<String>, password: impl Into<String>, requires_token_auth: bool) -> Self {
+		Self { pid, username: username.into(), password: password.into(), requires_token_auth }
+	}
+}
@@ -0,0 +1,83 @@
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Notify;
+
+pub type ClockFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+// The source of time for every timer: retransmissions, heartbeats, RTT samples
+// and Kerberos ticket timestamps. Tests can swap in a ManualClock to make
+// timeouts instant and deterministic
+pub trait Clock: fmt::Debug + Send + Sync {
+	// Monotonic time, used for timers
+	fn now(&self) -> Instant;
+
+	// Wall clock time, used for timestamps sent over the network
+	fn system_time(&self) -> SystemTime;
+
+	// Resolves once now has reached the deadline. Clocks which do not follow real
+	// time must override this
+	fn sleep_until(&self, deadline: Instant) -> ClockFuture<'_> {
+		let duration = deadline.saturating_duration_since(self.now());
+		Box::pin(tokio::time::sleep(duration))
+	}
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> Instant { Instant::now() }
+
+	fn system_time(&self) -> SystemTime { SystemTime::now() }
+}
+
+// A clock which only moves when told to. Both times start at the moment the
+// clock was created
+#[derive(Debug)]
+pub struct ManualClock {
+	start: Instant,
+	start_system_time: SystemTime,
+	elapsed: Mutex<Duration>,
+	advanced: Notify,
+}
+
+impl ManualClock {
+	pub fn new() -> Self {
+		Self { start: Instant::now(), start_system_time: SystemTime::now(), elapsed: Mutex::new(Duration::ZERO), advanced: Notify::new() }
+	}
+
+	// Moves the clock forward, waking everything sleeping until a time which has
+	// now been reached
+	pub fn advance(&self, duration: Duration) {
+		*self.elapsed.lock().unwrap() += duration;
+		self.advanced.notify_waiters();
+	}
+
+	pub fn elapsed(&self) -> Duration { *self.elapsed.lock().unwrap() }
+}
+
+impl Default for ManualClock {
+	fn default() -> Self { Self::new() }
+}
+
+impl Clock for ManualClock {
+	fn now(&self) -> Instant { self.start + self.elapsed() }
+
+	fn system_time(&self) -> SystemTime { self.start_system_time + self.elapsed() }
+
+	fn sleep_until(&self, deadline: Instant) -> ClockFuture<'_> {
+		Box::pin(async move {
+			loop {
+				// Registered before checking the time, so an advance in between is not missed
+				let advanced = self.advanced.notified();
+				tokio::pin!(advanced);
+				advanced.as_mut().enable();
+				if self.now() >= deadline { return; }
+				advanced.await;
+			}
+		})
+	}
+}
@@ -10,8 +10,21 @@ pub enum PrudpPacketType {
 	Ping = 0x4,
 }
 
+impl PrudpPacketType {
+	pub fn from_u16(value: u16) -> Option<Self> {
+		match value {
+			0x0 => Some(Self::Syn),
+			0x1 => Some(Self::Connect),
+			0x2 => Some(Self::Data),
+			0x3 => Some(Self::Disconnect),
+			0x4 => Some(Self::Ping),
+			_ => None,
+		}
+	}
+}
+
 bitflags! {
-	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 	pub struct PrudpPacketFlags: u16 {
 		const ACK        = 0x0001;
 		const RELIABLE   = 0x0002;
@@ -45,8 +58,9 @@ bitflags! {
 }
 
 #[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum StationUrlType {
+	#[default]
 	Unknown = 0,
 	Prudp = 1,
 	Prudps = 2,
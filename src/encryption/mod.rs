@@ -1,9 +1,11 @@
 use crate::NexResult;
-use crate::error::NexError;
+use std::sync::Mutex;
 
 pub trait Cipher: Send + Sync {
 	fn encrypt(&self, data: &[u8]) -> NexResult<Vec<u8>>;
 	fn decrypt(&self, data: &[u8]) -> NexResult<Vec<u8>>;
+	fn set_key(&mut self, key: &[u8]);
+	fn boxed_clone(&self) -> Box<dyn Cipher>;
 }
 
 pub struct Dummy;
@@ -11,44 +13,78 @@ pub struct Dummy;
 impl Cipher for Dummy {
 	fn encrypt(&self, data: &[u8]) -> NexResult<Vec<u8>> { Ok(data.to_vec()) }
 	fn decrypt(&self, data: &[u8]) -> NexResult<Vec<u8>> { Ok(data.to_vec()) }
+	fn set_key(&mut self, _key: &[u8]) {}
+	fn boxed_clone(&self) -> Box<dyn Cipher> { Box::new(Dummy) }
 }
 
-pub struct Rc4 { key: Vec<u8> }
+// Reliable substreams use one continuous RC4 stream per direction, so the
+// cipher and decipher states persist between calls
+pub struct Rc4 {
+	key: Vec<u8>,
+	cipher: Mutex<Rc4State>,
+	decipher: Mutex<Rc4State>,
+}
 
 impl Rc4 {
-	pub fn new(key: Vec<u8>) -> Self { Self { key } }
+	pub fn new(key: Vec<u8>) -> Self {
+		let cipher = Mutex::new(Rc4State::new(&key));
+		let decipher = Mutex::new(Rc4State::new(&key));
+		Self { key, cipher, decipher }
+	}
+
+	pub fn key(&self) -> &[u8] { &self.key }
 }
 
 impl Cipher for Rc4 {
 	fn encrypt(&self, data: &[u8]) -> NexResult<Vec<u8>> {
-		Ok(rc4(self.key.as_slice(), data))
+		Ok(self.cipher.lock().unwrap().apply(data))
 	}
 
 	fn decrypt(&self, data: &[u8]) -> NexResult<Vec<u8>> {
-		Ok(rc4(self.key.as_slice(), data))
+		Ok(self.decipher.lock().unwrap().apply(data))
 	}
-}
 
-fn rc4(key: &[u8], data: &[u8]) -> Vec<u8> {
-	let mut s: [u8; 256] = [0; 256];
-	for i in 0..256 { s[i] = i as u8; }
-	let mut j: u8 = 0;
-	for i in 0..256u16 {
-		let idx = i as usize;
-		j = j.wrapping_add(s[idx]).wrapping_add(key[idx % key.len()]);
-		s.swap(idx, j as usize);
-	}
-	let mut i: u8 = 0;
-	let mut j2: u8 = 0;
-	let mut out = Vec::with_capacity(data.len());
-	for &b in data {
-		i = i.wrapping_add(1);
-		j2 = j2.wrapping_add(s[i as usize]);
-		s.swap(i as usize, j2 as usize);
-		let k = s[(s[i as usize].wrapping_add(s[j2 as usize])) as usize];
-		out.push(b ^ k);
+	fn set_key(&mut self, key: &[u8]) { *self = Rc4::new(key.to_vec()); }
+
+	fn boxed_clone(&self) -> Box<dyn Cipher> {
+		Box::new(Rc4 {
+			key: self.key.clone(),
+			cipher: Mutex::new(self.cipher.lock().unwrap().clone()),
+			decipher: Mutex::new(self.decipher.lock().unwrap().clone()),
+		})
 	}
-	out
 }
 
+#[derive(Clone)]
+pub struct Rc4State {
+	s: [u8; 256],
+	i: u8,
+	j: u8,
+}
+
+impl Rc4State {
+	pub fn new(key: &[u8]) -> Self {
+		let mut s: [u8; 256] = [0; 256];
+		for (i, v) in s.iter_mut().enumerate() { *v = i as u8; }
+		if !key.is_empty() {
+			let mut j: u8 = 0;
+			for idx in 0..256 {
+				j = j.wrapping_add(s[idx]).wrapping_add(key[idx % key.len()]);
+				s.swap(idx, j as usize);
+			}
+		}
+		Self { s, i: 0, j: 0 }
+	}
 
+	pub fn apply(&mut self, data: &[u8]) -> Vec<u8> {
+		let mut out = Vec::with_capacity(data.len());
+		for &b in data {
+			self.i = self.i.wrapping_add(1);
+			self.j = self.j.wrapping_add(self.s[self.i as usize]);
+			self.s.swap(self.i as usize, self.j as usize);
+			let k = self.s[(self.s[self.i as usize].wrapping_add(self.s[self.j as usize])) as usize];
+			out.push(b ^ k);
+		}
+		out
+	}
+}
@@ -1,3 +1,5 @@
+use crate::prudp::virtual_port::VirtualPort;
+use crate::result_codes::result_code_to_name;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -8,4 +10,38 @@ pub enum NexError {
 	Parse(String),
 	#[error("Unsupported: {0}")]
 	Unsupported(String),
+	#[error("Connection error: {0}")]
+	Connection(String),
+	// An error with a NEX result code, such as RendezVous::MaxConnectionsReached
+	#[error("{}: {message}", result_code_to_name(*code))]
+	ResultCode { code: u32, message: String },
+	// An RMC message whose length prefix does not match the bytes which follow it
+	#[error("RMC message is {}: length prefix is {declared} bytes but {available} follow", if .available < .declared { "truncated" } else { "oversized" })]
+	RmcLength { declared: usize, available: usize },
+	// A PRUDPv0 packet whose checksum does not match. The header was read, so
+	// the packet can still be attributed to the endpoint it was sent to
+	#[error("Parse error: Invalid PRUDPv0 checksum on a packet for stream {}", .destination_port.stream_id)]
+	BadChecksum { source_port: VirtualPort, destination_port: VirtualPort },
+	#[error("Timed out: {0}")]
+	Timeout(String),
+	#[error("Cancelled: {0}")]
+	Cancelled(String),
+}
+
+// Errors are handed to every registered error handler, so they must be cloneable.
+// io::Error is not, so only its kind and message are kept
+impl Clone for NexError {
+	fn clone(&self) -> Self {
+		match self {
+			Self::Io(err) => Self::Io(std::io::Error::new(err.kind(), err.to_string())),
+			Self::Parse(message) => Self::Parse(message.clone()),
+			Self::Unsupported(message) => Self::Unsupported(message.clone()),
+			Self::Connection(message) => Self::Connection(message.clone()),
+			Self::ResultCode { code, message } => Self::ResultCode { code: *code, message: message.clone() },
+			Self::RmcLength { declared, available } => Self::RmcLength { declared: *declared, available: *available },
+			Self::BadChecksum { source_port, destination_port } => Self::BadChecksum { source_port: *source_port, destination_port: *destination_port },
+			Self::Timeout(message) => Self::Timeout(message.clone()),
+			Self::Cancelled(message) => Self::Cancelled(message.clone()),
+		}
+	}
 }
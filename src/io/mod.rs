@@ -1,15 +1,20 @@
 use crate::error::NexError;
 use crate::NexResult;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::io::{Cursor, Read};
+use std::io::Cursor;
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct ByteStreamSettings {
 	pub string_length_size: usize, // 2 or 4
 	pub pid_size: usize,           // 4 or 8
 	pub use_structure_header: bool,
 }
 
+// The same as streams without settings
+impl Default for ByteStreamSettings {
+	fn default() -> Self { Self { string_length_size: 2, pid_size: 4, use_structure_header: false } }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct LibraryVersions;
 
@@ -42,6 +47,10 @@ impl ByteStreamIn {
 		self.data.len().saturating_sub(self.pos)
 	}
 
+	pub fn position(&self) -> usize { self.pos }
+
+	pub fn bytes(&self) -> &[u8] { &self.data }
+
 	pub fn read_remaining(&mut self) -> Vec<u8> {
 		let len = self.remaining();
 		self.read(len as u64).unwrap_or_default()
@@ -118,6 +127,14 @@ impl ByteStreamIn {
 	pub fn read_bool(&mut self) -> NexResult<bool> {
 		Ok(self.read_u8()? == 1)
 	}
+
+	// Reads a NEX String. The length includes the null terminator, which is removed
+	pub fn read_string(&mut self) -> NexResult<String> {
+		let length = if self.string_length_size() == 4 { self.read_u32_le()? as u64 } else { self.read_u16_le()? as u64 };
+		let data = self.read(length)?;
+		let string = String::from_utf8(data).map_err(|_| NexError::Parse("String is not valid UTF-8".into()))?;
+		Ok(string.trim_end_matches('\0').to_string())
+	}
 }
 
 #[derive(Debug, Clone, Default)]
@@ -172,5 +189,13 @@ impl ByteStreamOut {
 
 	pub fn write_bool(&mut self, v: bool) { self.buf.push(if v {1} else {0}); }
 
+	// Writes a NEX String, which is null terminated
+	pub fn write_string(&mut self, v: &str) {
+		let length = v.len() + 1;
+		if self.string_length_size() == 4 { self.write_u32_le(length as u32); } else { self.write_u16_le(length as u16); }
+		self.write(v.as_bytes());
+		self.write_u8(0);
+	}
+
 	pub fn bytes(&self) -> &[u8] { &self.buf }
 }
@@ -1,4 +1,130 @@
-#[derive(Debug, Default)]
-pub struct Kerberos;
+use crate::encryption::Rc4State;
+use crate::error::NexError;
+use crate::io::{ByteStreamIn, ByteStreamOut};
+use crate::types::buffer::Buffer;
+use crate::types::datetime::DateTime;
+use crate::types::pid::Pid;
+use crate::NexResult;
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
 
+type HmacMd5 = Hmac<Md5>;
 
+// Kerberos encryption utility. Data is RC4 encrypted and followed by an HMAC-MD5
+// checksum of the encrypted data
+#[derive(Debug, Clone)]
+pub struct KerberosEncryption {
+	key: Vec<u8>,
+}
+
+impl KerberosEncryption {
+	pub fn new(key: &[u8]) -> Self { Self { key: key.to_vec() } }
+
+	fn mac(&self) -> HmacMd5 { HmacMd5::new_from_slice(&self.key).expect("HMAC accepts any key length") }
+
+	// Checks the integrity of the given buffer by verifying the HMAC checksum
+	pub fn validate(&self, buffer: &[u8]) -> bool {
+		if buffer.len() < 0x10 { return false; }
+		let (data, checksum) = buffer.split_at(buffer.len() - 0x10);
+		let mut mac = self.mac();
+		mac.update(data);
+		mac.verify_slice(checksum).is_ok()
+	}
+
+	pub fn decrypt(&self, buffer: &[u8]) -> NexResult<Vec<u8>> {
+		if !self.validate(buffer) { return Err(NexError::Parse("Invalid Kerberos checksum (incorrect password)".into())); }
+		Ok(Rc4State::new(&self.key).apply(&buffer[..buffer.len() - 0x10]))
+	}
+
+	pub fn encrypt(&self, buffer: &[u8]) -> Vec<u8> {
+		let mut encrypted = Rc4State::new(&self.key).apply(buffer);
+		let mut mac = self.mac();
+		mac.update(&encrypted);
+		encrypted.extend_from_slice(&mac.finalize().into_bytes());
+		encrypted
+	}
+}
+
+// A ticket granting a user access to a secure server. The internal data is
+// encrypted with the key of the target account and can only be read by it
+#[derive(Debug, Clone, Default)]
+pub struct KerberosTicket {
+	pub session_key: Vec<u8>,
+	pub target_pid: Pid,
+	pub internal_data: Buffer,
+}
+
+impl KerberosTicket {
+	// Writes the ticket to the given stream and returns it encrypted with the key of the source account
+	pub fn encrypt(&self, key: &[u8], mut stream: ByteStreamOut) -> Vec<u8> {
+		stream.write(&self.session_key);
+		self.target_pid.write_to(&mut stream);
+		self.internal_data.write_to(&mut stream);
+		KerberosEncryption::new(key).encrypt(stream.bytes())
+	}
+
+	// Reads a ticket given to a client by the authentication server
+	pub fn decrypt(mut stream: ByteStreamIn, key: &[u8], session_key_length: usize) -> NexResult<Self> {
+		let decrypted = KerberosEncryption::new(key).decrypt(&stream.read_remaining())?;
+		let mut stream = ByteStreamIn::new(decrypted, stream.library_versions, stream.settings);
+		let session_key = stream.read(session_key_length as u64)?;
+		let target_pid = Pid::read_from(&mut stream)?;
+		let internal_data = Buffer::read_from(&mut stream)?;
+		Ok(Self { session_key, target_pid, internal_data })
+	}
+}
+
+// The part of a ticket which is processed by the target server
+#[derive(Debug, Clone, Default)]
+pub struct KerberosTicketInternalData {
+	pub issued: DateTime,
+	pub source_pid: Pid,
+	pub session_key: Vec<u8>,
+}
+
+impl KerberosTicketInternalData {
+	// Writes the ticket data to the given stream and returns it encrypted. Version 1
+	// tickets derive the final key from a random ticket key sent alongside the data
+	pub fn encrypt(&self, key: &[u8], mut stream: ByteStreamOut, ticket_version: u32) -> Vec<u8> {
+		let mut final_stream = stream.copy_new();
+		self.issued.write_to(&mut stream);
+		self.source_pid.write_to(&mut stream);
+		stream.write(&self.session_key);
+
+		if ticket_version != 1 { return KerberosEncryption::new(key).encrypt(stream.bytes()); }
+
+		let ticket_key = rand::random::<[u8; 16]>().to_vec();
+		let final_key = Md5::digest([key, &ticket_key].concat());
+		let encrypted = KerberosEncryption::new(&final_key).encrypt(stream.bytes());
+		Buffer(ticket_key).write_to(&mut final_stream);
+		Buffer(encrypted).write_to(&mut final_stream);
+		final_stream.bytes().to_vec()
+	}
+
+	pub fn decrypt(mut stream: ByteStreamIn, key: &[u8], ticket_version: u32, session_key_length: usize) -> NexResult<Self> {
+		let mut key = key.to_vec();
+		let data = if ticket_version == 1 {
+			let ticket_key = Buffer::read_from(&mut stream).map_err(|err| NexError::Parse(format!("Failed to read Kerberos ticket internal data key. {err}")))?;
+			let data = Buffer::read_from(&mut stream).map_err(|err| NexError::Parse(format!("Failed to read Kerberos ticket internal data. {err}")))?;
+			key = Md5::digest([&key[..], &ticket_key.0].concat()).to_vec();
+			data.0
+		} else {
+			stream.read_remaining()
+		};
+
+		let decrypted = KerberosEncryption::new(&key).decrypt(&data).map_err(|err| NexError::Parse(format!("Failed to decrypt Kerberos ticket internal data. {err}")))?;
+		let mut stream = ByteStreamIn::new(decrypted, stream.library_versions, stream.settings);
+		let issued = DateTime::read_from(&mut stream).map_err(|err| NexError::Parse(format!("Failed to read Kerberos ticket internal data timestamp {err}")))?;
+		let source_pid = Pid::read_from(&mut stream).map_err(|err| NexError::Parse(format!("Failed to read Kerberos ticket internal data user PID {err}")))?;
+		let session_key = stream.read(session_key_length as u64)?;
+		Ok(Self { issued, source_pid, session_key })
+	}
+}
+
+// Derives the Kerberos key of an account from its PID and password
+pub fn derive_kerberos_key(pid: Pid, password: &[u8]) -> Vec<u8> {
+	let iteration_count = 65000 + pid.0 % 1024;
+	let mut key = password.to_vec();
+	for _ in 0..iteration_count { key = Md5::digest(&key).to_vec(); }
+	key
+}
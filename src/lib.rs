@@ -1,3 +1,4 @@
+pub mod clock;
 pub mod constants;
 pub mod types;
 pub mod io;
@@ -7,13 +8,23 @@ pub mod compression;
 pub mod encryption;
 pub mod prudp;
 pub mod rmc_message;
+pub mod rmc_format;
+pub mod protocol_registry;
 pub mod socket_connection;
+pub mod transport;
 pub mod timeout;
 pub mod timeout_manager;
 pub mod packet_dispatch_queue;
 pub mod service_protocol;
+pub mod rmc_interceptor;
+pub mod rmc_rate_limit;
 pub mod rtt;
 pub mod hpp;
 pub mod kerberos;
+pub mod account;
+mod trace;
+
+#[cfg(feature = "macros")]
+pub use nex_macros::nex_protocol;
 
 pub type NexResult<T> = Result<T, error::NexError>;
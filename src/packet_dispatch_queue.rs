@@ -1,10 +1,45 @@
-#[derive(Debug, Default)]
-pub struct PacketDispatchQueue;
+use crate::prudp::packet::PrudpPacket;
+use std::collections::HashMap;
+
+// Implementation of rdv::PacketDispatchQueue. Sequences incoming reliable packets
+// on one substream so they are handled in order
+#[derive(Debug)]
+pub struct PacketDispatchQueue {
+	queue: HashMap<u16, PrudpPacket>,
+	next_expected_sequence_id: u16,
+}
+
+impl Default for PacketDispatchQueue {
+	fn default() -> Self {
+		// The first DATA packet from a client is always 2, as the CONNECT packet is assigned 1
+		Self { queue: HashMap::new(), next_expected_sequence_id: 2 }
+	}
+}
 
 impl PacketDispatchQueue {
 	pub fn new() -> Self { Self::default() }
-	pub fn enqueue(&mut self, _packet: Vec<u8>) {}
-	pub fn drain(&mut self) {}
-}
 
+	// Clients expect the first DATA packet from the server to be 1, since the
+	// CONNECT ACK is not sequenced by the server sliding windows
+	pub fn starting_at(next_expected_sequence_id: u16) -> Self {
+		Self { queue: HashMap::new(), next_expected_sequence_id }
+	}
+
+	pub fn enqueue(&mut self, packet: PrudpPacket) {
+		// Drop retransmissions of packets which were already dispatched
+		if packet.header.sequence_id.wrapping_sub(self.next_expected_sequence_id) >= 0x8000 { return; }
+		self.queue.insert(packet.header.sequence_id, packet);
+	}
 
+	// Removes and returns every packet which is now in order
+	pub fn drain(&mut self) -> Vec<PrudpPacket> {
+		let mut ready = Vec::new();
+		while let Some(packet) = self.queue.remove(&self.next_expected_sequence_id) {
+			self.next_expected_sequence_id = self.next_expected_sequence_id.wrapping_add(1);
+			ready.push(packet);
+		}
+		ready
+	}
+
+	pub fn purge(&mut self) { self.queue.clear(); }
+}
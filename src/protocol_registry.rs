@@ -0,0 +1,116 @@
+use crate::error::NexError;
+use crate::io::{ByteStreamIn, ByteStreamSettings, LibraryVersions};
+use crate::rmc_message::RmcMessage;
+use crate::types::rv_type::RvType;
+use crate::NexResult;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+type FieldFormatter = Arc<dyn Fn(&mut ByteStreamIn, usize) -> NexResult<Vec<String>> + Send + Sync>;
+
+// The parameters and response values of a method, as the types they are read
+// with. Only used to print messages
+#[derive(Clone)]
+pub struct MethodInfo {
+	pub id: u32,
+	pub name: String,
+	request: Option<(Vec<String>, FieldFormatter)>,
+	response: Option<(Vec<String>, FieldFormatter)>,
+}
+
+impl MethodInfo {
+	pub fn new(id: u32, name: &str) -> Self { Self { id, name: name.into(), request: None, response: None } }
+
+	// The request parameters, as a tuple of their types, and their names
+	pub fn request<T: RvType>(mut self, names: &[&str]) -> Self {
+		self.request = Some((names.iter().map(|name| name.to_string()).collect(), formatter::<T>()));
+		self
+	}
+
+	// The response values, as a single type or a tuple. Values without a name are
+	// listed by their position
+	pub fn response<T: RvType>(mut self, names: &[&str]) -> Self {
+		self.response = Some((names.iter().map(|name| name.to_string()).collect(), formatter::<T>()));
+		self
+	}
+}
+
+impl std::fmt::Debug for MethodInfo {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("MethodInfo").field("id", &self.id).field("name", &self.name).finish_non_exhaustive()
+	}
+}
+
+fn formatter<T: RvType>() -> FieldFormatter {
+	Arc::new(|input, indentation_level| Ok(T::read_rv(input)?.format_fields(indentation_level)))
+}
+
+#[derive(Debug, Clone)]
+pub struct ProtocolInfo {
+	pub id: u16,
+	pub name: String,
+	pub methods: BTreeMap<u32, MethodInfo>,
+}
+
+impl ProtocolInfo {
+	pub fn new(id: u16, name: &str) -> Self { Self { id, name: name.into(), methods: BTreeMap::new() } }
+
+	pub fn method(mut self, method: MethodInfo) -> Self {
+		self.methods.insert(method.id, method);
+		self
+	}
+}
+
+// Names and parameter types of known protocols, used to print RMC messages with
+// their protocol and method names and decoded parameters. #[nex_protocol] traits
+// describe themselves with the protocol_info of their wrapper
+#[derive(Debug, Clone, Default)]
+pub struct ProtocolRegistry {
+	// Must match the settings of the endpoint the messages are from
+	pub byte_stream_settings: ByteStreamSettings,
+	protocols: HashMap<u16, ProtocolInfo>,
+}
+
+impl ProtocolRegistry {
+	pub fn new() -> Self { Self::default() }
+
+	// Replaces any protocol already registered with the same ID
+	pub fn register(&mut self, protocol: ProtocolInfo) { self.protocols.insert(protocol.id, protocol); }
+
+	pub fn protocol(&self, protocol_id: u16) -> Option<&ProtocolInfo> { self.protocols.get(&protocol_id) }
+
+	pub fn protocol_by_name(&self, name: &str) -> Option<&ProtocolInfo> { self.protocols.values().find(|protocol| protocol.name == name) }
+
+	// The protocol and method of a message, by name for verbose messages
+	pub fn find(&self, message: &RmcMessage) -> (Option<&ProtocolInfo>, Option<&MethodInfo>) {
+		let protocol = if message.verbose_mode { self.protocol_by_name(&message.protocol_name) } else { self.protocol(message.protocol_id) };
+		let method = protocol.and_then(|protocol| {
+			if message.verbose_mode { protocol.methods.values().find(|method| method.name == message.method_name) } else { protocol.methods.get(&message.method_id) }
+		});
+		(protocol, method)
+	}
+
+	// Like the Display of RmcMessage, with the names filled in and the parameters
+	// decoded when the method is known. Parameters which can not be decoded are
+	// shown as hex
+	pub fn format_message(&self, message: &RmcMessage) -> String {
+		let (protocol, method) = self.find(message);
+		let names = (protocol.map(|protocol| protocol.name.as_str()), method.map(|method| method.name.as_str()));
+		let fields = if message.is_request() { method.and_then(|method| method.request.as_ref()) } else if message.is_success() { method.and_then(|method| method.response.as_ref()) } else { None };
+		let parameters = fields.map(|(field_names, formatter)| self.decode(&message.parameters, field_names, formatter));
+		message.format_with(names, parameters)
+	}
+
+	// RmcMessage::to_json, with the names filled in
+	pub fn format_json(&self, message: &RmcMessage) -> String {
+		let (protocol, method) = self.find(message);
+		message.json_with((protocol.map(|protocol| protocol.name.as_str()), method.map(|method| method.name.as_str())))
+	}
+
+	fn decode(&self, parameters: &[u8], names: &[String], formatter: &FieldFormatter) -> NexResult<Vec<(String, String)>> {
+		let mut input = ByteStreamIn::new(parameters.to_vec(), Some(LibraryVersions), Some(self.byte_stream_settings.clone()));
+		let values = formatter(&mut input, 2)?;
+		if input.remaining() != 0 { return Err(NexError::Parse(format!("{} bytes left after the last parameter", input.remaining()))); }
+		Ok(values.into_iter().enumerate().map(|(i, value)| (names.get(i).cloned().unwrap_or_else(|| i.to_string()), value)).collect())
+	}
+}
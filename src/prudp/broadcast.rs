@@ -0,0 +1,93 @@
+use crate::constants::{PrudpPacketFlags, PrudpPacketType};
+use crate::error::NexError;
+use crate::prudp::connection::{ConnectionState, PrudpConnection};
+use crate::prudp::endpoint::PrudpEndpoint;
+use crate::prudp::packet::PrudpPacket;
+use crate::prudp::virtual_port::VirtualPort;
+use crate::rmc_message::RmcMessage;
+use crate::types::pid::Pid;
+use crate::NexResult;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+
+// The result of sending one message to many connections. A failure on one
+// connection does not stop the message being sent to the others
+#[derive(Debug, Default)]
+pub struct BroadcastSummary {
+	pub sent: usize,
+	pub failures: Vec<(Arc<PrudpConnection>, NexError)>,
+	// Connections which were selected but not sent to, because they are still in
+	// the handshake or already disconnecting
+	pub skipped: Vec<Arc<PrudpConnection>>,
+	// PIDs given to multicast_to_pids which have no connection on the endpoint
+	pub offline_pids: Vec<Pid>,
+}
+
+impl BroadcastSummary {
+	pub fn is_success(&self) -> bool { self.failures.is_empty() }
+}
+
+impl PrudpEndpoint {
+	// Sends an RMC message to every connected client of the endpoint
+	pub async fn broadcast(&self, message: &RmcMessage) -> NexResult<BroadcastSummary> {
+		self.multicast(message, self.connections.all()).await
+	}
+
+	// Sends an RMC message to every connection of the given users
+	pub async fn multicast_to_pids(&self, message: &RmcMessage, pids: &[Pid]) -> NexResult<BroadcastSummary> {
+		let mut connections = Vec::new();
+		let mut offline_pids = Vec::new();
+		let mut seen = HashSet::new();
+		for &pid in pids.iter().filter(|&&pid| seen.insert(pid)) {
+			let found = self.connections.find_all_by_pid(pid);
+			if found.is_empty() { offline_pids.push(pid); }
+			connections.extend(found);
+		}
+		let mut summary = self.multicast(message, connections).await?;
+		summary.offline_pids = offline_pids;
+		Ok(summary)
+	}
+
+	// Sends an RMC message to every connection the predicate selects
+	pub async fn multicast_filtered(&self, message: &RmcMessage, predicate: impl Fn(&PrudpConnection) -> bool) -> NexResult<BroadcastSummary> {
+		let connections = self.connections.all().into_iter().filter(|connection| predicate(connection)).collect();
+		self.multicast(message, connections).await
+	}
+
+	// The message is encoded once, in the RMC format of the endpoint. Each
+	// connection then sequences, compresses and encrypts its own copy, and all of
+	// them are sent at the same time
+	async fn multicast(&self, message: &RmcMessage, connections: Vec<Arc<PrudpConnection>>) -> NexResult<BroadcastSummary> {
+		let server = self.server().ok_or_else(|| NexError::Connection(format!("PRUDPEndPoint {} is not bound to a server", self.stream_id)))?;
+		let payload = if self.use_verbose_rmc { message.to_bytes_verbose() } else { message.to_bytes_packed() };
+
+		let mut summary = BroadcastSummary::default();
+		let mut sends = JoinSet::new();
+		for connection in connections {
+			// Connections still in the handshake have no sliding windows to sequence with
+			if connection.connection_state() != ConnectionState::Connected {
+				summary.skipped.push(connection);
+				continue;
+			}
+			let mut packet = PrudpPacket::new(connection.default_prudp_version, PrudpPacketType::Data);
+			packet.add_flag(PrudpPacketFlags::RELIABLE | PrudpPacketFlags::NEEDS_ACK | PrudpPacketFlags::HAS_SIZE);
+			packet.header.source = VirtualPort::new(connection.stream_type, self.stream_id);
+			packet.header.destination = VirtualPort::new(connection.stream_type, connection.stream_id);
+			packet.payload = payload.clone();
+			packet.sender = Some(connection.clone());
+			let server = server.clone();
+			sends.spawn(async move { (connection, server.send(packet).await) });
+		}
+
+		while let Some(joined) = sends.join_next().await {
+			match joined {
+				Ok((_, Ok(()))) => summary.sent += 1,
+				Ok((connection, Err(err))) => summary.failures.push((connection, err)),
+				// Send tasks are never aborted, so this only happens if one panicked
+				Err(err) => return Err(NexError::Connection(format!("Broadcast send task failed: {err}"))),
+			}
+		}
+		Ok(summary)
+	}
+}
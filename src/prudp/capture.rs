@@ -0,0 +1,286 @@
+use crate::clock::Clock;
+use crate::prudp::connection::PrudpConnection;
+use crate::prudp::packet::PrudpPacket;
+use crate::prudp::server::PrudpServer;
+use crate::prudp::settings::PrudpSettings;
+use crate::types::pid::Pid;
+use crate::NexResult;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::UNIX_EPOCH;
+
+// Writes the traffic of chosen connections to a pcapng file, after decryption.
+// Every PRUDP packet becomes an Enhanced Packet Block inside made up IP and UDP
+// headers, with its payload replaced by the decrypted and decompressed data.
+// Reliable packets are written in the order they are processed, not the order
+// they arrived in. Every complete RMC message is also written to a Custom Block
+// holding:
+//
+//   u32 PEN, u32 connection ID, u8 direction (1 = from client, 2 = to client),
+//   u64 microseconds since the Unix epoch, u32 message length, then the RMC
+//   message followed by padding
+//
+// All fields are little endian
+
+// IANA enterprise number reserved for documentation (RFC 5612), as this project
+// has no enterprise number of its own
+pub const RMC_BLOCK_PEN: u32 = 32473;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x00000001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x00000006;
+const CUSTOM_BLOCK: u32 = 0x00000BAD;
+const LINKTYPE_RAW: u16 = 101;
+const EPB_FLAGS: u16 = 2;
+
+// Which connections a capture records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureTarget {
+	Pid(Pid),
+	// Every connection from an IP address, whatever the port
+	Address(IpAddr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureDirection {
+	Incoming = 1,
+	Outgoing = 2,
+}
+
+// An open capture file. Blocks are encoded by the caller and written by a
+// dedicated thread, so recording never blocks on file IO. None stops the thread
+#[derive(Debug)]
+pub struct CaptureSession {
+	pub target: CaptureTarget,
+	server_address: SocketAddr,
+	settings: PrudpSettings,
+	clock: Arc<dyn Clock>,
+	sender: mpsc::Sender<Option<Vec<u8>>>,
+}
+
+impl CaptureSession {
+	pub(crate) fn create(target: CaptureTarget, path: &Path, server_address: SocketAddr, settings: PrudpSettings, clock: Arc<dyn Clock>) -> NexResult<(Arc<Self>, JoinHandle<()>)> {
+		let mut file = BufWriter::new(File::create(path)?);
+		file.write_all(&section_header_block())?;
+		file.write_all(&interface_description_block())?;
+		file.flush()?;
+
+		let (sender, receiver) = mpsc::channel::<Option<Vec<u8>>>();
+		let writer = std::thread::spawn(move || {
+			while let Ok(Some(block)) = receiver.recv() {
+				if file.write_all(&block).is_err() { return; }
+			}
+			let _ = file.flush();
+		});
+		Ok((Arc::new(Self { target, server_address, settings, clock, sender }), writer))
+	}
+
+	pub fn matches(&self, pid: Pid, address: SocketAddr) -> bool {
+		match self.target {
+			CaptureTarget::Pid(target) => target == pid,
+			CaptureTarget::Address(target) => target == address.ip(),
+		}
+	}
+
+	// Blocks recorded after this are dropped
+	pub(crate) fn stop(&self) { let _ = self.sender.send(None); }
+
+	fn record_packet(&self, client: SocketAddr, direction: CaptureDirection, packet: &PrudpPacket) {
+		let (source, destination) = match direction {
+			CaptureDirection::Incoming => (client, self.server_address),
+			CaptureDirection::Outgoing => (self.server_address, client),
+		};
+		let datagram = ip_udp_datagram(source, destination, &packet.to_bytes(&self.settings));
+		let mut body = Vec::with_capacity(datagram.len() + 32);
+		let timestamp = self.timestamp();
+		body.extend_from_slice(&0u32.to_le_bytes()); // Interface ID
+		body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+		body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+		body.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+		body.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+		body.extend_from_slice(&datagram);
+		pad(&mut body);
+		// Inbound and outbound are direction values 1 and 2, the same as ours
+		body.extend_from_slice(&EPB_FLAGS.to_le_bytes());
+		body.extend_from_slice(&4u16.to_le_bytes());
+		body.extend_from_slice(&(direction as u32).to_le_bytes());
+		body.extend_from_slice(&[0; 4]); // opt_endofopt
+		let _ = self.sender.send(Some(block(ENHANCED_PACKET_BLOCK, &body)));
+	}
+
+	fn record_rmc(&self, connection_id: u32, direction: CaptureDirection, message: &[u8]) {
+		let mut body = Vec::with_capacity(message.len() + 24);
+		body.extend_from_slice(&RMC_BLOCK_PEN.to_le_bytes());
+		body.extend_from_slice(&connection_id.to_le_bytes());
+		body.push(direction as u8);
+		body.extend_from_slice(&self.timestamp().to_le_bytes());
+		body.extend_from_slice(&(message.len() as u32).to_le_bytes());
+		body.extend_from_slice(message);
+		pad(&mut body);
+		let _ = self.sender.send(Some(block(CUSTOM_BLOCK, &body)));
+	}
+
+	fn timestamp(&self) -> u64 {
+		self.clock.system_time().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_micros() as u64).unwrap_or(0)
+	}
+}
+
+// A capture session attached to one connection
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionCapture {
+	pub session: Arc<CaptureSession>,
+	client: SocketAddr,
+	connection_id: u32,
+}
+
+impl ConnectionCapture {
+	pub(crate) fn new(session: Arc<CaptureSession>, client: SocketAddr, connection_id: u32) -> Self { Self { session, client, connection_id } }
+
+	// The payload of DATA packets must already be decrypted and decompressed
+	pub(crate) fn record_packet(&self, direction: CaptureDirection, packet: &PrudpPacket) { self.session.record_packet(self.client, direction, packet); }
+
+	// Records a complete RMC message, after fragments have been put back together
+	pub(crate) fn record_rmc(&self, direction: CaptureDirection, message: &[u8]) { self.session.record_rmc(self.connection_id, direction, message); }
+}
+
+impl PrudpServer {
+	// Starts writing the traffic of every connection matching the target to a new
+	// pcapng file, including connections which are already open. Connections
+	// captured by PID are only matched once their CONNECT has been handled. PRUDPv1
+	// SYNs are answered before there is a connection, so they are never captured
+	pub fn start_capture(&self, target: CaptureTarget, path: impl AsRef<Path>) -> NexResult<()> {
+		let server_address = self.udp_local_addr().unwrap_or((Ipv4Addr::UNSPECIFIED, 0).into());
+		let (session, writer) = CaptureSession::create(target, path.as_ref(), server_address, self.settings.clone(), self.clock.clone())?;
+		self.captures.lock().unwrap().push((session, writer));
+		for endpoint in self.endpoints() {
+			for connection in endpoint.connections.all() { self.attach_capture(&connection); }
+		}
+		Ok(())
+	}
+
+	// Stops every capture of the target, returning once their files are written.
+	// Returns false if the target was not being captured
+	pub async fn stop_capture(&self, target: CaptureTarget) -> bool {
+		self.stop_captures(|session| session.target == target).await
+	}
+
+	pub(crate) async fn stop_captures(&self, stop: impl Fn(&CaptureSession) -> bool) -> bool {
+		let stopped: Vec<_> = {
+			let mut captures = self.captures.lock().unwrap();
+			let (stopped, kept) = captures.drain(..).partition(|(session, _)| stop(session));
+			*captures = kept;
+			stopped
+		};
+		if stopped.is_empty() { return false; }
+
+		for endpoint in self.endpoints() {
+			for connection in endpoint.connections.all() {
+				let mut inner = connection.lock();
+				if inner.capture.as_ref().is_some_and(|capture| stopped.iter().any(|(session, _)| Arc::ptr_eq(session, &capture.session))) {
+					inner.capture = None;
+				}
+			}
+		}
+		for (session, writer) in stopped {
+			session.stop();
+			let _ = tokio::task::spawn_blocking(move || writer.join()).await;
+		}
+		true
+	}
+
+	// Attaches the first matching capture to a connection which has none
+	pub(crate) fn attach_capture(&self, connection: &PrudpConnection) {
+		let captures = self.captures.lock().unwrap();
+		if captures.is_empty() { return; }
+		let mut inner = connection.lock();
+		if inner.capture.is_some() { return; }
+		let address = connection.address();
+		inner.capture = captures.iter()
+			.find(|(session, _)| session.matches(inner.pid, address))
+			.map(|(session, _)| ConnectionCapture::new(session.clone(), address, connection.id));
+	}
+}
+
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+	let length = (body.len() + 12) as u32;
+	let mut data = Vec::with_capacity(length as usize);
+	data.extend_from_slice(&block_type.to_le_bytes());
+	data.extend_from_slice(&length.to_le_bytes());
+	data.extend_from_slice(body);
+	data.extend_from_slice(&length.to_le_bytes());
+	data
+}
+
+fn pad(data: &mut Vec<u8>) {
+	data.resize(data.len().next_multiple_of(4), 0);
+}
+
+fn section_header_block() -> Vec<u8> {
+	let mut body = Vec::new();
+	body.extend_from_slice(&0x1A2B3C4Du32.to_le_bytes()); // Byte order magic
+	body.extend_from_slice(&1u16.to_le_bytes());
+	body.extend_from_slice(&0u16.to_le_bytes());
+	body.extend_from_slice(&(-1i64).to_le_bytes()); // Section length is not known
+	block(SECTION_HEADER_BLOCK, &body)
+}
+
+// Timestamps use the default resolution of microseconds
+fn interface_description_block() -> Vec<u8> {
+	let mut body = Vec::new();
+	body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+	body.extend_from_slice(&0u16.to_le_bytes());
+	body.extend_from_slice(&0u32.to_le_bytes()); // No snapshot length limit
+	block(INTERFACE_DESCRIPTION_BLOCK, &body)
+}
+
+// Wraps a PRUDP packet in IP and UDP headers so that it can be dissected like
+// real traffic. The UDP checksum is left as 0, which means none for IPv4
+fn ip_udp_datagram(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> Vec<u8> {
+	let udp_length = (payload.len() + 8) as u16;
+	let mut udp = Vec::with_capacity(udp_length as usize);
+	udp.extend_from_slice(&source.port().to_be_bytes());
+	udp.extend_from_slice(&destination.port().to_be_bytes());
+	udp.extend_from_slice(&udp_length.to_be_bytes());
+	udp.extend_from_slice(&[0, 0]);
+	udp.extend_from_slice(payload);
+
+	let mut datagram = Vec::with_capacity(udp.len() + 40);
+	match (source.ip(), destination.ip()) {
+		(IpAddr::V4(source), IpAddr::V4(destination)) => {
+			datagram.extend_from_slice(&[0x45, 0]);
+			datagram.extend_from_slice(&(udp_length + 20).to_be_bytes());
+			datagram.extend_from_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]); // Don't fragment, TTL 64, UDP
+			datagram.extend_from_slice(&source.octets());
+			datagram.extend_from_slice(&destination.octets());
+			let checksum = ipv4_checksum(&datagram);
+			datagram[10..12].copy_from_slice(&checksum.to_be_bytes());
+		}
+		(source, destination) => {
+			datagram.extend_from_slice(&[0x60, 0, 0, 0]);
+			datagram.extend_from_slice(&udp_length.to_be_bytes());
+			datagram.extend_from_slice(&[17, 64]); // UDP, hop limit 64
+			datagram.extend_from_slice(&ipv6_octets(source));
+			datagram.extend_from_slice(&ipv6_octets(destination));
+		}
+	}
+	datagram.extend_from_slice(&udp);
+	datagram
+}
+
+fn ipv6_octets(address: IpAddr) -> [u8; 16] {
+	match address {
+		IpAddr::V4(address) => address.to_ipv6_mapped().octets(),
+		IpAddr::V6(address) => address.octets(),
+	}
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+	let sum = header.chunks(2).fold(0u32, |sum, word| sum + u16::from_be_bytes([word[0], word[1]]) as u32);
+	let folded = (sum & 0xFFFF) + (sum >> 16);
+	!((folded & 0xFFFF) + (folded >> 16)) as u16
+}
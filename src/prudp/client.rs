@@ -0,0 +1,555 @@
+use crate::clock::{Clock, SystemClock};
+use crate::constants::{PrudpPacketFlags, PrudpPacketType, StreamType};
+use crate::error::NexError;
+use crate::io::{ByteStreamIn, ByteStreamOut, ByteStreamSettings};
+use crate::kerberos::{KerberosEncryption, KerberosTicket};
+use crate::packet_dispatch_queue::PacketDispatchQueue;
+use crate::prudp::connection::{ConnectionState, PrudpConnection};
+use crate::prudp::endpoint::handle_multi_acknowledgment;
+use crate::prudp::handlers::{handler, HandlerList};
+use crate::prudp::packet::{PrudpPacket, PRUDP_V0, PRUDP_V1};
+use crate::prudp::rmc_call::RmcCall;
+use crate::prudp::server::{FRAGMENT_DELAY, TIMER_RESOLUTION};
+use crate::prudp::settings::PrudpSettings;
+use crate::prudp::stream_settings::StreamSettings;
+use crate::prudp::virtual_port::VirtualPort;
+use crate::rmc_message::{RmcMessage, RmcSettings};
+use crate::socket_connection::SocketConnection;
+use crate::trace::{event, Span};
+use crate::transport::Transport;
+use crate::types::buffer::Buffer;
+use crate::types::pid::Pid;
+use crate::NexResult;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+// How long to wait for the server to acknowledge a SYN, CONNECT or DISCONNECT
+// packet before sending it again, and how many times to try
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+const HANDSHAKE_ATTEMPTS: u32 = 5;
+
+// A PRUDP client for a single virtual connection to a server endpoint, meant for
+// tests and bots. Both sides share the connection logic, so from the point of
+// view of the client the server session ID and connection signature are its own
+pub struct PrudpClient {
+	pub settings: PrudpSettings,
+	pub stream_settings: StreamSettings,
+	pub prudp_version: u8,
+	pub stream_type: u8,
+	pub source_stream_id: u8,
+	pub destination_stream_id: u8,
+	pub supported_functions: u32,
+	pub minor_version: u32,
+	pub maximum_substream_id: u8,
+	// Largest payload sent in one packet, 0 to never fragment
+	pub fragment_size: usize,
+	// Must match the use_verbose_rmc setting of the server endpoint
+	pub use_verbose_rmc: bool,
+	// How long calls wait for a response. None waits until the connection closes
+	pub call_timeout: Option<Duration>,
+	pub byte_stream_settings: ByteStreamSettings,
+	// The transport to connect over. A new UDP socket is bound when this is not set
+	pub transport: Option<Arc<dyn Transport>>,
+	pub clock: Arc<dyn Clock>,
+	connection: Mutex<Option<Arc<PrudpConnection>>>,
+	handshake: Mutex<Option<(u16, oneshot::Sender<PrudpPacket>)>>,
+	notification_handlers: HandlerList<RmcMessage>,
+	error_handlers: HandlerList<NexError>,
+	disconnect_handlers: HandlerList<()>,
+	tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl PrudpClient {
+	pub fn new() -> Self {
+		Self {
+			settings: PrudpSettings::default(),
+			stream_settings: StreamSettings::new(),
+			prudp_version: PRUDP_V1,
+			stream_type: StreamType::RvSecure as u8,
+			source_stream_id: 15,
+			destination_stream_id: 1,
+			supported_functions: 0,
+			minor_version: 0,
+			maximum_substream_id: 0,
+			fragment_size: 1300,
+			use_verbose_rmc: false,
+			call_timeout: None,
+			byte_stream_settings: ByteStreamSettings::default(),
+			transport: None,
+			clock: Arc::new(SystemClock),
+			connection: Mutex::new(None),
+			handshake: Mutex::new(None),
+			notification_handlers: HandlerList::new(),
+			error_handlers: HandlerList::new(),
+			disconnect_handlers: HandlerList::new(),
+			tasks: Mutex::new(Vec::new()),
+		}
+	}
+
+	pub fn connection(&self) -> Option<Arc<PrudpConnection>> { self.connection.lock().unwrap().clone() }
+
+	fn rmc_settings(&self) -> RmcSettings {
+		RmcSettings { verbose: self.use_verbose_rmc, byte_stream_settings: Some(self.byte_stream_settings.clone()), ..Default::default() }
+	}
+
+	fn connected(&self) -> NexResult<Arc<PrudpConnection>> {
+		self.connection().filter(|connection| connection.connection_state() == ConnectionState::Connected).ok_or_else(|| NexError::Connection("Client is not connected".into()))
+	}
+
+	// Adds an event handler which is fired when the server sends an RMC request,
+	// such as a notification event
+	pub fn on_notification<F, Fut>(&self, f: F)
+	where
+		F: Fn(RmcMessage) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = ()> + Send + 'static,
+	{
+		self.notification_handlers.push(handler(f));
+	}
+
+	// Adds an event handler which is fired when a packet from the server cannot be handled
+	pub fn on_error<F, Fut>(&self, f: F)
+	where
+		F: Fn(NexError) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = ()> + Send + 'static,
+	{
+		self.error_handlers.push(handler(f));
+	}
+
+	// Adds an event handler which is fired when the server ends the connection,
+	// either with a DISCONNECT packet or by going silent
+	pub fn on_disconnect<F, Fut>(&self, f: F)
+	where
+		F: Fn() -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = ()> + Send + 'static,
+	{
+		self.disconnect_handlers.push(handler(move |_: ()| f()));
+	}
+
+	// Connects to an endpoint which does not require authentication, such as an
+	// authentication server
+	pub async fn connect(self: &Arc<Self>, address: SocketAddr) -> NexResult<()> {
+		self.open(address, None).await
+	}
+
+	// Connects to a secure endpoint using a ticket from the authentication server
+	pub async fn connect_secure(self: &Arc<Self>, address: SocketAddr, pid: Pid, ticket: &KerberosTicket) -> NexResult<()> {
+		self.open(address, Some((pid, ticket))).await
+	}
+
+	async fn open(self: &Arc<Self>, address: SocketAddr, credentials: Option<(Pid, &KerberosTicket)>) -> NexResult<()> {
+		let transport: Arc<dyn Transport> = match &self.transport {
+			Some(transport) => transport.clone(),
+			None => {
+				let local_address = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+				Arc::new(UdpSocket::bind(local_address).await?)
+			}
+		};
+		let connection = Arc::new(PrudpConnection::new(
+			SocketConnection::datagram(address, transport.clone()),
+			Weak::new(),
+			0,
+			VirtualPort::new(self.stream_type, self.destination_stream_id),
+			self.prudp_version,
+			self.stream_settings.clone(),
+			self.clock.now(),
+		));
+		{
+			let mut current = self.connection.lock().unwrap();
+			if current.is_some() { return Err(NexError::Connection("Client has already connected".into())); }
+			*current = Some(connection.clone());
+		}
+		// The reader is needed for the acknowledgements of the handshake
+		self.spawn_reader(transport, address, &connection);
+
+		let result = self.handshake(&connection, credentials).await;
+		if result.is_err() {
+			self.handshake.lock().unwrap().take();
+			self.close(&connection);
+		}
+		result
+	}
+
+	// Runs the SYN and CONNECT exchanges, with a Kerberos ticket on secure endpoints
+	async fn handshake(self: &Arc<Self>, connection: &Arc<PrudpConnection>, credentials: Option<(Pid, &KerberosTicket)>) -> NexResult<()> {
+		let signature_size = if self.prudp_version == PRUDP_V0 { 4 } else { 16 };
+
+		let mut syn = self.new_packet(PrudpPacketType::Syn);
+		syn.add_flag(PrudpPacketFlags::NEEDS_ACK);
+		syn.header.connection_signature = vec![0; signature_size];
+		syn.header.supported_functions = self.supported_functions;
+		syn.header.minor_version = self.minor_version;
+		syn.header.maximum_substream_id = self.maximum_substream_id;
+		syn.header.signature = syn.calculate_signature(&self.settings, &[], &[]);
+		let syn_ack = self.exchange(connection, syn.to_bytes(&self.settings), PrudpPacketType::Syn).await?;
+		connection.span.in_scope(|| event!(debug, "Received SYN ACK"));
+		connection.lock().state = ConnectionState::Connecting;
+
+		let session_id = rand::random::<u8>();
+		let initial_unreliable_sequence_id = rand::random::<u16>();
+		let check_value = rand::random::<u32>();
+		let mut connect = self.new_packet(PrudpPacketType::Connect);
+		connect.add_flag(PrudpPacketFlags::RELIABLE | PrudpPacketFlags::NEEDS_ACK | PrudpPacketFlags::HAS_SIZE);
+		connect.header.session_id = session_id;
+		connect.header.sequence_id = 1;
+		connect.header.connection_signature = (0..signature_size).map(|_| rand::random::<u8>()).collect();
+		// The server only accepts what it agreed to in the SYN ACK
+		connect.header.supported_functions = syn_ack.header.supported_functions;
+		connect.header.minor_version = syn_ack.header.minor_version;
+		connect.header.maximum_substream_id = syn_ack.header.maximum_substream_id;
+		connect.header.initial_unreliable_sequence_id = initial_unreliable_sequence_id;
+
+		let mut payload = Vec::new();
+		if let Some((pid, ticket)) = credentials {
+			let mut request = ByteStreamOut::new(None, None);
+			pid.write_to(&mut request);
+			// The server does not check the CID of its station URL
+			request.write_u32_le(0);
+			request.write_u32_le(check_value);
+			let mut stream = ByteStreamOut::new(None, None);
+			ticket.internal_data.write_to(&mut stream);
+			Buffer(KerberosEncryption::new(&ticket.session_key).encrypt(request.bytes())).write_to(&mut stream);
+			payload = stream.bytes().to_vec();
+		}
+		connect.payload = {
+			let inner = connection.lock();
+			let compressed = inner.stream_settings.compression_algorithm.compress(&payload)?;
+			if self.settings.prudp_v0.encrypted_connect { inner.stream_settings.encryption_algorithm.encrypt(&compressed)? } else { compressed }
+		};
+		connect.header.signature = connect.calculate_signature(&self.settings, &[], &syn_ack.header.connection_signature);
+		let connect_ack = self.exchange(connection, connect.to_bytes(&self.settings), PrudpPacketType::Connect).await?;
+
+		{
+			let mut inner = connection.lock();
+			let decrypted = if self.settings.prudp_v0.encrypted_connect {
+				inner.stream_settings.encryption_algorithm.decrypt(&connect_ack.payload)?
+			} else {
+				connect_ack.payload.clone()
+			};
+			let response = inner.stream_settings.compression_algorithm.decompress(&decrypted)?;
+			if credentials.is_some() {
+				let mut stream = ByteStreamIn::new(response, None, None);
+				let mut response_check = ByteStreamIn::new(Buffer::read_from(&mut stream)?.0, None, None);
+				if response_check.read_u32_le()? != check_value.wrapping_add(1) {
+					return Err(NexError::Connection("Server sent an invalid Kerberos check value".into()));
+				}
+			}
+
+			let maximum_substream_id = syn_ack.header.maximum_substream_id;
+			inner.signature = connect.header.connection_signature.clone();
+			inner.server_connection_signature = syn_ack.header.connection_signature.clone();
+			inner.session_id = session_id;
+			inner.server_session_id = session_id;
+			inner.initialize_sliding_windows(maximum_substream_id);
+			for substream_id in 0..=maximum_substream_id {
+				// The CONNECT packet took sequence ID 1 from every substream, but the
+				// CONNECT ACK is not part of the sequence of the server
+				inner.sliding_window(substream_id).next_outgoing_sequence_id();
+				inner.packet_dispatch_queues.insert(substream_id, PacketDispatchQueue::starting_at(1));
+			}
+			if self.prudp_version != PRUDP_V0 { inner.outgoing_unreliable_sequence_id = initial_unreliable_sequence_id; }
+			if let Some((pid, ticket)) = credentials {
+				inner.pid = pid;
+				connection.span.record_pid(pid);
+				inner.set_session_key(&ticket.session_key);
+			}
+			inner.reset_heartbeat(self.clock.now());
+			inner.state = ConnectionState::Connected;
+		}
+
+		connection.span.in_scope(|| event!(info, "Connection established"));
+		self.spawn_timer();
+		Ok(())
+	}
+
+	// Sends an RMC request and waits for the response from the server
+	pub async fn call(&self, protocol_id: u16, method_id: u32, parameters: Vec<u8>) -> NexResult<RmcMessage> {
+		self.call_message(RmcMessage::request(protocol_id, method_id, parameters)).await
+	}
+
+	// Sends an RMC request and waits for the response from the server. The call ID
+	// is filled in. Verbose requests must set the protocol and method names
+	pub async fn call_message(&self, request: RmcMessage) -> NexResult<RmcMessage> {
+		let (call, span) = self.send_call(request).await?;
+		span.instrument(async {
+			let response = call.await?;
+			event!(debug, success = response.is_success(), "Received RMC response");
+			Ok(response)
+		})
+		.await
+	}
+
+	// Sends an RMC request and returns the handle which waits for the response,
+	// with the call_timeout of the client applied
+	pub async fn start_call(&self, request: RmcMessage) -> NexResult<RmcCall> {
+		Ok(self.send_call(request).await?.0)
+	}
+
+	async fn send_call(&self, mut request: RmcMessage) -> NexResult<(RmcCall, Span)> {
+		let connection = self.connected()?;
+		let mut call = connection.pending_calls.register();
+		if let Some(timeout) = self.call_timeout { call = call.with_timeout(timeout, self.clock.clone()); }
+		request.is_request = true;
+		request.call_id = call.call_id();
+		let span = connection.span.in_scope(|| Span::rmc(&request));
+		span.instrument(self.send_rmc(&request)).await?;
+		Ok((call, span))
+	}
+
+	// Sends an RMC message on the reliable substream 0, in the format picked by
+	// use_verbose_rmc. Payloads larger than the fragment size are split into several packets
+	pub async fn send_rmc(&self, message: &RmcMessage) -> NexResult<()> {
+		let connection = self.connected()?;
+		let mut packet = self.new_packet(PrudpPacketType::Data);
+		packet.add_flag(PrudpPacketFlags::RELIABLE | PrudpPacketFlags::NEEDS_ACK | PrudpPacketFlags::HAS_SIZE);
+		packet.payload = if self.use_verbose_rmc { message.to_bytes_verbose() } else { message.to_bytes_packed() };
+
+		let fragments = packet.into_fragments(self.fragment_size);
+		let count = fragments.len();
+		for (i, fragment) in fragments.into_iter().enumerate() {
+			let data = connection.lock().prepare_packet(&self.settings, fragment, self.clock.now())?;
+			connection.socket.send(&data).await?;
+			if i + 1 < count { tokio::time::sleep(FRAGMENT_DELAY).await; }
+		}
+		Ok(())
+	}
+
+	// Ends the connection. Waits for the server to acknowledge the DISCONNECT packet,
+	// but closes the connection even if it never does
+	pub async fn disconnect(&self) -> NexResult<()> {
+		let Some(connection) = self.connection() else { return Ok(()) };
+		if connection.connection_state() == ConnectionState::Connected {
+			let mut packet = self.new_packet(PrudpPacketType::Disconnect);
+			packet.add_flag(PrudpPacketFlags::NEEDS_ACK);
+			let data = connection.lock().prepare_packet(&self.settings, packet, self.clock.now())?;
+			let _ = self.exchange(&connection, data, PrudpPacketType::Disconnect).await;
+		}
+		self.close(&connection);
+		Ok(())
+	}
+
+	fn new_packet(&self, packet_type: PrudpPacketType) -> PrudpPacket {
+		let mut packet = PrudpPacket::new(self.prudp_version, packet_type);
+		packet.header.source = VirtualPort::new(self.stream_type, self.source_stream_id);
+		packet.header.destination = VirtualPort::new(self.stream_type, self.destination_stream_id);
+		packet
+	}
+
+	// Sends a packet until the server acknowledges it
+	async fn exchange(&self, connection: &PrudpConnection, data: Vec<u8>, packet_type: PrudpPacketType) -> NexResult<PrudpPacket> {
+		let (sender, mut receiver) = oneshot::channel();
+		*self.handshake.lock().unwrap() = Some((packet_type as u16, sender));
+		for _ in 0..HANDSHAKE_ATTEMPTS {
+			connection.lock().metrics.outgoing(packet_type as u16, data.len());
+			connection.socket.send(&data).await?;
+			match tokio::time::timeout(HANDSHAKE_TIMEOUT, &mut receiver).await {
+				Ok(Ok(ack)) => return Ok(ack),
+				// The server ended the connection instead
+				Ok(Err(_)) => return Err(NexError::Connection(format!("Server disconnected before acknowledging {packet_type:?}"))),
+				Err(_) => {}
+			}
+		}
+		self.handshake.lock().unwrap().take();
+		Err(NexError::Connection(format!("Timed out waiting for the server to acknowledge {packet_type:?}")))
+	}
+
+	fn spawn_reader(self: &Arc<Self>, transport: Arc<dyn Transport>, address: SocketAddr, connection: &Arc<PrudpConnection>) {
+		let client = Arc::downgrade(self);
+		let connection = Arc::downgrade(connection);
+		let task = tokio::spawn(async move {
+			let mut buf = vec![0u8; 64000];
+			while let Ok((read, from)) = transport.recv_from(&mut buf).await {
+				if from != address { continue; }
+				let Some(client) = client.upgrade() else { break };
+				// Packets are handled in the order they arrive, unlike on the server
+				client.handle_datagram(&buf[..read]).await;
+				// A DISCONNECT from the server closes the connection from this task,
+				// which is then left to end here instead of being aborted. It must not
+				// read again, the datagrams are for the next connection
+				if !client.connection().is_some_and(|current| std::ptr::eq(Arc::as_ptr(&current), connection.as_ptr())) { break; }
+			}
+		});
+		self.tasks.lock().unwrap().push(task);
+	}
+
+	fn spawn_timer(self: &Arc<Self>) {
+		let client = Arc::downgrade(self);
+		let task = tokio::spawn(async move {
+			let mut interval = tokio::time::interval(TIMER_RESOLUTION);
+			loop {
+				interval.tick().await;
+				let Some(client) = client.upgrade() else { break };
+				if client.connected().is_err() { break; }
+				client.tick().await;
+			}
+		});
+		self.tasks.lock().unwrap().push(task);
+	}
+
+	// Runs the retransmission and heartbeat timers once. Called every
+	// TIMER_RESOLUTION while connected, but may be called directly when testing
+	// with a manual clock
+	pub async fn tick(&self) {
+		let Ok(connection) = self.connected() else { return };
+		let tick = connection.lock().tick(self.clock.now());
+		if tick.dead {
+			connection.span.in_scope(|| event!(info, "Connection timed out"));
+			self.close(&connection);
+			self.disconnect_handlers.emit(()).await;
+			return;
+		}
+		if !tick.resend.is_empty() {
+			connection.span.in_scope(|| event!(debug, count = tick.resend.len(), "Retransmitting reliable packets"));
+		}
+		for data in tick.resend { let _ = connection.socket.send(&data).await; }
+		if tick.ping { let _ = self.send_ping(&connection).await; }
+	}
+
+	async fn handle_datagram(&self, data: &[u8]) {
+		let Some(connection) = self.connection() else { return };
+		let packets = match PrudpPacket::decode_all(data, &self.settings, false) {
+			Ok(packets) => packets,
+			Err(err) => {
+				connection.span.in_scope(|| event!(warn, error = %err, "Failed to decode PRUDP packet"));
+				return self.error_handlers.emit(err).await;
+			}
+		};
+		for packet in packets {
+			if let Err(err) = connection.span.instrument(self.handle_packet(packet)).await {
+				connection.span.in_scope(|| event!(warn, error = %err, "Failed to handle PRUDP packet"));
+				self.error_handlers.emit(err).await;
+			}
+		}
+	}
+
+	async fn handle_packet(&self, packet: PrudpPacket) -> NexResult<()> {
+		let Some(connection) = self.connection() else { return Ok(()) };
+		{
+			let mut inner = connection.lock();
+			inner.reset_heartbeat(self.clock.now());
+			inner.metrics.incoming(packet.header.type_id, packet.size);
+		}
+
+		if packet.is_ack() {
+			if packet.is_type(PrudpPacketType::Data) {
+				let mut inner = connection.lock();
+				if inner.state != ConnectionState::Connected { return Ok(()); }
+				if packet.has_flag(PrudpPacketFlags::MULTI_ACK) { return handle_multi_acknowledgment(&mut inner, &packet, self.clock.now()); }
+				inner.acknowledge_packet(packet.header.substream_id, packet.header.sequence_id, self.clock.now());
+			} else if !packet.is_type(PrudpPacketType::Ping) {
+				self.complete_exchange(packet);
+			}
+			return Ok(());
+		}
+
+		match packet.packet_type() {
+			Some(PrudpPacketType::Data) => self.handle_data(&connection, packet).await,
+			Some(PrudpPacketType::Disconnect) => self.handle_disconnect(&connection, &packet).await,
+			Some(PrudpPacketType::Ping) if packet.has_flag(PrudpPacketFlags::NEEDS_ACK) => self.acknowledge_packet(&connection, &packet).await,
+			// Servers never start connections
+			Some(_) => Ok(()),
+			None => Err(NexError::Parse(format!("Unknown PRUDP packet type {}", packet.header.type_id))),
+		}
+	}
+
+	fn complete_exchange(&self, packet: PrudpPacket) {
+		let mut handshake = self.handshake.lock().unwrap();
+		match handshake.take() {
+			Some((type_id, sender)) if type_id == packet.header.type_id => { let _ = sender.send(packet); }
+			other => *handshake = other,
+		}
+	}
+
+	async fn handle_data(&self, connection: &Arc<PrudpConnection>, packet: PrudpPacket) -> NexResult<()> {
+		if connection.connection_state() != ConnectionState::Connected { return Ok(()); }
+		if packet.has_flag(PrudpPacketFlags::NEEDS_ACK) { self.acknowledge_packet(connection, &packet).await?; }
+
+		if packet.has_flag(PrudpPacketFlags::RELIABLE) {
+			let (messages, result) = connection.read_reliable_messages(packet, &self.rmc_settings());
+			for message in messages.into_iter().filter_map(|packet| packet.rmc_message) { self.dispatch_rmc(message).await; }
+			return result;
+		}
+
+		// Unreliable DATA packets can never be fragmented
+		let payload = packet.process_unreliable_crypto(&connection.lock().unreliable_packet_base_key);
+		self.dispatch_rmc(RmcMessage::from_bytes(payload, &self.rmc_settings())?).await;
+		Ok(())
+	}
+
+	// Responses complete the call waiting on them, requests are notifications
+	async fn dispatch_rmc(&self, message: RmcMessage) {
+		if message.is_request() { return Span::rmc(&message).instrument(self.notification_handlers.emit(message)).await; }
+		if let Some(connection) = self.connection() { connection.pending_calls.complete(message); }
+	}
+
+	async fn handle_disconnect(&self, connection: &PrudpConnection, packet: &PrudpPacket) -> NexResult<()> {
+		if packet.has_flag(PrudpPacketFlags::NEEDS_ACK) { self.acknowledge_packet(connection, packet).await?; }
+		// Servers refuse a CONNECT with a DISCONNECT, failing the handshake
+		match connection.connection_state() {
+			ConnectionState::NotConnected => return Ok(()),
+			ConnectionState::Connecting => {
+				self.handshake.lock().unwrap().take();
+				return Ok(());
+			}
+			_ => {}
+		}
+		event!(info, "Server disconnected");
+		self.close(connection);
+		self.disconnect_handlers.emit(()).await;
+		Ok(())
+	}
+
+	async fn acknowledge_packet(&self, connection: &PrudpConnection, packet: &PrudpPacket) -> NexResult<()> {
+		let Some(packet_type) = packet.packet_type() else { return Ok(()) };
+		let mut ack = packet.reply(packet_type);
+		ack.add_flag(PrudpPacketFlags::ACK);
+		ack.header.sequence_id = packet.header.sequence_id;
+		ack.header.fragment_id = packet.header.fragment_id;
+		ack.header.substream_id = packet.header.substream_id;
+		let data = connection.lock().prepare_packet(&self.settings, ack, self.clock.now())?;
+		connection.socket.send(&data).await
+	}
+
+	async fn send_ping(&self, connection: &PrudpConnection) -> NexResult<()> {
+		let mut ping = self.new_packet(PrudpPacketType::Ping);
+		ping.add_flag(PrudpPacketFlags::NEEDS_ACK);
+		let data = connection.lock().prepare_packet(&self.settings, ping, self.clock.now())?;
+		connection.socket.send(&data).await
+	}
+
+	// Stops every timer and task of the connection and forgets it, so the client
+	// can connect again. Calls still waiting on a response fail. The task calling
+	// this is not aborted, it stops by itself once it sees the connection is gone
+	fn close(&self, connection: &PrudpConnection) {
+		{
+			let mut current = self.connection.lock().unwrap();
+			if current.as_deref().is_some_and(|current| std::ptr::eq(current, connection)) { current.take(); }
+		}
+		connection.lock().cleanup();
+		connection.pending_calls.fail_all(NexError::Connection("Connection closed before a response was received".into()));
+		let closing_task = tokio::task::try_id();
+		for task in self.tasks.lock().unwrap().drain(..) {
+			if Some(task.id()) != closing_task { task.abort(); }
+		}
+	}
+}
+
+impl Default for PrudpClient {
+	fn default() -> Self { Self::new() }
+}
+
+impl Drop for PrudpClient {
+	fn drop(&mut self) {
+		for task in self.tasks.get_mut().unwrap().drain(..) { task.abort(); }
+	}
+}
+
+impl std::fmt::Debug for PrudpClient {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("PrudpClient").field("connection", &self.connection()).finish_non_exhaustive()
+	}
+}
@@ -1,4 +1,28 @@
+use crate::constants::{PrudpPacketFlags, PrudpPacketType};
+use crate::packet_dispatch_queue::PacketDispatchQueue;
+use crate::prudp::capture::{CaptureDirection, ConnectionCapture};
+use crate::prudp::endpoint::{compute_retransmit_timeout, PrudpEndpoint};
+use crate::prudp::metrics::{MetricsRecorder, MetricsSnapshot, TransportMetrics};
+use crate::prudp::packet::{PrudpPacket, PRUDP_LITE};
+use crate::prudp::rmc_call::PendingCalls;
+use crate::prudp::settings::PrudpSettings;
+use crate::prudp::sliding_window::SlidingWindow;
+use crate::prudp::stream_settings::StreamSettings;
+use crate::prudp::virtual_port::VirtualPort;
+use crate::rmc_message::{RmcMessage, RmcSettings};
+use crate::rtt::Rtt;
+use crate::socket_connection::SocketConnection;
+use crate::timeout::Timeout;
+use crate::timeout_manager::PendingPacket;
+use crate::trace::Span;
+use crate::types::pid::Pid;
 use crate::NexResult;
+use md5::{Digest, Md5};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
 
 pub trait Endpoint: Send + Sync {
 	fn send(&self, data: &[u8]) -> NexResult<()>;
@@ -10,4 +34,369 @@ pub trait Connection: Send + Sync {
 	fn disconnect(&mut self) -> NexResult<()>;
 }
 
+// Implementation of the nn::nex::EndPoint::_ConnectionState enum. Rendez-Vous
+// supports states 0-6, NEX only 0-4. The remaining two are unknown
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConnectionState {
+	#[default]
+	NotConnected,
+	Connecting,
+	Connected,
+	Disconnecting,
+	Faulty,
+}
+
+// An individual PRUDP virtual connection. A single socket may carry many of these
+pub struct PrudpConnection {
+	pub socket: SocketConnection,
+	pub id: u32,
+	pub stream_type: u8,
+	pub stream_id: u8,
+	pub default_prudp_version: u8,
+	endpoint: Weak<PrudpEndpoint>,
+	metrics: Arc<TransportMetrics>,
+	pub(crate) span: Span,
+	// RMC calls made over the connection which are waiting on a response
+	pub(crate) pending_calls: Arc<PendingCalls>,
+	inner: Mutex<ConnectionInner>,
+}
+
+pub(crate) struct ConnectionInner {
+	pub state: ConnectionState,
+	pub session_id: u8,
+	pub server_session_id: u8,
+	pub session_key: Vec<u8>,
+	pub pid: Pid,
+	pub stream_settings: StreamSettings,
+	// Connection signature for packets coming from the client, as seen by the server
+	pub signature: Vec<u8>,
+	// Connection signature for packets coming from the server, as seen by the client
+	pub server_connection_signature: Vec<u8>,
+	pub unreliable_packet_base_key: Vec<u8>,
+	pub rtt: Rtt,
+	pub sliding_windows: HashMap<u8, SlidingWindow>,
+	pub packet_dispatch_queues: HashMap<u8, PacketDispatchQueue>,
+	pub incoming_fragment_buffers: HashMap<u8, Vec<u8>>,
+	pub outgoing_unreliable_sequence_id: u16,
+	pub outgoing_ping_sequence_id: u16,
+	pub last_sent_ping_time: Option<Instant>,
+	pub last_heard: Instant,
+	pub ping_kick_started: Option<Instant>,
+	pub metrics: MetricsRecorder,
+	// Set while the server is capturing the traffic of this connection
+	pub capture: Option<ConnectionCapture>,
+	// Set on secure connections from the CONNECT until the notifications kept
+	// while the user was offline are sent. New notifications are kept for them
+	// until then, so they cannot overtake the older ones
+	pub delivering_notifications: bool,
+}
+
+impl ConnectionInner {
+	// Resets the connection state to all zero values
+	pub fn reset(&mut self) {
+		self.state = ConnectionState::NotConnected;
+		self.packet_dispatch_queues.clear();
+		self.sliding_windows.clear();
+		self.incoming_fragment_buffers.clear();
+		self.signature.clear();
+		self.server_connection_signature.clear();
+		self.session_key.clear();
+		self.outgoing_unreliable_sequence_id = 1;
+		self.outgoing_ping_sequence_id = 0;
+		self.ping_kick_started = None;
+		self.delivering_notifications = false;
+	}
+
+	pub fn initialize_sliding_windows(&mut self, max_substream_id: u8) {
+		self.sliding_windows.clear();
+		for substream_id in 0..=max_substream_id { self.sliding_window(substream_id); }
+	}
+
+	pub fn initialize_packet_dispatch_queues(&mut self, max_substream_id: u8) {
+		self.packet_dispatch_queues.clear();
+		for substream_id in 0..=max_substream_id { self.packet_dispatch_queue(substream_id); }
+	}
+
+	// The connection may not always have the negotiated number of substreams, so
+	// missing windows and queues are created on demand
+	pub fn sliding_window(&mut self, substream_id: u8) -> &mut SlidingWindow {
+		let settings = &self.stream_settings;
+		self.sliding_windows.entry(substream_id).or_insert_with(|| SlidingWindow::new(settings.clone()))
+	}
+
+	pub fn packet_dispatch_queue(&mut self, substream_id: u8) -> &mut PacketDispatchQueue {
+		self.packet_dispatch_queues.entry(substream_id).or_default()
+	}
+
+	pub fn acknowledge_packet(&mut self, substream_id: u8, sequence_id: u16, now: Instant) {
+		let rtt_retransmit = self.stream_settings.rtt_retransmit;
+		let Some(pending) = self.sliding_window(substream_id).timeout_manager.acknowledge_packet(sequence_id) else { return };
+		// Resent packets are left out of RTT calculations, matching TCP
+		if pending.send_count < rtt_retransmit {
+			let sample = now.saturating_duration_since(pending.sent_at);
+			self.rtt.adjust(sample);
+			self.metrics.rtt(sample);
+		}
+	}
+
+	pub fn has_pending_packets(&self) -> bool { self.sliding_windows.values().any(|window| !window.timeout_manager.is_empty()) }
+
+	// Stops every pending retransmission and returns the connection to its initial state
+	pub fn cleanup(&mut self) {
+		for window in self.sliding_windows.values_mut() { window.timeout_manager.stop(); }
+		self.reset();
+	}
+
+	pub fn reset_heartbeat(&mut self, now: Instant) {
+		self.last_heard = now;
+		self.ping_kick_started = None;
+	}
+
+	// Sets the session key and derives the keys of every reliable substream and of
+	// unreliable packets from it
+	pub fn set_session_key(&mut self, session_key: &[u8]) {
+		self.session_key = session_key.to_vec();
+
+		// Every substream after the first modifies the first half of the previous key
+		let mut key = session_key.to_vec();
+		let mut substream_ids: Vec<u8> = self.sliding_windows.keys().copied().collect();
+		substream_ids.sort_unstable();
+		for substream_id in substream_ids {
+			if substream_id != 0 {
+				let half = key.len() / 2;
+				for (i, byte) in key.iter_mut().take(half).enumerate() {
+					*byte = byte.wrapping_add((half + 1 - i) as u8);
+				}
+			}
+			self.sliding_window(substream_id).set_cipher_key(&key);
+		}
+
+		let mut first_half = Md5::new();
+		first_half.update(session_key);
+		first_half.update([0x18, 0xD8, 0x23, 0x34, 0x37, 0xE4, 0xE3, 0xFE]);
+		let mut second_half = Md5::new();
+		second_half.update(session_key);
+		second_half.update([0x23, 0x3E, 0x60, 0x01, 0x23, 0xCD, 0xAB, 0x80]);
+		self.unreliable_packet_base_key = [first_half.finalize(), second_half.finalize()].concat();
+	}
+
+	// Sequences, encrypts and signs a single packet and returns its encoded bytes.
+	// Reliable packets are kept for retransmission until acknowledged
+	pub fn prepare_packet(&mut self, settings: &PrudpSettings, mut packet: PrudpPacket, now: Instant) -> NexResult<Vec<u8>> {
+		let substream_id = packet.header.substream_id;
+		let is_ack = packet.is_ack();
+		let is_reliable = packet.has_flag(PrudpPacketFlags::RELIABLE);
+
+		if !is_ack {
+			packet.header.sequence_id = if is_reliable {
+				self.sliding_window(substream_id).next_outgoing_sequence_id()
+			} else if packet.is_type(PrudpPacketType::Data) {
+				self.outgoing_unreliable_sequence_id = self.outgoing_unreliable_sequence_id.wrapping_add(1);
+				self.outgoing_unreliable_sequence_id
+			} else if packet.is_type(PrudpPacketType::Ping) {
+				self.outgoing_ping_sequence_id = self.outgoing_ping_sequence_id.wrapping_add(1);
+				self.last_sent_ping_time = Some(now);
+				self.outgoing_ping_sequence_id
+			} else {
+				0
+			};
+		}
+		packet.header.session_id = self.server_session_id;
+		// Captures are written with the payload as it was before compression and encryption
+		let plaintext = self.capture.as_ref().map(|_| packet.payload.clone());
+
+		if packet.is_type(PrudpPacketType::Data) && !is_ack {
+			if is_reliable {
+				let window = self.sliding_window(substream_id);
+				let compressed = window.stream_settings.compression_algorithm.compress(&packet.payload)?;
+				// PRUDPLite does not encrypt payloads, since they go over WSS
+				packet.payload = if packet.version() == PRUDP_LITE { compressed } else { window.encrypt(&compressed)? };
+			} else if packet.version() != PRUDP_LITE {
+				packet.payload = packet.process_unreliable_crypto(&self.unreliable_packet_base_key);
+			}
+		}
+
+		let connection_signature = if settings.prudp_v1.legacy_connection_signature { &self.signature } else { &self.server_connection_signature };
+		packet.header.signature = packet.calculate_signature(settings, &self.session_key, connection_signature);
+		let data = packet.to_bytes(settings);
+		self.metrics.outgoing(packet.header.type_id, data.len());
+		if let (Some(capture), Some(plaintext)) = (&self.capture, plaintext) {
+			capture.record_packet(CaptureDirection::Outgoing, &PrudpPacket { header: packet.header.clone(), payload: plaintext, ..Default::default() });
+		}
+
+		if is_reliable && packet.has_flag(PrudpPacketFlags::NEEDS_ACK) {
+			let timeout = compute_retransmit_timeout(&self.stream_settings, &self.rtt, 1);
+			self.sliding_window(substream_id).timeout_manager.schedule_packet_timeout(PendingPacket {
+				sequence_id: packet.header.sequence_id,
+				data: data.clone(),
+				send_count: 1,
+				sent_at: now,
+				timeout: Timeout(timeout.as_millis() as u64),
+			});
+		}
+		Ok(data)
+	}
 
+	// Drives the retransmission and heartbeat timers
+	pub fn tick(&mut self, now: Instant) -> ConnectionTick {
+		let mut tick = ConnectionTick::default();
+		if self.state == ConnectionState::Connected {
+			let ConnectionInner { sliding_windows, rtt, stream_settings, metrics, .. } = self;
+			for window in sliding_windows.values_mut() {
+				let timeouts = window.timeout_manager.tick(now, stream_settings.max_packet_retransmissions, |send_count| compute_retransmit_timeout(stream_settings, rtt, send_count));
+				for data in &timeouts.resend { metrics.retransmission(data.len()); }
+				tick.resend.extend(timeouts.resend);
+				tick.dead |= timeouts.expired;
+			}
+		}
+
+		// If the other side has not been heard from, send a PING to try and kick start
+		// the heartbeat again. If it still stays silent, assume the connection is dead
+		let max_silence_time = Duration::from_millis(self.stream_settings.max_silence_time as u64);
+		match self.ping_kick_started {
+			Some(started) => tick.dead |= now.saturating_duration_since(started) >= max_silence_time,
+			None if now.saturating_duration_since(self.last_heard) >= max_silence_time => {
+				if self.state == ConnectionState::Connected {
+					self.ping_kick_started = Some(now);
+					tick.ping = true;
+				} else {
+					tick.dead = true;
+				}
+			}
+			None => {}
+		}
+		tick
+	}
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionTick {
+	pub resend: Vec<Vec<u8>>,
+	pub dead: bool,
+	pub ping: bool,
+}
+
+impl PrudpConnection {
+	pub(crate) fn new(socket: SocketConnection, endpoint: Weak<PrudpEndpoint>, id: u32, port: VirtualPort, version: u8, stream_settings: StreamSettings, now: Instant) -> Self {
+		let metrics = Arc::new(TransportMetrics::default());
+		let inner = ConnectionInner {
+			state: ConnectionState::NotConnected,
+			session_id: 0,
+			server_session_id: 0,
+			session_key: Vec::new(),
+			pid: Pid::default(),
+			stream_settings,
+			signature: Vec::new(),
+			server_connection_signature: Vec::new(),
+			unreliable_packet_base_key: Vec::new(),
+			rtt: Rtt::new(),
+			sliding_windows: HashMap::new(),
+			packet_dispatch_queues: HashMap::new(),
+			incoming_fragment_buffers: HashMap::new(),
+			outgoing_unreliable_sequence_id: 1,
+			outgoing_ping_sequence_id: 0,
+			last_sent_ping_time: None,
+			last_heard: now,
+			ping_kick_started: None,
+			delivering_notifications: false,
+			metrics: MetricsRecorder::new(metrics.clone(), endpoint.upgrade().map(|endpoint| endpoint.metrics.clone())),
+			capture: None,
+		};
+		let span = Span::connection(socket.address(), id);
+		Self { socket, id, stream_type: port.stream_type, stream_id: port.stream_id, default_prudp_version: version, endpoint, metrics, span, pending_calls: Arc::new(PendingCalls::new()), inner: Mutex::new(inner) }
+	}
+
+	pub(crate) fn lock(&self) -> MutexGuard<'_, ConnectionInner> { self.inner.lock().unwrap() }
+
+	pub fn endpoint(&self) -> Option<Arc<PrudpEndpoint>> { self.endpoint.upgrade() }
+
+	pub fn address(&self) -> SocketAddr { self.socket.address() }
+
+	pub fn pid(&self) -> Pid { self.lock().pid }
+
+	pub fn set_pid(&self, pid: Pid) {
+		self.lock().pid = pid;
+		self.span.record_pid(pid);
+		if let Some(endpoint) = self.endpoint() { endpoint.connections.update_pid(self); }
+	}
+
+	pub fn connection_state(&self) -> ConnectionState { self.lock().state }
+
+	pub fn session_key(&self) -> Vec<u8> { self.lock().session_key.clone() }
+
+	pub fn rtt(&self) -> Rtt { self.lock().rtt.clone() }
+
+	pub fn metrics(&self) -> MetricsSnapshot { self.metrics.snapshot() }
+
+	// The number of RMC calls made over the connection still waiting on a response
+	pub fn pending_call_count(&self) -> usize { self.pending_calls.len() }
+
+	// Queues a reliable packet and reads the RMC messages of every packet which is now
+	// in order. Every packet taken off the queue must be processed to keep the RC4
+	// streams in sync, so a bad message does not stop the rest from being handled
+	pub(crate) fn read_reliable_messages(self: &Arc<Self>, packet: PrudpPacket, rmc_settings: &RmcSettings) -> (Vec<PrudpPacket>, NexResult<()>) {
+		let substream_id = packet.header.substream_id;
+		let mut inner = self.lock();
+		let capture = inner.capture.clone();
+		let queue = inner.packet_dispatch_queue(substream_id);
+		queue.enqueue(packet);
+		let ready = queue.drain();
+
+		let mut messages = Vec::new();
+		let mut result = Ok(());
+		for mut next in ready {
+			// PRUDPLite does not encrypt payloads, since they go over WSS
+			let decrypted = if next.version() == PRUDP_LITE {
+				next.payload.clone()
+			} else {
+				match inner.sliding_window(substream_id).decrypt(&next.payload) {
+					Ok(decrypted) => decrypted,
+					Err(err) => { result = Err(err); continue; }
+				}
+			};
+			let decompressed = match inner.stream_settings.compression_algorithm.decompress(&decrypted) {
+				Ok(decompressed) => decompressed,
+				Err(err) => { result = Err(err); continue; }
+			};
+			if let Some(capture) = &capture {
+				capture.record_packet(CaptureDirection::Incoming, &PrudpPacket { header: next.header.clone(), payload: decompressed.clone(), ..Default::default() });
+			}
+
+			let buffer = inner.incoming_fragment_buffers.entry(substream_id).or_default();
+			let is_reassembled = !buffer.is_empty();
+			buffer.extend_from_slice(&decompressed);
+			if next.header.fragment_id != 0 {
+				// Messages which outgrow their length prefix are dropped straight away
+				if let Err(err) = RmcMessage::check_partial(buffer) {
+					buffer.clear();
+					result = Err(err);
+				}
+				continue;
+			}
+			let buffer = std::mem::take(buffer);
+			if is_reassembled { inner.metrics.fragment_reassembly(); }
+			if let Some(capture) = &capture { capture.record_rmc(CaptureDirection::Incoming, &buffer); }
+
+			match RmcMessage::from_bytes(buffer, rmc_settings) {
+				Ok(message) => {
+					next.rmc_message = Some(message);
+					next.sender = Some(self.clone());
+					messages.push(next);
+				}
+				Err(err) => result = Err(err),
+			}
+		}
+		(messages, result)
+	}
+}
+
+impl fmt::Debug for PrudpConnection {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("PrudpConnection")
+			.field("address", &self.socket.address())
+			.field("id", &self.id)
+			.field("stream_type", &self.stream_type)
+			.field("stream_id", &self.stream_id)
+			.finish_non_exhaustive()
+	}
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// Limits on the connections an endpoint accepts. Half-open connections have been
+// sent a SYN ACK but have not completed the CONNECT yet. None disables a limit
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+	pub max_half_open: Option<usize>,
+	pub max_half_open_per_ip: Option<usize>,
+	pub max_established: Option<usize>,
+	pub max_established_per_ip: Option<usize>,
+	// SYN packets answered per second across all clients. The rest are dropped
+	pub max_syns_per_second: Option<u32>,
+}
+
+impl Default for ConnectionLimits {
+	fn default() -> Self {
+		Self {
+			max_half_open: Some(1024),
+			max_half_open_per_ip: Some(16),
+			// Many clients may share an address behind NAT
+			max_established: None,
+			max_established_per_ip: None,
+			max_syns_per_second: Some(1000),
+		}
+	}
+}
+
+#[derive(Debug, Default)]
+struct ConnectionCounts {
+	total: usize,
+	by_ip: HashMap<IpAddr, usize>,
+}
+
+impl ConnectionCounts {
+	fn has_room(&self, ip: IpAddr, max: Option<usize>, max_per_ip: Option<usize>) -> bool {
+		max.is_none_or(|max| self.total < max) && max_per_ip.is_none_or(|max| self.by_ip.get(&ip).copied().unwrap_or(0) < max)
+	}
+
+	fn add(&mut self, ip: IpAddr) {
+		self.total += 1;
+		*self.by_ip.entry(ip).or_default() += 1;
+	}
+
+	fn remove(&mut self, ip: IpAddr) {
+		self.total -= 1;
+		if let Some(count) = self.by_ip.get_mut(&ip) {
+			*count -= 1;
+			if *count == 0 { self.by_ip.remove(&ip); }
+		}
+	}
+}
+
+// Counts the half-open and established connections of an endpoint by connection
+// ID, and the SYN packets received in the current one second window
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionTracker {
+	connections: HashMap<u32, (IpAddr, bool)>,
+	half_open: ConnectionCounts,
+	established: ConnectionCounts,
+	syn_window_start: Option<Instant>,
+	syn_count: u32,
+}
+
+impl ConnectionTracker {
+	pub(crate) fn allow_syn(&mut self, limits: &ConnectionLimits, now: Instant) -> bool {
+		let Some(max) = limits.max_syns_per_second else { return true };
+		if self.syn_window_start.is_none_or(|start| now.duration_since(start) >= Duration::from_secs(1)) {
+			self.syn_window_start = Some(now);
+			self.syn_count = 0;
+		}
+		if self.syn_count >= max { return false; }
+		self.syn_count += 1;
+		true
+	}
+
+	pub(crate) fn can_open(&self, limits: &ConnectionLimits, ip: IpAddr) -> bool {
+		self.half_open.has_room(ip, limits.max_half_open, limits.max_half_open_per_ip)
+	}
+
+	// Starts counting a new connection as half-open. Connections which are already
+	// counted are left as they are
+	pub(crate) fn open(&mut self, id: u32, ip: IpAddr) {
+		if self.connections.contains_key(&id) { return; }
+		self.connections.insert(id, (ip, false));
+		self.half_open.add(ip);
+	}
+
+	// Moves a connection back to half-open, as when a client sends another SYN
+	pub(crate) fn reopen(&mut self, id: u32, ip: IpAddr) {
+		self.remove(id);
+		self.open(id, ip);
+	}
+
+	// Counts a connection as established if the limits allow it. Connections which
+	// are already established, such as on a retransmitted CONNECT, always pass
+	pub(crate) fn try_establish(&mut self, limits: &ConnectionLimits, id: u32, ip: IpAddr) -> bool {
+		if let Some(&(_, true)) = self.connections.get(&id) { return true; }
+		if !self.established.has_room(ip, limits.max_established, limits.max_established_per_ip) { return false; }
+		self.remove(id);
+		self.connections.insert(id, (ip, true));
+		self.established.add(ip);
+		true
+	}
+
+	pub(crate) fn remove(&mut self, id: u32) {
+		match self.connections.remove(&id) {
+			Some((ip, true)) => self.established.remove(ip),
+			Some((ip, false)) => self.half_open.remove(ip),
+			None => {}
+		}
+	}
+}
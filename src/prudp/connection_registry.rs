@@ -0,0 +1,252 @@
+use crate::prudp::connection::PrudpConnection;
+use crate::prudp::virtual_port::VirtualPort;
+use crate::types::pid::Pid;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+// The connections of one endpoint. Connections are keyed by the client address
+// and the virtual port the client connected from, with secondary indexes by
+// connection ID and PID. All indexes are updated under the same lock so lookups
+// never see a connection in one index but not another
+#[derive(Debug, Default)]
+pub struct ConnectionRegistry {
+	inner: RwLock<RegistryInner>,
+}
+
+#[derive(Debug, Default)]
+struct RegistryInner {
+	by_address: HashMap<SocketAddr, HashMap<VirtualPort, Arc<PrudpConnection>>>,
+	by_id: HashMap<u32, (Arc<PrudpConnection>, Pid)>,
+	by_pid: HashMap<Pid, Vec<u32>>,
+	last_id: u32,
+}
+
+impl RegistryInner {
+	// IDs wrap around, so skip any which are still in use. 0 is never used
+	fn next_id(&mut self) -> u32 {
+		loop {
+			self.last_id = self.last_id.wrapping_add(1);
+			if self.last_id != 0 && !self.by_id.contains_key(&self.last_id) { return self.last_id; }
+		}
+	}
+
+	// Connections which have not authenticated yet all have PID 0 and are not indexed
+	fn index_pid(&mut self, pid: Pid, id: u32) {
+		if pid != Pid::default() { self.by_pid.entry(pid).or_default().push(id); }
+	}
+
+	fn unindex_pid(&mut self, pid: Pid, id: u32) {
+		if let Some(ids) = self.by_pid.get_mut(&pid) {
+			ids.retain(|&other| other != id);
+			if ids.is_empty() { self.by_pid.remove(&pid); }
+		}
+	}
+}
+
+impl ConnectionRegistry {
+	pub fn new() -> Self { Self::default() }
+
+	pub fn get(&self, address: SocketAddr, port: VirtualPort) -> Option<Arc<PrudpConnection>> {
+		self.inner.read().unwrap().by_address.get(&address)?.get(&port).cloned()
+	}
+
+	// Returns the connection for a client virtual port, creating it with a newly
+	// allocated ID if it does not exist yet
+	pub fn get_or_insert_with(&self, address: SocketAddr, port: VirtualPort, create: impl FnOnce(u32) -> PrudpConnection) -> Arc<PrudpConnection> {
+		if let Some(connection) = self.get(address, port) { return connection; }
+
+		let mut inner = self.inner.write().unwrap();
+		// Another task may have created the connection between the two locks
+		if let Some(connection) = inner.by_address.get(&address).and_then(|ports| ports.get(&port)) {
+			return connection.clone();
+		}
+		let id = inner.next_id();
+		let connection = Arc::new(create(id));
+		let pid = connection.pid();
+		inner.by_address.entry(address).or_default().insert(port, connection.clone());
+		inner.by_id.insert(id, (connection.clone(), pid));
+		inner.index_pid(pid, id);
+		connection
+	}
+
+	// Removes a connection. Returns false if it was already removed or has been
+	// replaced by a newer connection on the same virtual port
+	pub fn remove(&self, connection: &PrudpConnection) -> bool {
+		let mut inner = self.inner.write().unwrap();
+		let address = connection.address();
+		let port = VirtualPort::new(connection.stream_type, connection.stream_id);
+		let Some(ports) = inner.by_address.get_mut(&address) else { return false };
+		if ports.get(&port).is_none_or(|current| current.id != connection.id) { return false; }
+		ports.remove(&port);
+		if ports.is_empty() { inner.by_address.remove(&address); }
+		if let Some((_, pid)) = inner.by_id.remove(&connection.id) { inner.unindex_pid(pid, connection.id); }
+		true
+	}
+
+	// Moves a connection to the PID index of its current PID
+	pub fn update_pid(&self, connection: &PrudpConnection) {
+		let mut inner = self.inner.write().unwrap();
+		let pid = connection.pid();
+		let Some(old_pid) = inner.by_id.get_mut(&connection.id).map(|(_, indexed)| std::mem::replace(indexed, pid)) else { return };
+		if old_pid == pid { return; }
+		inner.unindex_pid(old_pid, connection.id);
+		inner.index_pid(pid, connection.id);
+	}
+
+	pub fn find_by_id(&self, id: u32) -> Option<Arc<PrudpConnection>> {
+		self.inner.read().unwrap().by_id.get(&id).map(|(connection, _)| connection.clone())
+	}
+
+	// Returns the most recent connection made by a user
+	pub fn find_by_pid(&self, pid: Pid) -> Option<Arc<PrudpConnection>> {
+		let inner = self.inner.read().unwrap();
+		let id = inner.by_pid.get(&pid)?.last()?;
+		inner.by_id.get(id).map(|(connection, _)| connection.clone())
+	}
+
+	pub fn find_all_by_pid(&self, pid: Pid) -> Vec<Arc<PrudpConnection>> {
+		let inner = self.inner.read().unwrap();
+		let Some(ids) = inner.by_pid.get(&pid) else { return Vec::new() };
+		ids.iter().filter_map(|id| inner.by_id.get(id)).map(|(connection, _)| connection.clone()).collect()
+	}
+
+	pub fn find_by_address(&self, address: SocketAddr) -> Vec<Arc<PrudpConnection>> {
+		let inner = self.inner.read().unwrap();
+		inner.by_address.get(&address).map(|ports| ports.values().cloned().collect()).unwrap_or_default()
+	}
+
+	pub fn all(&self) -> Vec<Arc<PrudpConnection>> {
+		self.inner.read().unwrap().by_id.values().map(|(connection, _)| connection.clone()).collect()
+	}
+
+	pub fn len(&self) -> usize { self.inner.read().unwrap().by_id.len() }
+
+	pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::prudp::endpoint::PrudpEndpoint;
+	use crate::prudp::stream_settings::StreamSettings;
+	use crate::socket_connection::SocketConnection;
+	use crate::transport::channel::ChannelTransport;
+	use std::collections::HashSet;
+	use std::time::Instant;
+
+	const THREADS: u16 = 8;
+	const ROUNDS: u16 = 300;
+
+	fn address(thread: u16, round: u16) -> SocketAddr { SocketAddr::from(([127, 0, (thread >> 8) as u8, thread as u8], 10000 + round)) }
+
+	fn connect(endpoint: &Arc<PrudpEndpoint>, address: SocketAddr, port: VirtualPort) -> Arc<PrudpConnection> {
+		let (transport, _) = ChannelTransport::pair(address, address);
+		let socket = SocketConnection::datagram(address, Arc::new(transport));
+		endpoint.connections.get_or_insert_with(address, port, |id| PrudpConnection::new(socket, Arc::downgrade(endpoint), id, port, 1, StreamSettings::new(), Instant::now()))
+	}
+
+	fn assert_consistent(registry: &ConnectionRegistry) {
+		let inner = registry.inner.read().unwrap();
+		let by_address: usize = inner.by_address.values().map(HashMap::len).sum();
+		assert_eq!(by_address, inner.by_id.len());
+		for (id, (connection, pid)) in &inner.by_id {
+			assert_eq!(connection.id, *id);
+			assert_eq!(connection.pid(), *pid);
+			let indexed = inner.by_pid.get(pid).map_or(0, |ids| ids.iter().filter(|&other| other == id).count());
+			assert_eq!(indexed, usize::from(*pid != Pid::default()));
+		}
+		let by_pid: usize = inner.by_pid.values().map(Vec::len).sum();
+		assert_eq!(by_pid, inner.by_id.values().filter(|(_, pid)| *pid != Pid::default()).count());
+	}
+
+	#[test]
+	fn concurrent_inserts_allocate_unique_ids() {
+		let endpoint = Arc::new(PrudpEndpoint::new(1));
+		let ids: Vec<u32> = std::thread::scope(|scope| {
+			let workers: Vec<_> = (0..THREADS).map(|thread| {
+				let endpoint = &endpoint;
+				scope.spawn(move || (0..ROUNDS).map(|round| connect(endpoint, address(thread, round), VirtualPort::new(10, 15)).id).collect::<Vec<_>>())
+			}).collect();
+			workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+		});
+
+		assert_eq!(ids.iter().collect::<HashSet<_>>().len(), ids.len());
+		assert_eq!(endpoint.connections.len(), ids.len());
+		for id in ids { assert_eq!(endpoint.connections.find_by_id(id).unwrap().id, id); }
+		assert_consistent(&endpoint.connections);
+	}
+
+	#[test]
+	fn concurrent_connects_on_one_port_share_a_connection() {
+		let endpoint = Arc::new(PrudpEndpoint::new(1));
+		let ids: HashSet<u32> = std::thread::scope(|scope| {
+			let workers: Vec<_> = (0..THREADS).map(|_| scope.spawn(|| connect(&endpoint, address(0, 0), VirtualPort::new(10, 15)).id)).collect();
+			workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+		});
+
+		assert_eq!(ids.len(), 1);
+		assert_eq!(endpoint.connections.len(), 1);
+	}
+
+	#[test]
+	fn concurrent_churn_leaves_no_stale_entries() {
+		let endpoint = Arc::new(PrudpEndpoint::new(1));
+		std::thread::scope(|scope| {
+			for thread in 0..THREADS {
+				let endpoint = &endpoint;
+				scope.spawn(move || {
+					for round in 0..ROUNDS {
+						let connection = connect(endpoint, address(thread, round % 4), VirtualPort::new(10, (round % 2) as u8));
+						// Several threads log in as the same users
+						connection.set_pid(Pid(u64::from(round % 5) + 1));
+						assert!(endpoint.connections.find_all_by_pid(connection.pid()).iter().any(|other| other.id == connection.id));
+						if round % 3 != 0 { connection.set_pid(Pid(u64::from(thread) + 100)); }
+						assert!(endpoint.connections.remove(&connection));
+						assert!(!endpoint.connections.remove(&connection));
+					}
+				});
+			}
+		});
+
+		assert!(endpoint.connections.is_empty());
+		assert!(endpoint.connections.inner.read().unwrap().by_pid.is_empty());
+		assert!(endpoint.connections.inner.read().unwrap().by_address.is_empty());
+	}
+
+	#[test]
+	fn concurrent_set_pid_keeps_pid_index_in_sync() {
+		let endpoint = Arc::new(PrudpEndpoint::new(1));
+		let connections: Vec<_> = (0..64).map(|i| connect(&endpoint, address(0, i), VirtualPort::new(10, 15))).collect();
+		std::thread::scope(|scope| {
+			for thread in 0..THREADS {
+				let connections = &connections;
+				scope.spawn(move || {
+					for round in 0..ROUNDS {
+						let connection = &connections[usize::from(thread * 7 + round) % connections.len()];
+						connection.set_pid(Pid(u64::from(round % 4)));
+					}
+				});
+			}
+		});
+
+		// Every connection is indexed under whichever PID was set last
+		assert_consistent(&endpoint.connections);
+		for connection in &connections {
+			let pid = connection.pid();
+			if pid != Pid::default() { assert!(endpoint.connections.find_all_by_pid(pid).iter().any(|other| other.id == connection.id)); }
+		}
+	}
+
+	#[test]
+	fn replaced_connection_is_not_removed_by_its_predecessor() {
+		let endpoint = Arc::new(PrudpEndpoint::new(1));
+		let port = VirtualPort::new(10, 15);
+		let old = connect(&endpoint, address(0, 0), port);
+		assert!(endpoint.connections.remove(&old));
+		let new = connect(&endpoint, address(0, 0), port);
+		assert_ne!(old.id, new.id);
+		assert!(!endpoint.connections.remove(&old));
+		assert_eq!(endpoint.connections.get(address(0, 0), port).unwrap().id, new.id);
+	}
+}
@@ -1,12 +1,701 @@
+use crate::account::Account;
+use crate::constants::{PrudpPacketFlags, PrudpPacketType};
+use crate::error::NexError;
+use crate::io::{ByteStreamIn, ByteStreamOut, ByteStreamSettings, LibraryVersions};
+use crate::kerberos::{derive_kerberos_key, KerberosEncryption, KerberosTicketInternalData};
+use crate::prudp::capture::CaptureDirection;
+use crate::prudp::connection::{ConnectionInner, ConnectionState, PrudpConnection};
+use crate::prudp::connection_limits::{ConnectionLimits, ConnectionTracker};
+use crate::prudp::connection_registry::ConnectionRegistry;
+use crate::prudp::handlers::{handler, Handler, HandlerList};
+use crate::prudp::metrics::{EndpointMetrics, TransportMetrics};
+use crate::prudp::notifications::OfflineNotifications;
+use crate::prudp::packet::{PrudpPacket, PRUDP_LITE, PRUDP_V0, PRUDP_V1};
+use crate::prudp::server::PrudpServer;
+use crate::prudp::stream_settings::StreamSettings;
+use crate::prudp::virtual_port::VirtualPort;
+use crate::result_codes::RENDEZ_VOUS_MAX_CONNECTIONS_REACHED;
+use crate::rmc_message::{RmcMessage, RmcSettings};
+use crate::rtt::Rtt;
+use crate::rmc_interceptor::RmcInterceptor;
+use crate::rmc_rate_limit::RateLimiter;
+use crate::service_protocol::ServiceProtocol;
+use crate::socket_connection::{SendBatch, SocketConnection};
+use crate::trace::{event, Span};
+use crate::types::buffer::Buffer;
+use crate::types::pid::Pid;
 use crate::NexResult;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Default)]
-pub struct PrudpEndpoint;
+// How long a Kerberos ticket is accepted after being issued
+const KERBEROS_TICKET_LIFETIME: Duration = Duration::from_secs(2 * 60);
+
+// Implementation of rdv::PRUDPEndPoint. Handles the PRUDP virtual connections
+// made to one stream ID on a server
+pub struct PrudpEndpoint {
+	pub stream_id: u8,
+	pub default_stream_settings: StreamSettings,
+	// Secure endpoints authenticate clients with a Kerberos ticket in the CONNECT
+	// packet, decrypted using the key of the server account
+	pub is_secure_endpoint: bool,
+	pub server_account: Option<Account>,
+	pub connection_limits: ConnectionLimits,
+	// Quazal titles send verbose RMC messages, which name the protocol and method
+	pub use_verbose_rmc: bool,
+	// How long RMC calls made to clients wait for a response. None waits until
+	// the connection closes
+	pub call_timeout: Option<Duration>,
+	// How many notifications are kept for a user who is offline. None are kept when 0
+	pub offline_notification_limit: usize,
+	// How many are kept for all offline users together. Further notifications are
+	// dropped until some are delivered or expire. None has no limit
+	pub offline_notification_total_limit: Option<usize>,
+	// How long a notification is kept before the timer drops it. None keeps it
+	// until the user connects
+	pub offline_notification_ttl: Option<Duration>,
+	// Used to decode RMC messages and their parameters
+	pub byte_stream_settings: ByteStreamSettings,
+	pub library_versions: LibraryVersions,
+	server: OnceLock<Weak<PrudpServer>>,
+	pub connections: ConnectionRegistry,
+	tracker: Mutex<ConnectionTracker>,
+	pub(crate) metrics: Arc<TransportMetrics>,
+	data_handlers: HandlerList<PrudpPacket>,
+	error_handlers: HandlerList<NexError>,
+	disconnect_handlers: HandlerList<PrudpPacket>,
+	connection_ended_handlers: HandlerList<Arc<PrudpConnection>>,
+	packet_handlers: Mutex<HashMap<u16, Handler<PrudpPacket>>>,
+	pub(crate) service_protocols: Mutex<HashMap<u16, Arc<dyn ServiceProtocol>>>,
+	pub(crate) rmc_interceptors: Mutex<Vec<Arc<dyn RmcInterceptor>>>,
+	pub(crate) offline_notifications: Mutex<OfflineNotifications>,
+	pub(crate) rate_limiter: Mutex<RateLimiter>,
+}
 
 impl PrudpEndpoint {
-	pub fn new() -> Self { Self }
-	pub fn send(&self, _data: &[u8]) -> NexResult<()> { Ok(()) }
-	pub fn receive(&self) -> NexResult<Vec<u8>> { Ok(Vec::new()) }
+	pub fn new(stream_id: u8) -> Self {
+		Self {
+			stream_id,
+			default_stream_settings: StreamSettings::new(),
+			is_secure_endpoint: false,
+			server_account: None,
+			connection_limits: ConnectionLimits::default(),
+			use_verbose_rmc: false,
+			call_timeout: None,
+			offline_notification_limit: 0,
+			offline_notification_total_limit: None,
+			offline_notification_ttl: None,
+			byte_stream_settings: ByteStreamSettings::default(),
+			library_versions: LibraryVersions,
+			server: OnceLock::new(),
+			connections: ConnectionRegistry::new(),
+			tracker: Mutex::new(ConnectionTracker::default()),
+			metrics: Arc::new(TransportMetrics::default()),
+			data_handlers: HandlerList::new(),
+			error_handlers: HandlerList::new(),
+			disconnect_handlers: HandlerList::new(),
+			connection_ended_handlers: HandlerList::new(),
+			packet_handlers: Mutex::new(HashMap::new()),
+			service_protocols: Mutex::new(HashMap::new()),
+			rmc_interceptors: Mutex::new(Vec::new()),
+			offline_notifications: Mutex::new(OfflineNotifications::default()),
+			rate_limiter: Mutex::new(RateLimiter::default()),
+		}
+	}
+
+	pub fn server(&self) -> Option<Arc<PrudpServer>> { self.server.get().and_then(Weak::upgrade) }
+
+	// The time on the clock of the server, or the system clock before the
+	// endpoint is bound
+	pub(crate) fn now(&self) -> Instant { self.server().map_or_else(Instant::now, |server| server.clock.now()) }
+
+	pub(crate) fn set_server(&self, server: Weak<PrudpServer>) -> NexResult<()> {
+		self.server.set(server).map_err(|_| NexError::Connection(format!("PRUDPEndPoint {} is already bound to a server", self.stream_id)))
+	}
+
+	pub fn find_connection_by_id(&self, id: u32) -> Option<Arc<PrudpConnection>> { self.connections.find_by_id(id) }
+
+	pub fn find_connection_by_pid(&self, pid: Pid) -> Option<Arc<PrudpConnection>> { self.connections.find_by_pid(pid) }
+
+	pub fn rmc_settings(&self) -> RmcSettings {
+		RmcSettings { verbose: self.use_verbose_rmc, is_hpp: false, library_versions: Some(self.library_versions.clone()), byte_stream_settings: Some(self.byte_stream_settings.clone()) }
+	}
+
+	// Streams for reading and writing RMC parameters with the settings of the endpoint
+	pub fn byte_stream_in(&self, data: Vec<u8>) -> ByteStreamIn { ByteStreamIn::new(data, Some(self.library_versions.clone()), Some(self.byte_stream_settings.clone())) }
+
+	pub fn byte_stream_out(&self) -> ByteStreamOut { ByteStreamOut::new(Some(self.library_versions.clone()), Some(self.byte_stream_settings.clone())) }
+
+	pub fn metrics(&self) -> EndpointMetrics {
+		EndpointMetrics { stream_id: self.stream_id, active_connections: self.connections.len(), totals: self.metrics.snapshot() }
+	}
+
+	// Sends a packet to the connection set as its sender
+	pub async fn send(&self, packet: PrudpPacket) -> NexResult<()> {
+		let server = self.server().ok_or_else(|| NexError::Connection(format!("PRUDPEndPoint {} is not bound to a server", self.stream_id)))?;
+		server.send(packet).await
+	}
+
+	// Adds an event handler which is fired when a new DATA packet with a complete
+	// RMC message is received. The packet sender is the connection it arrived on.
+	// Requests already answered by a registered service protocol are not passed
+	// to it, so they are not answered twice
+	pub fn on_data<F, Fut>(&self, f: F)
+	where
+		F: Fn(PrudpPacket) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = ()> + Send + 'static,
+	{
+		self.data_handlers.push(handler(f));
+	}
+
+	// Adds an event handler which is fired when an error occurs on the endpoint
+	pub fn on_error<F, Fut>(&self, f: F)
+	where
+		F: Fn(NexError) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = ()> + Send + 'static,
+	{
+		self.error_handlers.push(handler(f));
+	}
+
+	// Adds an event handler which is fired when a client sends a DISCONNECT packet
+	pub fn on_disconnect<F, Fut>(&self, f: F)
+	where
+		F: Fn(PrudpPacket) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = ()> + Send + 'static,
+	{
+		self.disconnect_handlers.push(handler(f));
+	}
+
+	// Adds an event handler which is fired when a connection is removed from the
+	// endpoint, whether by DISCONNECT, heartbeat timeout or the socket closing
+	pub fn on_connection_ended<F, Fut>(&self, f: F)
+	where
+		F: Fn(Arc<PrudpConnection>) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = ()> + Send + 'static,
+	{
+		self.connection_ended_handlers.push(handler(f));
+	}
+
+	// Sets the handler for a packet type, replacing the built in handling. ACK
+	// packets are always handled by the endpoint
+	pub fn register_custom_packet_handler<F, Fut>(&self, packet_type: u16, f: F)
+	where
+		F: Fn(PrudpPacket) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = ()> + Send + 'static,
+	{
+		self.packet_handlers.lock().unwrap().insert(packet_type, handler(f));
+	}
+
+	pub(crate) async fn emit_error(&self, err: NexError) { self.error_handlers.emit(err).await; }
+
+	pub(crate) async fn process_packet(self: &Arc<Self>, server: &PrudpServer, packet: PrudpPacket, socket: &SocketConnection) {
+		if let Err(err) = self.handle_packet(server, packet, socket).await {
+			event!(warn, address = %socket.address(), error = %err, "Failed to handle PRUDP packet");
+			self.emit_error(err).await;
+		}
+	}
+
+	// Counts a packet for the endpoint which failed its checksum, on the
+	// connection it was sent over if there is one
+	pub(crate) fn bad_checksum(&self, address: SocketAddr, source: VirtualPort) {
+		match self.connections.get(address, source) {
+			Some(connection) => connection.lock().metrics.bad_checksum(),
+			None => self.metrics.bad_checksum(),
+		}
+	}
+
+	async fn handle_packet(self: &Arc<Self>, server: &PrudpServer, packet: PrudpPacket, socket: &SocketConnection) -> NexResult<()> {
+		if packet.is_type(PrudpPacketType::Syn) && !packet.is_ack() && !self.tracker.lock().unwrap().allow_syn(&self.connection_limits, server.clock.now()) {
+			event!(debug, address = %socket.address(), "Dropped SYN over the rate limit");
+			self.metrics.incoming(packet.header.type_id, packet.size);
+			return Ok(());
+		}
+
+		let connection = match self.connections.get(socket.address(), packet.header.source) {
+			Some(connection) => connection,
+			None => match self.accept_connection(server, &packet, socket).await? {
+				Some(connection) => connection,
+				None => {
+					self.metrics.incoming(packet.header.type_id, packet.size);
+					return Ok(());
+				}
+			},
+		};
+		{
+			let mut inner = connection.lock();
+			inner.reset_heartbeat(server.clock.now());
+			inner.metrics.incoming(packet.header.type_id, packet.size);
+			// DATA is captured once it has been decrypted
+			if let Some(capture) = inner.capture.as_ref().filter(|_| packet.is_ack() || !packet.is_type(PrudpPacketType::Data)) {
+				capture.record_packet(CaptureDirection::Incoming, &packet);
+			}
+		}
+
+		let span = connection.span.clone();
+		span.instrument(self.handle_connection_packet(server, connection, packet, socket)).await
+	}
+
+	async fn handle_connection_packet(&self, server: &PrudpServer, connection: Arc<PrudpConnection>, mut packet: PrudpPacket, socket: &SocketConnection) -> NexResult<()> {
+		if packet.is_ack() {
+			// The client has seen the DISCONNECT sent during shutdown
+			if packet.is_type(PrudpPacketType::Disconnect) && connection.connection_state() == ConnectionState::Disconnecting {
+				self.cleanup_connection(&connection).await;
+				return Ok(());
+			}
+			return self.handle_acknowledgment(server, &connection, &packet);
+		}
+
+		let custom_handler = self.packet_handlers.lock().unwrap().get(&packet.header.type_id).cloned();
+		if let Some(custom_handler) = custom_handler {
+			packet.sender = Some(connection);
+			custom_handler(packet).await;
+			return Ok(());
+		}
+
+		match packet.packet_type() {
+			Some(PrudpPacketType::Syn) => self.handle_syn(server, Some(&connection), &packet, socket).await,
+			Some(PrudpPacketType::Connect) => self.handle_connect(server, &connection, &packet).await,
+			Some(PrudpPacketType::Data) => self.handle_data(server, &connection, packet).await,
+			Some(PrudpPacketType::Disconnect) => self.handle_disconnect(server, &connection, packet).await,
+			Some(PrudpPacketType::Ping) => self.handle_ping(server, &connection, &packet).await,
+			None => Err(NexError::Parse(format!("Unknown PRUDP packet type {}", packet.header.type_id))),
+		}
+	}
+
+	// Creates the connection for a packet from an unknown virtual port, or returns
+	// None if the packet cannot open one. On v1 the connection signature sent in
+	// the SYN ACK is an HMAC of the client address, which the client signs its
+	// CONNECT with. It works as a cookie, so v1 SYNs are answered without
+	// allocating anything and the connection is only created by a valid CONNECT.
+	// Other versions have to keep a half-open connection for every SYN
+	async fn accept_connection(self: &Arc<Self>, server: &PrudpServer, packet: &PrudpPacket, socket: &SocketConnection) -> NexResult<Option<Arc<PrudpConnection>>> {
+		if packet.is_ack() || server.is_shutting_down() { return Ok(None); }
+		let address = socket.address();
+		let has_custom_handler = self.packet_handlers.lock().unwrap().contains_key(&packet.header.type_id);
+		let uses_cookie = packet.version() == PRUDP_V1 && !has_custom_handler;
+
+		match packet.packet_type() {
+			Some(PrudpPacketType::Syn) if uses_cookie => {
+				event!(debug, %address, "Answering SYN without a connection");
+				self.handle_syn(server, None, packet, socket).await?;
+				return Ok(None);
+			}
+			Some(PrudpPacketType::Connect) if uses_cookie => {
+				if !self.is_valid_connect_signature(server, packet, address) {
+					event!(warn, %address, "Dropped CONNECT with an invalid signature");
+					self.metrics.bad_signature();
+					return Ok(None);
+				}
+			}
+			Some(PrudpPacketType::Syn) => {}
+			_ if has_custom_handler => {}
+			_ => return Ok(None),
+		}
+
+		let source = packet.header.source;
+		let connection = {
+			let mut tracker = self.tracker.lock().unwrap();
+			// A CONNECT with a valid cookie completes the handshake straight away, so
+			// the half-open limits only apply to SYNs
+			if !uses_cookie && !tracker.can_open(&self.connection_limits, address.ip()) {
+				event!(debug, %address, "Dropped packet over the half-open connection limit");
+				return Ok(None);
+			}
+			let connection = self.connections.get_or_insert_with(address, source, |id| {
+				PrudpConnection::new(socket.clone(), Arc::downgrade(self), id, source, packet.version(), self.default_stream_settings.clone(), server.clock.now())
+			});
+			tracker.open(connection.id, address.ip());
+			connection
+		};
+		server.attach_capture(&connection);
+
+		if uses_cookie {
+			let mut inner = connection.lock();
+			inner.signature = packet.calculate_connection_signature(&server.connection_signature_key, address);
+			inner.state = ConnectionState::Connecting;
+		}
+		Ok(Some(connection))
+	}
+
+	// The client signs its CONNECT using the connection signature from the SYN ACK
+	fn is_valid_connect_signature(&self, server: &PrudpServer, packet: &PrudpPacket, address: SocketAddr) -> bool {
+		let connection_signature = packet.calculate_connection_signature(&server.connection_signature_key, address);
+		packet.header.signature == packet.calculate_signature(&server.settings, &[], &connection_signature)
+	}
+
+	fn handle_acknowledgment(&self, server: &PrudpServer, connection: &PrudpConnection, packet: &PrudpPacket) -> NexResult<()> {
+		let mut inner = connection.lock();
+		// Reliable packets cannot be acknowledged before the connection is established
+		if inner.state != ConnectionState::Connected { return Ok(()); }
+		if packet.has_flag(PrudpPacketFlags::MULTI_ACK) {
+			return handle_multi_acknowledgment(&mut inner, packet, server.clock.now());
+		}
+		// Only DATA packets are sequenced by the sliding windows. PING sequence IDs
+		// come from their own counter and would acknowledge the wrong packets
+		if packet.is_type(PrudpPacketType::Data) {
+			inner.acknowledge_packet(packet.header.substream_id, packet.header.sequence_id, server.clock.now());
+		}
+		Ok(())
+	}
+
+	// Answers a SYN. Connections are only given on versions which cannot use the
+	// connection signature as a cookie
+	async fn handle_syn(&self, server: &PrudpServer, connection: Option<&PrudpConnection>, packet: &PrudpPacket, socket: &SocketConnection) -> NexResult<()> {
+		if server.is_shutting_down() { return Ok(()); }
+
+		let mut ack = packet.reply(PrudpPacketType::Syn);
+		ack.add_flag(PrudpPacketFlags::ACK | PrudpPacketFlags::HAS_SIZE);
+		let connection_signature = packet.calculate_connection_signature(&server.connection_signature_key, socket.address());
+		ack.header.connection_signature = connection_signature.clone();
+		if packet.version() != PRUDP_V0 {
+			// The client decides the substreams and version, we only limit the functions
+			ack.header.maximum_substream_id = packet.header.maximum_substream_id;
+			ack.header.minor_version = packet.header.minor_version;
+			ack.header.supported_functions = server.supported_functions & packet.header.supported_functions;
+		}
+		ack.header.signature = ack.calculate_signature(&server.settings, &[], &[]);
+
+		let data = ack.to_bytes(&server.settings);
+		if let Some(connection) = connection {
+			event!(debug, "Received SYN");
+			self.tracker.lock().unwrap().reopen(connection.id, connection.address().ip());
+			let mut inner = connection.lock();
+			inner.reset();
+			inner.signature = connection_signature;
+			inner.state = ConnectionState::Connecting;
+			inner.metrics.outgoing(ack.header.type_id, data.len());
+			if let Some(capture) = &inner.capture { capture.record_packet(CaptureDirection::Outgoing, &ack); }
+		} else {
+			self.metrics.outgoing(ack.header.type_id, data.len());
+		}
+
+		socket.send(&data).await
+	}
+
+	async fn handle_connect(&self, server: &PrudpServer, connection: &Arc<PrudpConnection>, packet: &PrudpPacket) -> NexResult<()> {
+		// Retransmissions of the CONNECT packet may arrive after the connection was reset
+		if connection.connection_state() < ConnectionState::Connecting || server.is_shutting_down() { return Ok(()); }
+		if packet.version() == PRUDP_V1 && !self.is_valid_connect_signature(server, packet, connection.address()) {
+			event!(warn, "Dropped CONNECT with an invalid signature");
+			connection.lock().metrics.bad_signature();
+			return Ok(());
+		}
+
+		let mut ack = packet.reply(PrudpPacketType::Connect);
+		ack.add_flag(PrudpPacketFlags::ACK | PrudpPacketFlags::HAS_SIZE);
+		ack.header.connection_signature = vec![0; packet.header.connection_signature.len()];
+		ack.header.session_id = packet.header.session_id;
+		ack.header.sequence_id = 1;
+
+		let mut maximum_substream_id = 0;
+		if packet.version() != PRUDP_V0 {
+			// At this stage the client and server have already negotiated these values
+			maximum_substream_id = packet.header.maximum_substream_id;
+			ack.header.maximum_substream_id = packet.header.maximum_substream_id;
+			ack.header.minor_version = packet.header.minor_version;
+			ack.header.supported_functions = packet.header.supported_functions;
+			ack.header.initial_unreliable_sequence_id = packet.header.initial_unreliable_sequence_id;
+		}
+
+		// Secure endpoints require a Kerberos ticket from the authentication server
+		let mut ticket = None;
+		if self.is_secure_endpoint {
+			let payload = {
+				let inner = connection.lock();
+				let decrypted = if server.settings.prudp_v0.encrypted_connect {
+					inner.stream_settings.encryption_algorithm.decrypt(&packet.payload)?
+				} else {
+					packet.payload.clone()
+				};
+				inner.stream_settings.compression_algorithm.decompress(&decrypted)?
+			};
+			let (session_key, pid, check_value) = self.read_kerberos_ticket(server, payload)?;
+			connection.set_pid(pid);
+			server.attach_capture(connection);
+			ticket = Some((session_key, check_value));
+		}
+
+		let ip = connection.address().ip();
+		if !self.tracker.lock().unwrap().try_establish(&self.connection_limits, connection.id, ip) {
+			event!(warn, "Refused CONNECT over the established connection limit");
+			// The client is refused with a DISCONNECT instead of the CONNECT ACK. The
+			// connection was never established, so it is removed without firing the
+			// connection ended handlers
+			{
+				let mut inner = connection.lock();
+				inner.server_connection_signature = packet.header.connection_signature.clone();
+				inner.server_session_id = packet.header.session_id;
+			}
+			let _ = self.send_disconnect(server, connection).await;
+			self.remove_connection(connection);
+			return Err(NexError::ResultCode {
+				code: RENDEZ_VOUS_MAX_CONNECTIONS_REACHED,
+				message: format!("Refused connection from {ip}, too many connections are established"),
+			});
+		}
+
+		{
+			let mut inner = connection.lock();
+			inner.server_connection_signature = packet.header.connection_signature.clone();
+			inner.session_id = packet.header.session_id;
+			inner.server_session_id = packet.header.session_id;
+			inner.initialize_sliding_windows(maximum_substream_id);
+			inner.initialize_packet_dispatch_queues(maximum_substream_id);
+			if packet.version() != PRUDP_V0 {
+				inner.outgoing_unreliable_sequence_id = packet.header.initial_unreliable_sequence_id;
+			}
+
+			let mut payload = Vec::new();
+			if let Some((session_key, check_value)) = &ticket {
+				inner.set_session_key(session_key);
+				let mut stream = ByteStreamOut::new(None, None);
+				Buffer(check_value.wrapping_add(1).to_le_bytes().to_vec()).write_to(&mut stream);
+				payload = stream.bytes().to_vec();
+			}
+
+			let compressed = inner.stream_settings.compression_algorithm.compress(&payload)?;
+			ack.payload = if server.settings.prudp_v0.encrypted_connect {
+				inner.stream_settings.encryption_algorithm.encrypt(&compressed)?
+			} else {
+				compressed
+			};
+			inner.state = ConnectionState::Connected;
+			inner.delivering_notifications = ticket.is_some();
+		}
+
+		ack.header.signature = ack.calculate_signature(&server.settings, &[], &packet.header.connection_signature);
+		let data = ack.to_bytes(&server.settings);
+		{
+			let inner = connection.lock();
+			inner.metrics.outgoing(ack.header.type_id, data.len());
+			if let Some(capture) = &inner.capture { capture.record_packet(CaptureDirection::Outgoing, &ack); }
+		}
+		event!(info, pid = connection.pid().0, "Connection established");
+		connection.socket.send(&data).await?;
+		if ticket.is_some() { self.deliver_buffered_notifications(connection).await; }
+		Ok(())
+	}
+
+	// Reads the Kerberos ticket and check data a client sends in its CONNECT packet.
+	// Returns the session key, the PID of the user and the check value
+	fn read_kerberos_ticket(&self, server: &PrudpServer, payload: Vec<u8>) -> NexResult<(Vec<u8>, Pid, u32)> {
+		let mut stream = ByteStreamIn::new(payload, None, None);
+		let ticket_data = Buffer::read_from(&mut stream)?;
+		let request_data = Buffer::read_from(&mut stream)?;
+
+		let server_account = self.server_account.as_ref().ok_or_else(|| NexError::Connection("Failed to find endpoint server account".into()))?;
+		let server_key = derive_kerberos_key(server_account.pid, server_account.password.as_bytes());
+		let ticket_stream = ByteStreamIn::new(ticket_data.0, None, None);
+		let ticket = KerberosTicketInternalData::decrypt(ticket_stream, &server_key, server.kerberos_ticket_version, server.session_key_length)?;
+
+		if server.clock.system_time() > ticket.issued.standard() + KERBEROS_TICKET_LIFETIME {
+			return Err(NexError::Connection("Kerberos ticket expired".into()));
+		}
+
+		let decrypted = KerberosEncryption::new(&ticket.session_key).decrypt(&request_data.0)?;
+		let mut check_data = ByteStreamIn::new(decrypted, None, None);
+		let user_pid = Pid::read_from(&mut check_data)?;
+		if user_pid != ticket.source_pid {
+			return Err(NexError::Connection("User PID and ticket source PID mismatch".into()));
+		}
+		check_data.read_u32_le()?; // CID of the secure server station URL
+		let check_value = check_data.read_u32_le()?;
+		Ok((ticket.session_key, user_pid, check_value))
+	}
+
+	async fn handle_data(&self, server: &PrudpServer, connection: &Arc<PrudpConnection>, packet: PrudpPacket) -> NexResult<()> {
+		if connection.connection_state() != ConnectionState::Connected { return Ok(()); }
+		if packet.has_flag(PrudpPacketFlags::NEEDS_ACK) { self.acknowledge_packet(server, connection, &packet).await?; }
+		if packet.has_flag(PrudpPacketFlags::RELIABLE) {
+			self.handle_reliable(connection, packet).await
+		} else {
+			self.handle_unreliable(connection, packet).await
+		}
+	}
+
+	async fn handle_reliable(&self, connection: &Arc<PrudpConnection>, packet: PrudpPacket) -> NexResult<()> {
+		let (messages, result) = connection.read_reliable_messages(packet, &self.rmc_settings());
+		for message in messages { self.emit_data(message).await; }
+		result
+	}
+
+	async fn handle_unreliable(&self, connection: &Arc<PrudpConnection>, mut packet: PrudpPacket) -> NexResult<()> {
+		// Unreliable DATA packets can reach the server in any order and lack a
+		// substream, so they can never be fragmented
+		let payload = if packet.version() == PRUDP_LITE {
+			packet.payload.clone()
+		} else {
+			packet.process_unreliable_crypto(&connection.lock().unreliable_packet_base_key)
+		};
+		if let Some(capture) = connection.lock().capture.clone() {
+			capture.record_packet(CaptureDirection::Incoming, &PrudpPacket { header: packet.header.clone(), payload: payload.clone(), ..Default::default() });
+			capture.record_rmc(CaptureDirection::Incoming, &payload);
+		}
+		packet.rmc_message = Some(RmcMessage::from_bytes(payload, &self.rmc_settings())?);
+		packet.sender = Some(connection.clone());
+		self.emit_data(packet).await;
+		Ok(())
+	}
+
+	// Answers requests for registered protocols, and runs the data handlers for
+	// every other message, all inside a span for the RMC call. Responses to calls
+	// made with start_call are handed to the waiting call instead
+	async fn emit_data(&self, packet: PrudpPacket) {
+		let Some(message) = &packet.rmc_message else { return };
+		if !message.is_request() {
+			if let Some(connection) = &packet.sender {
+				if connection.pending_calls.complete(message.clone()) { return; }
+			}
+		}
+		let span = Span::rmc(message);
+		span.instrument(async {
+			if !self.dispatch_rmc(&packet).await { self.data_handlers.emit(packet).await; }
+		})
+		.await;
+	}
+
+	pub(crate) fn has_data_handlers(&self) -> bool { !self.data_handlers.is_empty() }
+
+	async fn handle_disconnect(&self, server: &PrudpServer, connection: &Arc<PrudpConnection>, mut packet: PrudpPacket) -> NexResult<()> {
+		if packet.has_flag(PrudpPacketFlags::NEEDS_ACK) { self.acknowledge_packet(server, connection, &packet).await?; }
+		event!(info, "Client disconnected");
+		self.cleanup_connection(connection).await;
+		packet.sender = Some(connection.clone());
+		self.disconnect_handlers.emit(packet).await;
+		Ok(())
+	}
+
+	async fn handle_ping(&self, server: &PrudpServer, connection: &PrudpConnection, packet: &PrudpPacket) -> NexResult<()> {
+		if packet.has_flag(PrudpPacketFlags::NEEDS_ACK) { self.acknowledge_packet(server, connection, packet).await?; }
+		Ok(())
+	}
+
+	async fn acknowledge_packet(&self, server: &PrudpServer, connection: &PrudpConnection, packet: &PrudpPacket) -> NexResult<()> {
+		let Some(packet_type) = packet.packet_type() else { return Ok(()) };
+		let mut ack = packet.reply(packet_type);
+		ack.add_flag(PrudpPacketFlags::ACK);
+		ack.header.sequence_id = packet.header.sequence_id;
+		ack.header.fragment_id = packet.header.fragment_id;
+		ack.header.substream_id = packet.header.substream_id;
+		// Servers send the DISCONNECT ACK 3 times
+		let count = if packet_type == PrudpPacketType::Disconnect { 3 } else { 1 };
+		for _ in 0..count {
+			server.send_packet(connection, ack.clone()).await?;
+		}
+		Ok(())
+	}
+
+	fn ping_packet(&self, connection: &PrudpConnection) -> PrudpPacket {
+		let mut ping = PrudpPacket::new(connection.default_prudp_version, PrudpPacketType::Ping);
+		ping.add_flag(PrudpPacketFlags::NEEDS_ACK);
+		ping.header.source = VirtualPort::new(connection.stream_type, self.stream_id);
+		ping.header.destination = VirtualPort::new(connection.stream_type, connection.stream_id);
+		ping
+	}
+
+	// Sends a DISCONNECT which the client must acknowledge. It is not sent
+	// reliably, the caller resends it until the connection is cleaned up
+	pub(crate) async fn send_disconnect(&self, server: &PrudpServer, connection: &PrudpConnection) -> NexResult<()> {
+		let mut disconnect = PrudpPacket::new(connection.default_prudp_version, PrudpPacketType::Disconnect);
+		disconnect.add_flag(PrudpPacketFlags::NEEDS_ACK);
+		disconnect.header.source = VirtualPort::new(connection.stream_type, self.stream_id);
+		disconnect.header.destination = VirtualPort::new(connection.stream_type, connection.stream_id);
+		server.send_packet(connection, disconnect).await
+	}
+
+	// Removes a connection from the endpoint and stops all of its timers
+	pub async fn cleanup_connection(&self, connection: &Arc<PrudpConnection>) {
+		if self.remove_connection(connection) {
+			connection.span.in_scope(|| event!(info, "Connection ended"));
+			self.connection_ended_handlers.emit(connection.clone()).await;
+		}
+	}
+
+	// Like cleanup_connection, without firing the connection ended handlers.
+	// Returns false if the connection had already been removed
+	fn remove_connection(&self, connection: &PrudpConnection) -> bool {
+		// The client may have already reconnected using the same virtual port
+		let removed = self.connections.remove(connection);
+		if removed { self.tracker.lock().unwrap().remove(connection.id); }
+		self.rate_limiter.lock().unwrap().remove_connection(connection.id);
+		connection.lock().cleanup();
+		connection.pending_calls.fail_all(NexError::Connection("Connection closed before a response was received".into()));
+		removed
+	}
+
+	// Removes every connection made over a socket which has been closed
+	pub(crate) async fn cleanup_socket(&self, address: SocketAddr) {
+		for connection in self.connections.find_by_address(address) { self.cleanup_connection(&connection).await; }
+	}
+
+	// Drives the retransmission and heartbeat timers of every connection
+	pub(crate) async fn tick(&self, server: &PrudpServer, now: Instant, batch: &mut SendBatch) {
+		self.expire_offline_notifications(now);
+		for connection in self.connections.all() {
+			let tick = connection.lock().tick(now);
+			if tick.dead {
+				connection.span.in_scope(|| event!(info, "Connection timed out"));
+				self.cleanup_connection(&connection).await;
+				continue;
+			}
+			if !tick.resend.is_empty() {
+				connection.span.in_scope(|| event!(debug, count = tick.resend.len(), "Retransmitting reliable packets"));
+			}
+			for data in tick.resend { batch.push(&connection.socket, data); }
+			if tick.ping {
+				if let Ok(data) = connection.lock().prepare_packet(&server.settings, self.ping_packet(&connection), now) { batch.push(&connection.socket, data); }
+			}
+		}
+	}
+}
+
+impl std::fmt::Debug for PrudpEndpoint {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("PrudpEndpoint").field("stream_id", &self.stream_id).finish_non_exhaustive()
+	}
 }
 
+pub(crate) fn handle_multi_acknowledgment(inner: &mut ConnectionInner, packet: &PrudpPacket, now: Instant) -> NexResult<()> {
+	let mut stream = ByteStreamIn::new(packet.payload.clone(), None, None);
+	let mut sequence_ids = Vec::new();
+	let substream_id;
+	let base_sequence_id;
+
+	if packet.header.substream_id == 1 {
+		// New aggregate acknowledgment packets set this to 1 and encode the real substream ID in the payload
+		substream_id = stream.read_u8()?;
+		let additional_ids_count = stream.read_u8()?;
+		base_sequence_id = stream.read_u16_le()?;
+		for _ in 0..additional_ids_count { sequence_ids.push(stream.read_u16_le()?); }
+	} else {
+		// Old aggregate acknowledgment packets always use substream 0
+		substream_id = 0;
+		base_sequence_id = packet.header.sequence_id;
+		while stream.remaining() >= 2 { sequence_ids.push(stream.read_u16_le()?); }
+	}
 
+	let pending = inner.sliding_window(substream_id).timeout_manager.pending_sequence_ids();
+	sequence_ids.extend(pending.into_iter().filter(|&sequence_id| sequence_id <= base_sequence_id));
+
+	for sequence_id in sequence_ids { inner.acknowledge_packet(substream_id, sequence_id, now); }
+	Ok(())
+}
+
+// Implementation of the rdv::PRUDPEndPoint retransmission timeout. Before any
+// RTT samples exist the stream settings provide the base timeout
+pub(crate) fn compute_retransmit_timeout(settings: &StreamSettings, rtt: &Rtt, send_count: u32) -> Duration {
+	let base = if rtt.initialized() {
+		rtt.average() + rtt.variance() * 4
+	} else {
+		Duration::from_millis(settings.initial_rtt as u64)
+	};
+	let multiplier = if send_count < settings.extra_retransmit_timeout_trigger {
+		settings.retransmit_timeout_multiplier
+	} else {
+		settings.extra_retransmit_timeout_multiplier
+	};
+	base.mul_f32(multiplier)
+}
@@ -0,0 +1,38 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+pub type HandlerFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+pub type Handler<T> = Arc<dyn Fn(T) -> HandlerFuture + Send + Sync>;
+
+// Boxes an async closure so it can be stored alongside handlers of other types
+pub fn handler<T, F, Fut>(f: F) -> Handler<T>
+where
+	F: Fn(T) -> Fut + Send + Sync + 'static,
+	Fut: Future<Output = ()> + Send + 'static,
+{
+	Arc::new(move |value| Box::pin(f(value)))
+}
+
+// A list of handlers for one event. Handlers run in the order they were added
+pub struct HandlerList<T> {
+	handlers: Mutex<Vec<Handler<T>>>,
+}
+
+impl<T: Clone> HandlerList<T> {
+	pub fn new() -> Self { Self { handlers: Mutex::new(Vec::new()) } }
+
+	pub fn push(&self, handler: Handler<T>) { self.handlers.lock().unwrap().push(handler); }
+
+	pub fn is_empty(&self) -> bool { self.handlers.lock().unwrap().is_empty() }
+
+	pub async fn emit(&self, value: T) {
+		let handlers = self.handlers.lock().unwrap().clone();
+		for handler in handlers { handler(value.clone()).await; }
+	}
+}
+
+impl<T: Clone> Default for HandlerList<T> {
+	fn default() -> Self { Self::new() }
+}
@@ -0,0 +1,342 @@
+use crate::constants::PrudpPacketType;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Upper bounds of the RTT histogram buckets. Slower samples only count towards +Inf
+pub const RTT_BUCKETS: [Duration; 10] = [
+	Duration::from_millis(5),
+	Duration::from_millis(10),
+	Duration::from_millis(25),
+	Duration::from_millis(50),
+	Duration::from_millis(100),
+	Duration::from_millis(250),
+	Duration::from_millis(500),
+	Duration::from_millis(1000),
+	Duration::from_millis(2500),
+	Duration::from_millis(5000),
+];
+
+const PACKET_TYPES: [(PrudpPacketType, &str); 5] = [
+	(PrudpPacketType::Syn, "syn"),
+	(PrudpPacketType::Connect, "connect"),
+	(PrudpPacketType::Data, "data"),
+	(PrudpPacketType::Disconnect, "disconnect"),
+	(PrudpPacketType::Ping, "ping"),
+];
+
+// Packet and byte counts by packet type, indexed by type ID
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PacketCounts {
+	pub packets: [u64; 5],
+	pub bytes: [u64; 5],
+}
+
+impl PacketCounts {
+	pub fn packets(&self, packet_type: PrudpPacketType) -> u64 { self.packets[packet_type as usize] }
+
+	pub fn bytes(&self, packet_type: PrudpPacketType) -> u64 { self.bytes[packet_type as usize] }
+
+	pub fn total_packets(&self) -> u64 { self.packets.iter().sum() }
+
+	pub fn total_bytes(&self) -> u64 { self.bytes.iter().sum() }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RttHistogram {
+	// Samples in each bucket of RTT_BUCKETS, followed by the samples over the last bound
+	pub buckets: [u64; RTT_BUCKETS.len() + 1],
+	pub count: u64,
+	pub sum: Duration,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+	pub incoming: PacketCounts,
+	pub outgoing: PacketCounts,
+	pub retransmissions: u64,
+	pub dropped_bad_signature: u64,
+	// PRUDPv0 packets whose checksum did not match
+	pub dropped_bad_checksum: u64,
+	pub fragment_reassemblies: u64,
+	pub rtt: RttHistogram,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EndpointMetrics {
+	pub stream_id: u8,
+	pub active_connections: usize,
+	// Totals over every connection the endpoint has had, including ended ones
+	pub totals: MetricsSnapshot,
+}
+
+impl EndpointMetrics {
+	pub fn to_prometheus(&self) -> String { prometheus_text(None, std::slice::from_ref(self)) }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerMetrics {
+	// Datagrams which could not be decoded and cannot be attributed to an
+	// endpoint. Bad PRUDPv0 checksums are counted by the endpoint they were sent
+	// to instead, unless no endpoint is bound to that stream ID
+	pub dropped_malformed: u64,
+	pub endpoints: Vec<EndpointMetrics>,
+}
+
+impl ServerMetrics {
+	pub fn to_prometheus(&self) -> String { prometheus_text(Some(self.dropped_malformed), &self.endpoints) }
+}
+
+#[derive(Debug, Default)]
+struct PacketCounters {
+	packets: [AtomicU64; 5],
+	bytes: [AtomicU64; 5],
+}
+
+impl PacketCounters {
+	fn record(&self, type_id: u16, size: usize) {
+		let Some(index) = PrudpPacketType::from_u16(type_id).map(|packet_type| packet_type as usize) else { return };
+		self.packets[index].fetch_add(1, Ordering::Relaxed);
+		self.bytes[index].fetch_add(size as u64, Ordering::Relaxed);
+	}
+
+	fn snapshot(&self) -> PacketCounts {
+		PacketCounts {
+			packets: self.packets.each_ref().map(|count| count.load(Ordering::Relaxed)),
+			bytes: self.bytes.each_ref().map(|count| count.load(Ordering::Relaxed)),
+		}
+	}
+}
+
+// Transport counters of an endpoint or connection. Counters are only ever added
+// to, so they can be updated without holding any lock
+#[derive(Debug, Default)]
+pub struct TransportMetrics {
+	incoming: PacketCounters,
+	outgoing: PacketCounters,
+	retransmissions: AtomicU64,
+	dropped_bad_signature: AtomicU64,
+	dropped_bad_checksum: AtomicU64,
+	fragment_reassemblies: AtomicU64,
+	rtt_buckets: [AtomicU64; RTT_BUCKETS.len() + 1],
+	rtt_sum_micros: AtomicU64,
+}
+
+impl TransportMetrics {
+	pub fn snapshot(&self) -> MetricsSnapshot {
+		let buckets = self.rtt_buckets.each_ref().map(|count| count.load(Ordering::Relaxed));
+		MetricsSnapshot {
+			incoming: self.incoming.snapshot(),
+			outgoing: self.outgoing.snapshot(),
+			retransmissions: self.retransmissions.load(Ordering::Relaxed),
+			dropped_bad_signature: self.dropped_bad_signature.load(Ordering::Relaxed),
+			dropped_bad_checksum: self.dropped_bad_checksum.load(Ordering::Relaxed),
+			fragment_reassemblies: self.fragment_reassemblies.load(Ordering::Relaxed),
+			rtt: RttHistogram { buckets, count: buckets.iter().sum(), sum: Duration::from_micros(self.rtt_sum_micros.load(Ordering::Relaxed)) },
+		}
+	}
+
+	pub(crate) fn incoming(&self, type_id: u16, size: usize) { self.incoming.record(type_id, size); }
+
+	pub(crate) fn outgoing(&self, type_id: u16, size: usize) { self.outgoing.record(type_id, size); }
+
+	// Only DATA packets are retransmitted
+	pub(crate) fn retransmission(&self, size: usize) {
+		self.outgoing(PrudpPacketType::Data as u16, size);
+		self.retransmissions.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub(crate) fn bad_signature(&self) { self.dropped_bad_signature.fetch_add(1, Ordering::Relaxed); }
+
+	pub(crate) fn bad_checksum(&self) { self.dropped_bad_checksum.fetch_add(1, Ordering::Relaxed); }
+
+	pub(crate) fn fragment_reassembly(&self) { self.fragment_reassemblies.fetch_add(1, Ordering::Relaxed); }
+
+	pub(crate) fn rtt(&self, sample: Duration) {
+		let bucket = RTT_BUCKETS.iter().position(|&bound| sample <= bound).unwrap_or(RTT_BUCKETS.len());
+		self.rtt_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+		self.rtt_sum_micros.fetch_add(sample.as_micros() as u64, Ordering::Relaxed);
+	}
+}
+
+// Records to the metrics of a connection and of the endpoint it belongs to.
+// Client connections have no endpoint
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MetricsRecorder {
+	targets: Vec<Arc<TransportMetrics>>,
+}
+
+impl MetricsRecorder {
+	pub(crate) fn new(connection: Arc<TransportMetrics>, endpoint: Option<Arc<TransportMetrics>>) -> Self {
+		Self { targets: std::iter::once(connection).chain(endpoint).collect() }
+	}
+
+	pub(crate) fn incoming(&self, type_id: u16, size: usize) { self.targets.iter().for_each(|metrics| metrics.incoming(type_id, size)); }
+
+	pub(crate) fn outgoing(&self, type_id: u16, size: usize) { self.targets.iter().for_each(|metrics| metrics.outgoing(type_id, size)); }
+
+	pub(crate) fn retransmission(&self, size: usize) { self.targets.iter().for_each(|metrics| metrics.retransmission(size)); }
+
+	pub(crate) fn bad_signature(&self) { self.targets.iter().for_each(|metrics| metrics.bad_signature()); }
+
+	pub(crate) fn bad_checksum(&self) { self.targets.iter().for_each(|metrics| metrics.bad_checksum()); }
+
+	pub(crate) fn fragment_reassembly(&self) { self.targets.iter().for_each(|metrics| metrics.fragment_reassembly()); }
+
+	pub(crate) fn rtt(&self, sample: Duration) { self.targets.iter().for_each(|metrics| metrics.rtt(sample)); }
+}
+
+// Formats metrics in the Prometheus text exposition format, labelled by stream ID
+fn prometheus_text(dropped_malformed: Option<u64>, endpoints: &[EndpointMetrics]) -> String {
+	let mut out = String::new();
+
+	for (name, help, counts_of) in [
+		("nex_prudp_packets_total", "PRUDP packets by direction and type", PacketCounts::packets as fn(&PacketCounts, PrudpPacketType) -> u64),
+		("nex_prudp_bytes_total", "PRUDP bytes by direction and type", PacketCounts::bytes),
+	] {
+		write_family(&mut out, name, "counter", help);
+		for endpoint in endpoints {
+			for (direction, counts) in [("in", &endpoint.totals.incoming), ("out", &endpoint.totals.outgoing)] {
+				for (packet_type, type_name) in PACKET_TYPES {
+					let _ = writeln!(out, "{name}{{stream_id=\"{}\",direction=\"{direction}\",type=\"{type_name}\"}} {}", endpoint.stream_id, counts_of(counts, packet_type));
+				}
+			}
+		}
+	}
+
+	for (name, kind, help, value_of) in [
+		("nex_prudp_retransmissions_total", "counter", "Reliable packets sent again after timing out", (|metrics| metrics.totals.retransmissions) as fn(&EndpointMetrics) -> u64),
+		("nex_prudp_fragment_reassemblies_total", "counter", "RMC messages reassembled from several fragments", |metrics| metrics.totals.fragment_reassemblies),
+		("nex_prudp_active_connections", "gauge", "Connections currently known to the endpoint", |metrics| metrics.active_connections as u64),
+	] {
+		write_family(&mut out, name, kind, help);
+		for endpoint in endpoints {
+			let _ = writeln!(out, "{name}{{stream_id=\"{}\"}} {}", endpoint.stream_id, value_of(endpoint));
+		}
+	}
+
+	write_family(&mut out, "nex_prudp_dropped_packets_total", "counter", "Packets dropped before being handled, by reason");
+	for endpoint in endpoints {
+		let _ = writeln!(out, "nex_prudp_dropped_packets_total{{stream_id=\"{}\",reason=\"bad_signature\"}} {}", endpoint.stream_id, endpoint.totals.dropped_bad_signature);
+		let _ = writeln!(out, "nex_prudp_dropped_packets_total{{stream_id=\"{}\",reason=\"bad_checksum\"}} {}", endpoint.stream_id, endpoint.totals.dropped_bad_checksum);
+	}
+	if let Some(dropped_malformed) = dropped_malformed {
+		let _ = writeln!(out, "nex_prudp_dropped_packets_total{{reason=\"malformed\"}} {dropped_malformed}");
+	}
+
+	write_family(&mut out, "nex_prudp_rtt_seconds", "histogram", "Round trip times of acknowledged reliable packets");
+	for endpoint in endpoints {
+		let rtt = &endpoint.totals.rtt;
+		let mut cumulative = 0;
+		for (bound, count) in RTT_BUCKETS.iter().zip(rtt.buckets) {
+			cumulative += count;
+			let _ = writeln!(out, "nex_prudp_rtt_seconds_bucket{{stream_id=\"{}\",le=\"{}\"}} {cumulative}", endpoint.stream_id, bound.as_secs_f64());
+		}
+		let _ = writeln!(out, "nex_prudp_rtt_seconds_bucket{{stream_id=\"{}\",le=\"+Inf\"}} {}", endpoint.stream_id, rtt.count);
+		let _ = writeln!(out, "nex_prudp_rtt_seconds_sum{{stream_id=\"{}\"}} {}", endpoint.stream_id, rtt.sum.as_secs_f64());
+		let _ = writeln!(out, "nex_prudp_rtt_seconds_count{{stream_id=\"{}\"}} {}", endpoint.stream_id, rtt.count);
+	}
+	out
+}
+
+fn write_family(out: &mut String, name: &str, kind: &str, help: &str) {
+	let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::constants::{PrudpPacketFlags, StreamType};
+	use crate::prudp::endpoint::PrudpEndpoint;
+	use crate::prudp::packet::{PrudpPacket, PRUDP_V0};
+	use crate::prudp::server::PrudpServer;
+	use crate::prudp::virtual_port::VirtualPort;
+	use crate::socket_connection::SocketConnection;
+	use crate::transport::channel::ChannelTransport;
+
+	// The buckets are cumulative, and the RTT sample over the last bound is only
+	// counted by +Inf, which always matches _count
+	const EXPECTED: &str = r#"# HELP nex_prudp_packets_total PRUDP packets by direction and type
+# TYPE nex_prudp_packets_total counter
+nex_prudp_packets_total{stream_id="1",direction="in",type="syn"} 0
+nex_prudp_packets_total{stream_id="1",direction="in",type="connect"} 0
+nex_prudp_packets_total{stream_id="1",direction="in",type="data"} 0
+nex_prudp_packets_total{stream_id="1",direction="in",type="disconnect"} 0
+nex_prudp_packets_total{stream_id="1",direction="in",type="ping"} 0
+nex_prudp_packets_total{stream_id="1",direction="out",type="syn"} 0
+nex_prudp_packets_total{stream_id="1",direction="out",type="connect"} 0
+nex_prudp_packets_total{stream_id="1",direction="out",type="data"} 1
+nex_prudp_packets_total{stream_id="1",direction="out",type="disconnect"} 0
+nex_prudp_packets_total{stream_id="1",direction="out",type="ping"} 0
+# HELP nex_prudp_bytes_total PRUDP bytes by direction and type
+# TYPE nex_prudp_bytes_total counter
+nex_prudp_bytes_total{stream_id="1",direction="in",type="syn"} 0
+nex_prudp_bytes_total{stream_id="1",direction="in",type="connect"} 0
+nex_prudp_bytes_total{stream_id="1",direction="in",type="data"} 0
+nex_prudp_bytes_total{stream_id="1",direction="in",type="disconnect"} 0
+nex_prudp_bytes_total{stream_id="1",direction="in",type="ping"} 0
+nex_prudp_bytes_total{stream_id="1",direction="out",type="syn"} 0
+nex_prudp_bytes_total{stream_id="1",direction="out",type="connect"} 0
+nex_prudp_bytes_total{stream_id="1",direction="out",type="data"} 40
+nex_prudp_bytes_total{stream_id="1",direction="out",type="disconnect"} 0
+nex_prudp_bytes_total{stream_id="1",direction="out",type="ping"} 0
+# HELP nex_prudp_retransmissions_total Reliable packets sent again after timing out
+# TYPE nex_prudp_retransmissions_total counter
+nex_prudp_retransmissions_total{stream_id="1"} 1
+# HELP nex_prudp_fragment_reassemblies_total RMC messages reassembled from several fragments
+# TYPE nex_prudp_fragment_reassemblies_total counter
+nex_prudp_fragment_reassemblies_total{stream_id="1"} 0
+# HELP nex_prudp_active_connections Connections currently known to the endpoint
+# TYPE nex_prudp_active_connections gauge
+nex_prudp_active_connections{stream_id="1"} 0
+# HELP nex_prudp_dropped_packets_total Packets dropped before being handled, by reason
+# TYPE nex_prudp_dropped_packets_total counter
+nex_prudp_dropped_packets_total{stream_id="1",reason="bad_signature"} 0
+nex_prudp_dropped_packets_total{stream_id="1",reason="bad_checksum"} 1
+nex_prudp_dropped_packets_total{reason="malformed"} 1
+# HELP nex_prudp_rtt_seconds Round trip times of acknowledged reliable packets
+# TYPE nex_prudp_rtt_seconds histogram
+nex_prudp_rtt_seconds_bucket{stream_id="1",le="0.005"} 1
+nex_prudp_rtt_seconds_bucket{stream_id="1",le="0.01"} 1
+nex_prudp_rtt_seconds_bucket{stream_id="1",le="0.025"} 3
+nex_prudp_rtt_seconds_bucket{stream_id="1",le="0.05"} 3
+nex_prudp_rtt_seconds_bucket{stream_id="1",le="0.1"} 3
+nex_prudp_rtt_seconds_bucket{stream_id="1",le="0.25"} 3
+nex_prudp_rtt_seconds_bucket{stream_id="1",le="0.5"} 3
+nex_prudp_rtt_seconds_bucket{stream_id="1",le="1"} 3
+nex_prudp_rtt_seconds_bucket{stream_id="1",le="2.5"} 3
+nex_prudp_rtt_seconds_bucket{stream_id="1",le="5"} 3
+nex_prudp_rtt_seconds_bucket{stream_id="1",le="+Inf"} 4
+nex_prudp_rtt_seconds_sum{stream_id="1"} 6.043
+nex_prudp_rtt_seconds_count{stream_id="1"} 4
+"#;
+
+	#[tokio::test]
+	async fn prometheus_text_snapshot() {
+		let server = Arc::new(PrudpServer::new());
+		let endpoint = Arc::new(PrudpEndpoint::new(1));
+		server.bind_prudp_endpoint(endpoint.clone()).unwrap();
+		let address = "10.0.0.2:50000".parse().unwrap();
+		let (transport, _client) = ChannelTransport::pair(address, address);
+		let socket = SocketConnection::datagram(address, Arc::new(transport));
+
+		// A SYN with a bad checksum is counted by the endpoint it was sent to, a
+		// datagram too short to decode by the server
+		let mut syn = PrudpPacket::new(PRUDP_V0, PrudpPacketType::Syn);
+		syn.add_flag(PrudpPacketFlags::NEEDS_ACK);
+		syn.header.source = VirtualPort::new(StreamType::RvSecure as u8, 15);
+		syn.header.destination = VirtualPort::new(StreamType::RvSecure as u8, 1);
+		syn.header.connection_signature = vec![0; 4];
+		syn.header.signature = syn.calculate_signature(&server.settings, &[], &[]);
+		let mut data = syn.to_bytes(&server.settings);
+		*data.last_mut().unwrap() ^= 0xFF;
+		server.handle_socket_message(&data, &socket).await;
+		server.handle_socket_message(&[0xAA], &socket).await;
+
+		for millis in [3, 20, 20, 6000] { endpoint.metrics.rtt(Duration::from_millis(millis)); }
+		endpoint.metrics.retransmission(40);
+
+		assert_eq!(server.metrics().to_prometheus(), EXPECTED);
+	}
+}
@@ -1,4 +1,19 @@
 pub mod packet;
+pub mod broadcast;
+pub mod capture;
+pub mod client;
 pub mod connection;
-
-
+pub mod connection_limits;
+pub mod connection_registry;
+pub mod endpoint;
+pub mod handlers;
+pub mod metrics;
+pub mod notifications;
+pub mod rmc_call;
+pub mod server;
+pub mod settings;
+pub mod sliding_window;
+pub mod stream_settings;
+pub mod virtual_port;
+#[cfg(feature = "websocket")]
+mod websocket;
@@ -0,0 +1,203 @@
+use crate::prudp::connection::{ConnectionState, PrudpConnection};
+use crate::prudp::endpoint::PrudpEndpoint;
+use crate::rmc_message::RmcMessage;
+use crate::trace::event;
+use crate::types::pid::Pid;
+use crate::NexResult;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Instant;
+
+// Notifications kept for offline users, oldest first, with the time each was
+// kept at
+#[derive(Debug, Default)]
+pub(crate) struct OfflineNotifications {
+	by_pid: HashMap<Pid, VecDeque<(Instant, RmcMessage)>>,
+	total: usize,
+}
+
+impl OfflineNotifications {
+	fn take(&mut self, pid: Pid) -> Option<VecDeque<(Instant, RmcMessage)>> {
+		let buffered = self.by_pid.remove(&pid)?;
+		self.total -= buffered.len();
+		Some(buffered)
+	}
+}
+
+// What happened to a notification sent with notify
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationDelivery {
+	// Sent to this many connections of the user
+	Sent(usize),
+	// The user is offline, it is sent on their next secure connect
+	Buffered,
+	// The user is offline and offline notifications are not buffered, or the
+	// offline_notification_total_limit of the endpoint is reached
+	Dropped,
+}
+
+impl PrudpEndpoint {
+	// Sends a notification, such as a friend presence change, to every connection of
+	// a user as a reliable RMC request. The call ID is filled in for each connection.
+	// While the user has no connection it is kept for their next secure connect,
+	// dropping the oldest once offline_notification_limit are kept. Connections
+	// still being sent the kept notifications get it after them
+	pub async fn notify(&self, pid: Pid, notification: &RmcMessage) -> NexResult<NotificationDelivery> {
+		let connections = {
+			let mut offline = self.offline_notifications.lock().unwrap();
+			// Checked while holding the buffer, so a user connecting at the same time
+			// either gets the notification sent or finds it buffered
+			let mut delivering = false;
+			let connections: Vec<_> = self.connections.find_all_by_pid(pid).into_iter().filter(|connection| {
+				let inner = connection.lock();
+				delivering |= inner.delivering_notifications;
+				inner.state == ConnectionState::Connected && !inner.delivering_notifications
+			}).collect();
+			if connections.is_empty() || delivering {
+				let delivery = self.buffer_notification(&mut offline, pid, notification);
+				if connections.is_empty() { return Ok(delivery); }
+			}
+			connections
+		};
+
+		let mut sent = 0;
+		let mut last_error = None;
+		for connection in &connections {
+			match self.send_notification(connection, notification.clone()).await {
+				Ok(()) => sent += 1,
+				Err(err) => last_error = Some(err),
+			}
+		}
+		match last_error {
+			Some(err) if sent == 0 => Err(err),
+			_ => Ok(NotificationDelivery::Sent(sent)),
+		}
+	}
+
+	fn buffer_notification(&self, offline: &mut OfflineNotifications, pid: Pid, notification: &RmcMessage) -> NotificationDelivery {
+		if self.offline_notification_limit == 0 { return NotificationDelivery::Dropped; }
+		let is_full = offline.by_pid.get(&pid).is_some_and(|buffered| buffered.len() >= self.offline_notification_limit);
+		// A user at their own limit replaces their oldest notification, which does
+		// not change the total
+		if !is_full && self.offline_notification_total_limit.is_some_and(|limit| offline.total >= limit) {
+			return NotificationDelivery::Dropped;
+		}
+		let buffered = offline.by_pid.entry(pid).or_default();
+		if is_full { buffered.pop_front(); } else { offline.total += 1; }
+		buffered.push_back((self.now(), notification.clone()));
+		NotificationDelivery::Buffered
+	}
+
+	// The number of notifications kept for a user who is offline
+	pub fn buffered_notification_count(&self, pid: Pid) -> usize {
+		self.offline_notifications.lock().unwrap().by_pid.get(&pid).map_or(0, VecDeque::len)
+	}
+
+	// The number of notifications kept for all offline users
+	pub fn total_buffered_notification_count(&self) -> usize { self.offline_notifications.lock().unwrap().total }
+
+	// Drops the notifications kept for a user, such as when their account is deleted
+	pub fn clear_buffered_notifications(&self, pid: Pid) {
+		self.offline_notifications.lock().unwrap().take(pid);
+	}
+
+	// Sends the notifications kept while the user of a new secure connection was
+	// offline, except those which expired since the last timer tick. Notifications
+	// kept while these are sent follow them, and once none are left the connection
+	// is sent new ones directly
+	pub(crate) async fn deliver_buffered_notifications(&self, connection: &Arc<PrudpConnection>) {
+		loop {
+			let buffered = {
+				let mut offline = self.offline_notifications.lock().unwrap();
+				match offline.take(connection.pid()) {
+					Some(buffered) => buffered,
+					None => {
+						connection.lock().delivering_notifications = false;
+						return;
+					}
+				}
+			};
+			let now = self.now();
+			let buffered: Vec<_> = buffered.into_iter().filter(|(buffered_at, _)| !self.is_expired(*buffered_at, now)).collect();
+			event!(debug, count = buffered.len(), "Delivering buffered notifications");
+			for (_, notification) in buffered {
+				if let Err(err) = self.send_notification(connection, notification).await { self.emit_error(err).await; }
+			}
+		}
+	}
+
+	// Drops the notifications kept for longer than offline_notification_ttl
+	pub(crate) fn expire_offline_notifications(&self, now: Instant) {
+		if self.offline_notification_ttl.is_none() { return; }
+		let mut offline = self.offline_notifications.lock().unwrap();
+		let mut expired = 0;
+		offline.by_pid.retain(|_, buffered| {
+			while buffered.front().is_some_and(|(buffered_at, _)| self.is_expired(*buffered_at, now)) {
+				buffered.pop_front();
+				expired += 1;
+			}
+			!buffered.is_empty()
+		});
+		offline.total -= expired;
+	}
+
+	fn is_expired(&self, buffered_at: Instant, now: Instant) -> bool {
+		self.offline_notification_ttl.is_some_and(|ttl| now.saturating_duration_since(buffered_at) >= ttl)
+	}
+
+	async fn send_notification(&self, connection: &Arc<PrudpConnection>, mut notification: RmcMessage) -> NexResult<()> {
+		notification.call_id = connection.pending_calls.next_call_id();
+		self.send_request(connection, notification).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::prudp::connection::ConnectionState;
+	use crate::prudp::server::PrudpServer;
+	use crate::prudp::stream_settings::StreamSettings;
+	use crate::prudp::virtual_port::VirtualPort;
+	use crate::socket_connection::SocketConnection;
+	use crate::transport::channel::ChannelTransport;
+	use crate::transport::Transport;
+	use std::net::SocketAddr;
+	use std::time::Duration;
+
+	#[tokio::test]
+	async fn notifications_wait_for_the_kept_ones_to_be_delivered() {
+		let server = Arc::new(PrudpServer::new());
+		let mut endpoint = PrudpEndpoint::new(1);
+		endpoint.offline_notification_limit = 8;
+		let endpoint = Arc::new(endpoint);
+		server.bind_prudp_endpoint(endpoint.clone()).unwrap();
+
+		let address = SocketAddr::from(([10, 0, 0, 2], 50000));
+		let (transport, client) = ChannelTransport::pair(address, address);
+		let port = VirtualPort::new(10, 15);
+		let connection = endpoint.connections.get_or_insert_with(address, port, |id| {
+			PrudpConnection::new(SocketConnection::datagram(address, Arc::new(transport)), Arc::downgrade(&endpoint), id, port, 1, StreamSettings::new(), Instant::now())
+		});
+		connection.set_pid(Pid(1000));
+		let notification = RmcMessage::request(0x70, 1, Vec::new());
+		assert_eq!(endpoint.notify(Pid(1000), &notification).await.unwrap(), NotificationDelivery::Buffered);
+
+		// As left by a secure CONNECT, established but with kept notifications to send
+		{
+			let mut inner = connection.lock();
+			inner.initialize_sliding_windows(0);
+			inner.state = ConnectionState::Connected;
+			inner.delivering_notifications = true;
+		}
+		assert_eq!(endpoint.notify(Pid(1000), &notification).await.unwrap(), NotificationDelivery::Buffered);
+		assert_eq!(endpoint.buffered_notification_count(Pid(1000)), 2);
+
+		endpoint.deliver_buffered_notifications(&connection).await;
+		assert!(!connection.lock().delivering_notifications);
+		assert_eq!(endpoint.total_buffered_notification_count(), 0);
+		assert_eq!(endpoint.notify(Pid(1000), &notification).await.unwrap(), NotificationDelivery::Sent(1));
+
+		let mut buf = vec![0; 1500];
+		for _ in 0..3 { tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf)).await.unwrap().unwrap(); }
+	}
+}
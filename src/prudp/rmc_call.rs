@@ -0,0 +1,192 @@
+use crate::clock::{Clock, ClockFuture, SystemClock};
+use crate::constants::{PrudpPacketFlags, PrudpPacketType};
+use crate::error::NexError;
+use crate::prudp::connection::PrudpConnection;
+use crate::prudp::endpoint::PrudpEndpoint;
+use crate::prudp::packet::PrudpPacket;
+use crate::prudp::virtual_port::VirtualPort;
+use crate::rmc_message::RmcMessage;
+use crate::NexResult;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+// The RMC calls a connection has made which are still waiting on a response,
+// by call ID. Both servers calling methods on clients and clients calling
+// servers use one of these per connection
+#[derive(Debug)]
+pub struct PendingCalls {
+	next_call_id: AtomicU32,
+	calls: Mutex<HashMap<u32, oneshot::Sender<NexResult<RmcMessage>>>>,
+}
+
+impl PendingCalls {
+	pub fn new() -> Self { Self { next_call_id: AtomicU32::new(1), calls: Mutex::new(HashMap::new()) } }
+
+	// Allocates a call ID and the handle which waits for its response. IDs of
+	// calls still waiting are skipped once the counter wraps around
+	pub fn register(self: &Arc<Self>) -> RmcCall {
+		let (sender, receiver) = oneshot::channel();
+		let mut calls = self.calls.lock().unwrap();
+		let call_id = self.allocate_call_id(&calls);
+		calls.insert(call_id, sender);
+		RmcCall { call_id, receiver, deadline: None, calls: Arc::downgrade(self) }
+	}
+
+	// A call ID for a request which is not waited on, such as a notification
+	pub fn next_call_id(&self) -> u32 { self.allocate_call_id(&self.calls.lock().unwrap()) }
+
+	fn allocate_call_id(&self, calls: &HashMap<u32, oneshot::Sender<NexResult<RmcMessage>>>) -> u32 {
+		loop {
+			let call_id = self.next_call_id.fetch_add(1, Ordering::Relaxed);
+			if call_id != 0 && !calls.contains_key(&call_id) { return call_id; }
+		}
+	}
+
+	// Hands a response to the call waiting on it. Returns false if no call has its
+	// call ID, such as when the call timed out or was cancelled
+	pub fn complete(&self, response: RmcMessage) -> bool {
+		let pending = self.calls.lock().unwrap().remove(&response.call_id);
+		match pending {
+			Some(sender) => { let _ = sender.send(Ok(response)); true }
+			None => false,
+		}
+	}
+
+	// Ends a call with an error. Returns false if it was no longer waiting
+	pub fn fail(&self, call_id: u32, err: NexError) -> bool {
+		let pending = self.calls.lock().unwrap().remove(&call_id);
+		match pending {
+			Some(sender) => { let _ = sender.send(Err(err)); true }
+			None => false,
+		}
+	}
+
+	// Ends every call with an error, used when the connection is closed
+	pub fn fail_all(&self, err: NexError) {
+		let calls: Vec<_> = self.calls.lock().unwrap().drain().collect();
+		for (_, sender) in calls { let _ = sender.send(Err(err.clone())); }
+	}
+
+	pub fn len(&self) -> usize { self.calls.lock().unwrap().len() }
+
+	pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+impl Default for PendingCalls {
+	fn default() -> Self { Self::new() }
+}
+
+// A call waiting on its response. Resolves to the response message, which may be
+// an error response, or fails when the call times out, is cancelled or the
+// connection closes. Dropping the handle cancels the call
+pub struct RmcCall {
+	call_id: u32,
+	receiver: oneshot::Receiver<NexResult<RmcMessage>>,
+	deadline: Option<ClockFuture<'static>>,
+	calls: Weak<PendingCalls>,
+}
+
+impl fmt::Debug for RmcCall {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("RmcCall").field("call_id", &self.call_id).field("has_deadline", &self.deadline.is_some()).finish()
+	}
+}
+
+impl RmcCall {
+	pub fn call_id(&self) -> u32 { self.call_id }
+
+	// Fails the call if no response arrives within the timeout, counting from now
+	// on the given clock, so a ManualClock decides when the call times out
+	pub fn with_timeout(mut self, timeout: Duration, clock: Arc<dyn Clock>) -> Self {
+		let deadline = clock.now() + timeout;
+		self.deadline = Some(Box::pin(async move { clock.sleep_until(deadline).await }));
+		self
+	}
+
+	// A handle which can cancel the call from another task while this one waits on it
+	pub fn canceller(&self) -> CallCanceller { CallCanceller { call_id: self.call_id, calls: self.calls.clone() } }
+
+	pub fn cancel(self) { self.canceller().cancel(); }
+}
+
+impl Future for RmcCall {
+	type Output = NexResult<RmcMessage>;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		if let Poll::Ready(result) = Pin::new(&mut self.receiver).poll(cx) {
+			return Poll::Ready(result.unwrap_or_else(|_| Err(NexError::Connection("Connection closed before a response was received".into()))));
+		}
+		let call_id = self.call_id;
+		if let Some(deadline) = &mut self.deadline {
+			if deadline.as_mut().poll(cx).is_ready() {
+				// The response may have arrived since the receiver was polled, in which
+				// case the call is no longer pending and the response is returned
+				let err = NexError::Timeout(format!("No response to RMC call {call_id}"));
+				if let Some(calls) = self.calls.upgrade() { calls.fail(call_id, err.clone()); }
+				return Poll::Ready(self.receiver.try_recv().unwrap_or(Err(err)));
+			}
+		}
+		Poll::Pending
+	}
+}
+
+impl Drop for RmcCall {
+	fn drop(&mut self) {
+		if let Some(calls) = self.calls.upgrade() { calls.calls.lock().unwrap().remove(&self.call_id); }
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct CallCanceller {
+	call_id: u32,
+	calls: Weak<PendingCalls>,
+}
+
+impl CallCanceller {
+	// The call fails with NexError::Cancelled. Returns false if it had already ended
+	pub fn cancel(&self) -> bool {
+		let Some(calls) = self.calls.upgrade() else { return false };
+		calls.fail(self.call_id, NexError::Cancelled(format!("RMC call {} was cancelled", self.call_id)))
+	}
+}
+
+impl PrudpEndpoint {
+	// Sends an RMC request to a client and returns the handle which waits for its
+	// response. The call ID is filled in, and the call_timeout of the endpoint is
+	// applied. Verbose requests must set the protocol and method names
+	pub async fn start_call(&self, connection: &Arc<PrudpConnection>, mut request: RmcMessage) -> NexResult<RmcCall> {
+		let mut call = connection.pending_calls.register();
+		if let Some(timeout) = self.call_timeout {
+			let clock = self.server().map_or_else(|| Arc::new(SystemClock) as Arc<dyn Clock>, |server| server.clock.clone());
+			call = call.with_timeout(timeout, clock);
+		}
+		request.call_id = call.call_id();
+		self.send_request(connection, request).await?;
+		Ok(call)
+	}
+
+	// Sends an RMC request reliably, in the RMC format of the endpoint
+	pub(crate) async fn send_request(&self, connection: &Arc<PrudpConnection>, mut request: RmcMessage) -> NexResult<()> {
+		request.is_request = true;
+		request.verbose_mode = self.use_verbose_rmc;
+		let mut packet = PrudpPacket::new(connection.default_prudp_version, PrudpPacketType::Data);
+		packet.add_flag(PrudpPacketFlags::RELIABLE | PrudpPacketFlags::NEEDS_ACK | PrudpPacketFlags::HAS_SIZE);
+		packet.header.source = VirtualPort::new(connection.stream_type, self.stream_id);
+		packet.header.destination = VirtualPort::new(connection.stream_type, connection.stream_id);
+		packet.payload = request.to_bytes();
+		packet.sender = Some(connection.clone());
+		self.send(packet).await
+	}
+
+	// Sends an RMC request to a client and waits for the response
+	pub async fn call(&self, connection: &Arc<PrudpConnection>, request: RmcMessage) -> NexResult<RmcMessage> {
+		self.start_call(connection, request).await?.await
+	}
+}
@@ -1,6 +1,388 @@
-pub struct PrudpServer;
+use crate::clock::{Clock, SystemClock};
+use crate::constants::{PrudpPacketType, StreamType};
+use crate::error::NexError;
+use crate::prudp::capture::{CaptureDirection, CaptureSession};
+use crate::prudp::connection::{ConnectionState, PrudpConnection};
+use crate::prudp::endpoint::PrudpEndpoint;
+use crate::prudp::handlers::{handler, HandlerList};
+use crate::prudp::metrics::ServerMetrics;
+use crate::prudp::packet::{PrudpPacket, PRUDP_LITE};
+use crate::prudp::settings::PrudpSettings;
+use crate::socket_connection::{SendBatch, SocketConnection};
+use crate::trace::event;
+use crate::transport::batching::BatchingTransport;
+use crate::transport::Transport;
+use crate::NexResult;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio::net::UdpSocket;
+
+// How often retransmissions and heartbeats are checked
+pub(crate) const TIMER_RESOLUTION: Duration = Duration::from_millis(10);
+
+// Delay between the fragments of a large payload. 16ms (1/60th of a second) keeps
+// clients from being overloaded, roughly matching the framerate most games target
+pub(crate) const FRAGMENT_DELAY: Duration = Duration::from_millis(16);
+
+// How often a DISCONNECT is resent during shutdown until the client acknowledges it
+const DISCONNECT_RESEND_INTERVAL: Duration = Duration::from_millis(500);
+
+// Datagrams waiting to be handled per client address. More are dropped, the
+// same as a full socket buffer would
+const RECEIVE_QUEUE_SIZE: usize = 256;
+
+// How long the task handling the datagrams of an address waits for more before
+// exiting
+const RECEIVE_QUEUE_IDLE: Duration = Duration::from_secs(30);
+
+// Represents a PRUDP server. Several endpoints may be bound to one server, told
+// apart by the stream ID of their virtual port. The same endpoint logic is shared
+// between the UDP and WebSocket transports
+pub struct PrudpServer {
+	pub settings: PrudpSettings,
+	pub supported_functions: u32,
+	pub session_key_length: usize,
+	pub kerberos_ticket_version: u32,
+	// Largest payload sent in one packet, 0 to never fragment
+	pub fragment_size: usize,
+	// Most datagrams read or written by one recvmmsg or sendmmsg call on Linux
+	pub datagram_batch_size: usize,
+	pub connection_signature_key: Vec<u8>,
+	pub clock: Arc<dyn Clock>,
+	endpoints: Mutex<HashMap<u8, Arc<PrudpEndpoint>>>,
+	error_handlers: HandlerList<NexError>,
+	transport: Mutex<Option<Arc<dyn Transport>>>,
+	receive_queues: Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>,
+	timers_started: AtomicBool,
+	dropped_malformed: AtomicU64,
+	pub(crate) captures: Mutex<Vec<(Arc<CaptureSession>, JoinHandle<()>)>>,
+	// Set once shutdown starts, after which new connections are refused
+	stopping: AtomicBool,
+	// Set once shutdown has finished, stopping every socket and timer task
+	pub(crate) closed: watch::Sender<bool>,
+}
 
 impl PrudpServer {
-	pub fn new() -> Self { Self }
-	pub fn start(&self) {}
+	pub fn new() -> Self {
+		Self {
+			settings: PrudpSettings::default(),
+			supported_functions: 0,
+			session_key_length: 32,
+			kerberos_ticket_version: 0,
+			fragment_size: 1300,
+			datagram_batch_size: 32,
+			connection_signature_key: rand::random::<[u8; 16]>().to_vec(),
+			clock: Arc::new(SystemClock),
+			endpoints: Mutex::new(HashMap::new()),
+			error_handlers: HandlerList::new(),
+			transport: Mutex::new(None),
+			receive_queues: Mutex::new(HashMap::new()),
+			timers_started: AtomicBool::new(false),
+			dropped_malformed: AtomicU64::new(0),
+			captures: Mutex::new(Vec::new()),
+			stopping: AtomicBool::new(false),
+			closed: watch::channel(false).0,
+		}
+	}
+
+	// Binds an endpoint to the server. Packets are routed to it by the stream ID of
+	// their destination virtual port
+	pub fn bind_prudp_endpoint(self: &Arc<Self>, endpoint: Arc<PrudpEndpoint>) -> NexResult<()> {
+		let mut endpoints = self.endpoints.lock().unwrap();
+		if endpoints.contains_key(&endpoint.stream_id) {
+			return Err(NexError::Connection(format!("Tried to bind already existing PRUDPEndPoint {}", endpoint.stream_id)));
+		}
+		endpoint.set_server(Arc::downgrade(self))?;
+		endpoints.insert(endpoint.stream_id, endpoint);
+		Ok(())
+	}
+
+	pub fn endpoint(&self, stream_id: u8) -> Option<Arc<PrudpEndpoint>> { self.endpoints.lock().unwrap().get(&stream_id).cloned() }
+
+	pub fn endpoints(&self) -> Vec<Arc<PrudpEndpoint>> { self.endpoints.lock().unwrap().values().cloned().collect() }
+
+	// Adds a handler for errors which happen before a packet reaches an endpoint,
+	// such as malformed packets or packets for an unbound stream ID
+	pub fn on_error<F, Fut>(&self, f: F)
+	where
+		F: Fn(NexError) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = ()> + Send + 'static,
+	{
+		self.error_handlers.push(handler(f));
+	}
+
+	pub fn udp_local_addr(&self) -> Option<SocketAddr> { self.transport.lock().unwrap().as_ref().and_then(|transport| transport.local_addr().ok()) }
+
+	pub fn is_shutting_down(&self) -> bool { self.stopping.load(Ordering::SeqCst) }
+
+	// Resolves once shutdown has finished
+	pub(crate) async fn wait_closed(&self) {
+		let mut closed = self.closed.subscribe();
+		let _ = closed.wait_for(|closed| *closed).await;
+	}
+
+	// Starts listening for PRUDPv0 and PRUDPv1 packets on the given UDP port
+	pub async fn listen_udp(self: &Arc<Self>, port: u16) -> NexResult<()> {
+		let socket = UdpSocket::bind(("0.0.0.0", port)).await?;
+		self.listen_transport(Arc::new(socket))
+	}
+
+	// Starts listening for PRUDPv0 and PRUDPv1 packets on any datagram transport,
+	// such as one end of an in-process channel pair. Datagrams are read and
+	// written in batches of up to datagram_batch_size
+	pub fn listen_transport(self: &Arc<Self>, transport: Arc<dyn Transport>) -> NexResult<()> {
+		let transport: Arc<dyn Transport> = Arc::new(BatchingTransport::new(transport, self.datagram_batch_size));
+		{
+			let mut current = self.transport.lock().unwrap();
+			if current.is_some() { return Err(NexError::Connection("Server is already listening on a datagram transport".into())); }
+			*current = Some(transport.clone());
+		}
+		self.start_timers();
+
+		let server = Arc::downgrade(self);
+		let mut closed = self.closed.subscribe();
+		let mut bufs = vec![vec![0u8; 64000]; self.datagram_batch_size.max(1)];
+		tokio::spawn(async move {
+			let mut received = Vec::with_capacity(bufs.len());
+			loop {
+				received.clear();
+				tokio::select! {
+					result = transport.recv_batch(&mut bufs, &mut received) => if result.is_err() { break },
+					_ = closed.wait_for(|closed| *closed) => break,
+				};
+				let Some(server) = server.upgrade() else { break };
+				for (buf, &(read, address)) in bufs.iter().zip(&received) {
+					server.queue_datagram(buf[..read].to_vec(), address, &transport);
+				}
+			}
+		});
+		Ok(())
+	}
+
+	// Datagrams from one address are handled in the order they arrived by a single
+	// task, so the packets of a client are never processed concurrently. Clients
+	// do not wait on each other
+	fn queue_datagram(self: &Arc<Self>, data: Vec<u8>, address: SocketAddr, transport: &Arc<dyn Transport>) {
+		let mut queues = self.receive_queues.lock().unwrap();
+		let data = match queues.get(&address) {
+			None => data,
+			Some(queue) => match queue.try_send(data) {
+				Ok(()) => return,
+				Err(mpsc::error::TrySendError::Full(_)) => return event!(debug, address = %address, "Dropping datagram, receive queue is full"),
+				Err(mpsc::error::TrySendError::Closed(data)) => data,
+			},
+		};
+
+		let (sender, mut receiver) = mpsc::channel(RECEIVE_QUEUE_SIZE);
+		let _ = sender.try_send(data);
+		queues.insert(address, sender);
+
+		let server = Arc::downgrade(self);
+		let socket = SocketConnection::datagram(address, transport.clone());
+		tokio::spawn(async move {
+			loop {
+				let data = match tokio::time::timeout(RECEIVE_QUEUE_IDLE, receiver.recv()).await {
+					Ok(Some(data)) => data,
+					Ok(None) => break,
+					Err(_) => {
+						let Some(server) = server.upgrade() else { break };
+						// Datagrams are only queued while holding the map, so none can
+						// arrive between the check and the removal
+						let mut queues = server.receive_queues.lock().unwrap();
+						if !receiver.is_empty() { continue; }
+						queues.remove(&address);
+						break;
+					}
+				};
+				let Some(server) = server.upgrade() else { break };
+				if *server.closed.borrow() { break; }
+				server.handle_socket_message(&data, &socket).await;
+			}
+		});
+	}
+
+	pub(crate) fn start_timers(self: &Arc<Self>) {
+		if self.timers_started.swap(true, Ordering::SeqCst) { return; }
+		let server = Arc::downgrade(self);
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(TIMER_RESOLUTION);
+			loop {
+				interval.tick().await;
+				let Some(server) = server.upgrade() else { break };
+				if *server.closed.borrow() { break; }
+				server.tick().await;
+			}
+		});
+	}
+
+	// Shuts the server down. New connections are refused straight away, and
+	// reliable packets still waiting for an acknowledgement are given until the
+	// deadline, measured by the server clock, to be acknowledged. Every client is
+	// then sent a DISCONNECT, resent until the client acknowledges it or the
+	// deadline passes. Finally incoming packets are ignored and every remaining
+	// connection is removed, firing the connection ended handlers, before the
+	// sockets are closed. A server which has been shut down can not be started again
+	pub async fn shutdown(&self, deadline: Instant) {
+		if self.stopping.swap(true, Ordering::SeqCst) { return self.wait_closed().await; }
+
+		// Clients stop acknowledging DATA once they see a DISCONNECT, so pending
+		// packets are drained first
+		self.wait_until(deadline, || !self.has_connections(|connection| connection.lock().has_pending_packets())).await;
+
+		for endpoint in self.endpoints() {
+			for connection in endpoint.connections.all() {
+				let mut inner = connection.lock();
+				if inner.state == ConnectionState::Connected { inner.state = ConnectionState::Disconnecting; }
+			}
+		}
+		let mut resend_at = self.clock.now();
+		loop {
+			if self.clock.now() >= resend_at {
+				for (endpoint, connection) in self.disconnecting() { let _ = endpoint.send_disconnect(self, &connection).await; }
+				resend_at = self.clock.now() + DISCONNECT_RESEND_INTERVAL;
+			}
+			// Acknowledged connections are removed as the ACKs arrive
+			let acknowledged = || !self.has_connections(|connection| connection.connection_state() == ConnectionState::Disconnecting);
+			if self.wait_until(resend_at.min(deadline), acknowledged).await || self.clock.now() >= deadline { break; }
+		}
+
+		self.closed.send_replace(true);
+		self.transport.lock().unwrap().take();
+		self.receive_queues.lock().unwrap().clear();
+		for endpoint in self.endpoints() {
+			for connection in endpoint.connections.all() { endpoint.cleanup_connection(&connection).await; }
+		}
+		self.stop_captures(|_| true).await;
+	}
+
+	// Waits until done returns true or the clock reaches the deadline. Returns
+	// false if the deadline was reached first
+	async fn wait_until(&self, deadline: Instant, done: impl Fn() -> bool) -> bool {
+		let sleep = self.clock.sleep_until(deadline);
+		tokio::pin!(sleep);
+		loop {
+			if done() { return true; }
+			tokio::select! {
+				_ = &mut sleep => return done(),
+				_ = tokio::time::sleep(TIMER_RESOLUTION) => {}
+			}
+		}
+	}
+
+	fn has_connections(&self, filter: impl Fn(&PrudpConnection) -> bool) -> bool {
+		self.endpoints().iter().any(|endpoint| endpoint.connections.all().iter().any(|connection| filter(connection)))
+	}
+
+	fn disconnecting(&self) -> Vec<(Arc<PrudpEndpoint>, Arc<PrudpConnection>)> {
+		let mut disconnecting = Vec::new();
+		for endpoint in self.endpoints() {
+			for connection in endpoint.connections.all() {
+				if connection.connection_state() == ConnectionState::Disconnecting { disconnecting.push((endpoint.clone(), connection)); }
+			}
+		}
+		disconnecting
+	}
+
+	// Counters of every bound endpoint. See ServerMetrics::to_prometheus for
+	// exposing them to Prometheus
+	pub fn metrics(&self) -> ServerMetrics {
+		let mut endpoints: Vec<_> = self.endpoints().iter().map(|endpoint| endpoint.metrics()).collect();
+		endpoints.sort_by_key(|metrics| metrics.stream_id);
+		ServerMetrics { dropped_malformed: self.dropped_malformed.load(Ordering::Relaxed), endpoints }
+	}
+
+	// Runs the retransmission and heartbeat timers of every endpoint once. Called
+	// every TIMER_RESOLUTION once listening, but may be called directly when
+	// testing with a manual clock. Everything the timers send is written in one batch
+	pub async fn tick(&self) {
+		let now = self.clock.now();
+		let mut batch = SendBatch::default();
+		for endpoint in self.endpoints() { endpoint.tick(self, now, &mut batch).await; }
+		batch.flush().await;
+	}
+
+	pub(crate) async fn handle_socket_message(&self, data: &[u8], socket: &SocketConnection) {
+		if data.is_empty() || *self.closed.borrow() { return; }
+		// Only WebSocket clients speak PRUDPLite
+		let is_lite = socket.is_websocket() && data[0] == 0x80;
+		// Clients may send several packets at once
+		let packets = match PrudpPacket::decode_all(data, &self.settings, is_lite) {
+			Ok(packets) => packets,
+			Err(err) => {
+				event!(warn, address = %socket.address(), error = %err, "Failed to decode PRUDP packet");
+				let endpoint = match err {
+					NexError::BadChecksum { source_port, destination_port } => self.endpoint(destination_port.stream_id).map(|endpoint| (endpoint, source_port)),
+					_ => None,
+				};
+				match endpoint {
+					Some((endpoint, source)) => endpoint.bad_checksum(socket.address(), source),
+					None => { self.dropped_malformed.fetch_add(1, Ordering::Relaxed); }
+				}
+				return self.error_handlers.emit(err).await;
+			}
+		};
+		for packet in packets {
+			if let Err(err) = self.process_packet(packet, socket).await {
+				self.error_handlers.emit(err).await;
+			}
+		}
+	}
+
+	async fn process_packet(&self, packet: PrudpPacket, socket: &SocketConnection) -> NexResult<()> {
+		let (source, destination) = (packet.header.source, packet.header.destination);
+		if destination.stream_type != source.stream_type {
+			return Err(NexError::Parse(format!("Client {} trying to use non matching destination and source stream types {} and {}", socket.address(), destination.stream_type, source.stream_type)));
+		}
+		if destination.stream_type > StreamType::Relay as u8 {
+			return Err(NexError::Parse(format!("Client {} trying to use invalid destination stream type {}", socket.address(), destination.stream_type)));
+		}
+		// PRUDPLite can use port numbers 0-31, PRUDPv0 and PRUDPv1 only 0-15
+		let max_source_port = if packet.version() == PRUDP_LITE { 31 } else { 15 };
+		if source.stream_id > max_source_port {
+			return Err(NexError::Parse(format!("Client {} trying to use invalid source port number {}", socket.address(), source.stream_id)));
+		}
+		let endpoint = self.endpoint(destination.stream_id).ok_or_else(|| {
+			NexError::Connection(format!("Client {} trying to connect to unbound PRUDPEndPoint {}", socket.address(), destination.stream_id))
+		})?;
+		endpoint.process_packet(self, packet, socket).await;
+		Ok(())
+	}
+
+	// Removes every virtual connection made over a closed socket
+	#[cfg_attr(not(feature = "websocket"), allow(dead_code))]
+	pub(crate) async fn cleanup_socket(&self, address: SocketAddr) {
+		for endpoint in self.endpoints() { endpoint.cleanup_socket(address).await; }
+	}
+
+	// Sends a packet to the connection set as its sender. Payloads larger than the
+	// fragment size are split into several packets
+	pub async fn send(&self, mut packet: PrudpPacket) -> NexResult<()> {
+		let connection = packet.sender.take().ok_or_else(|| NexError::Connection("Packet has no sender connection".into()))?;
+		if packet.is_type(PrudpPacketType::Data) && !packet.is_ack() {
+			if let Some(capture) = connection.lock().capture.clone() { capture.record_rmc(CaptureDirection::Outgoing, &packet.payload); }
+		}
+		let fragments = packet.into_fragments(self.fragment_size);
+		let count = fragments.len();
+		for (i, fragment) in fragments.into_iter().enumerate() {
+			self.send_packet(&connection, fragment).await?;
+			if i + 1 < count { tokio::time::sleep(FRAGMENT_DELAY).await; }
+		}
+		Ok(())
+	}
+
+	// Sequences, encrypts and signs a single packet before writing it to the socket
+	pub(crate) async fn send_packet(&self, connection: &PrudpConnection, packet: PrudpPacket) -> NexResult<()> {
+		let data = connection.lock().prepare_packet(&self.settings, packet, self.clock.now())?;
+		connection.socket.send(&data).await
+	}
+}
+
+impl Default for PrudpServer {
+	fn default() -> Self { Self::new() }
 }
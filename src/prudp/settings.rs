@@ -0,0 +1,20 @@
+#[derive(Debug, Clone, Default)]
+pub struct PrudpV0Settings {
+	pub is_quazal_mode: bool,
+	pub encrypted_connect: bool,
+	pub legacy_connection_signature: bool,
+	pub use_enhanced_checksum: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PrudpV1Settings {
+	pub legacy_connection_signature: bool,
+}
+
+// Everything needed to encode, decode and sign packets. Shared by both sides of a link
+#[derive(Debug, Clone, Default)]
+pub struct PrudpSettings {
+	pub access_key: String,
+	pub prudp_v0: PrudpV0Settings,
+	pub prudp_v1: PrudpV1Settings,
+}
@@ -0,0 +1,29 @@
+use crate::prudp::stream_settings::StreamSettings;
+use crate::timeout_manager::TimeoutManager;
+use crate::NexResult;
+
+// Implementation of rdv::SlidingWindow. Sequences and encrypts outgoing reliable
+// packets. PRUDPv1 connections may have several reliable substreams, each with its own window
+pub struct SlidingWindow {
+	sequence_id_counter: u16,
+	pub stream_settings: StreamSettings,
+	pub timeout_manager: TimeoutManager,
+}
+
+impl SlidingWindow {
+	pub fn new(stream_settings: StreamSettings) -> Self {
+		// The first DATA packet from the server has sequence ID 1
+		Self { sequence_id_counter: 0, stream_settings, timeout_manager: TimeoutManager::new() }
+	}
+
+	pub fn set_cipher_key(&mut self, key: &[u8]) { self.stream_settings.encryption_algorithm.set_key(key); }
+
+	pub fn next_outgoing_sequence_id(&mut self) -> u16 {
+		self.sequence_id_counter = self.sequence_id_counter.wrapping_add(1);
+		self.sequence_id_counter
+	}
+
+	pub fn encrypt(&self, data: &[u8]) -> NexResult<Vec<u8>> { self.stream_settings.encryption_algorithm.encrypt(data) }
+
+	pub fn decrypt(&self, data: &[u8]) -> NexResult<Vec<u8>> { self.stream_settings.encryption_algorithm.decrypt(data) }
+}
@@ -0,0 +1,69 @@
+use crate::compression::{self, CompressionAlgorithm};
+use crate::encryption::{Cipher, Rc4};
+
+// Implementation of rdv::StreamSettings. Holds the settings for a PRUDP virtual
+// connection stream. Defaults are based on WATCH_DOGS unless stated otherwise
+pub struct StreamSettings {
+	pub extra_retransmit_timeout_trigger: u32,
+	pub max_packet_retransmissions: u32,
+	pub keep_alive_timeout: u32,
+	pub checksum_base: u32,
+	pub fault_detection_enabled: bool,
+	pub initial_rtt: u32,
+	pub syn_initial_rtt: u32,
+	pub encryption_algorithm: Box<dyn Cipher>,
+	pub extra_retransmit_timeout_multiplier: f32,
+	pub window_size: u32,
+	pub compression_algorithm: Box<dyn CompressionAlgorithm>,
+	pub rtt_retransmit: u32,
+	pub retransmit_timeout_multiplier: f32,
+	pub max_silence_time: u32,
+}
+
+impl StreamSettings {
+	pub fn new() -> Self {
+		Self {
+			extra_retransmit_timeout_trigger: 0x32,
+			max_packet_retransmissions: 0x14,
+			keep_alive_timeout: 1000,
+			checksum_base: 0,
+			fault_detection_enabled: true,
+			initial_rtt: 0x2EE,
+			syn_initial_rtt: 0xFA,
+			encryption_algorithm: Box::new(Rc4::new(b"CD&ML".to_vec())),
+			extra_retransmit_timeout_multiplier: 1.0,
+			window_size: 8,
+			compression_algorithm: Box::new(compression::new_dummy()),
+			// Taken from Xenoblade Chronicles. Resent packets are left out of RTT calculations, matching TCP
+			rtt_retransmit: 2,
+			retransmit_timeout_multiplier: 1.25,
+			// Taken from Xenoblade Chronicles. WATCH_DOGS uses 5000
+			max_silence_time: 10000,
+		}
+	}
+}
+
+impl Default for StreamSettings {
+	fn default() -> Self { Self::new() }
+}
+
+impl Clone for StreamSettings {
+	fn clone(&self) -> Self {
+		Self {
+			extra_retransmit_timeout_trigger: self.extra_retransmit_timeout_trigger,
+			max_packet_retransmissions: self.max_packet_retransmissions,
+			keep_alive_timeout: self.keep_alive_timeout,
+			checksum_base: self.checksum_base,
+			fault_detection_enabled: self.fault_detection_enabled,
+			initial_rtt: self.initial_rtt,
+			syn_initial_rtt: self.syn_initial_rtt,
+			encryption_algorithm: self.encryption_algorithm.boxed_clone(),
+			extra_retransmit_timeout_multiplier: self.extra_retransmit_timeout_multiplier,
+			window_size: self.window_size,
+			compression_algorithm: self.compression_algorithm.boxed_clone(),
+			rtt_retransmit: self.rtt_retransmit,
+			retransmit_timeout_multiplier: self.retransmit_timeout_multiplier,
+			max_silence_time: self.max_silence_time,
+		}
+	}
+}
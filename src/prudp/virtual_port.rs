@@ -0,0 +1,16 @@
+// PRUDP reuses a single socket for many virtual connections. On v0/v1 a virtual
+// port is one byte: the upper 4 bits are the stream type and the lower 4 bits the
+// stream ID. PRUDPLite carries both as separate bytes, allowing stream IDs 0-31
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct VirtualPort {
+	pub stream_type: u8,
+	pub stream_id: u8,
+}
+
+impl VirtualPort {
+	pub fn new(stream_type: u8, stream_id: u8) -> Self { Self { stream_type, stream_id } }
+
+	pub fn from_byte(value: u8) -> Self { Self { stream_type: value >> 4, stream_id: value & 0xF } }
+
+	pub fn to_byte(&self) -> u8 { (self.stream_type << 4) | (self.stream_id & 0xF) }
+}
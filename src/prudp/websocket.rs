@@ -0,0 +1,75 @@
+use crate::prudp::server::PrudpServer;
+use crate::socket_connection::SocketConnection;
+use crate::NexResult;
+use futures_util::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::Message;
+
+const WEBSOCKET_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// PRUDPLite over WebSocket. Each socket is treated as a single client, the same
+// way a UDP address is, and every message carries one or more PRUDPLite packets
+impl PrudpServer {
+	// Starts listening for WebSocket clients on the given TCP port
+	pub async fn listen_websocket(self: &Arc<Self>, port: u16) -> NexResult<()> {
+		let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+		self.start_timers();
+
+		let server = Arc::downgrade(self);
+		let mut closed = self.closed.subscribe();
+		tokio::spawn(async move {
+			loop {
+				let (stream, address) = tokio::select! {
+					result = listener.accept() => match result { Ok(result) => result, Err(_) => break },
+					_ = closed.wait_for(|closed| *closed) => break,
+				};
+				let Some(server) = server.upgrade() else { break };
+				tokio::spawn(server.handle_websocket(stream, address));
+			}
+		});
+		Ok(())
+	}
+
+	async fn handle_websocket(self: Arc<Self>, stream: TcpStream, address: SocketAddr) {
+		let Ok(websocket) = tokio_tungstenite::accept_async(stream).await else { return };
+		let (mut sink, mut source) = websocket.split();
+
+		// Connections only hold the sending half of a channel, so writes never
+		// need to lock the socket
+		let (sender, mut receiver) = mpsc::unbounded_channel::<Vec<u8>>();
+		let (stop, mut stopped) = oneshot::channel::<()>();
+		let writer = tokio::spawn(async move {
+			loop {
+				let data = tokio::select! {
+					data = receiver.recv() => data,
+					_ = &mut stopped => None,
+				};
+				let Some(data) = data else { break };
+				if sink.send(Message::Binary(data)).await.is_err() { break; }
+			}
+			let _ = sink.close().await;
+		});
+
+		let socket = SocketConnection::websocket(address, sender);
+		loop {
+			let message = tokio::select! {
+				message = source.next() => message,
+				_ = self.wait_closed() => break,
+			};
+			match message {
+				Some(Ok(Message::Binary(data))) => self.handle_socket_message(&data, &socket).await,
+				Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+				_ => {}
+			}
+		}
+
+		self.cleanup_socket(address).await;
+		// Let the writer send a close frame, unless the client has stopped reading
+		let _ = stop.send(());
+		let _ = tokio::time::timeout(WEBSOCKET_CLOSE_TIMEOUT, writer).await;
+	}
+}
@@ -1,6 +1,40 @@
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
 
+pub const CORE_UNKNOWN: u32 = 0x00010001;
+pub const CORE_NOT_IMPLEMENTED: u32 = 0x00010002;
+pub const CORE_EXCEPTION: u32 = 0x00010005;
+pub const CORE_INVALID_ARGUMENT: u32 = 0x0001000A;
+pub const RENDEZ_VOUS_MAX_CONNECTIONS_REACHED: u32 = 0x0003006C;
+pub const RENDEZ_VOUS_LIMIT_EXCEEDED: u32 = 0x000300DF;
+
+// Set on the result codes of error responses
+pub const ERROR_MASK: u32 = 1 << 31;
+
+// A result code without the error bit, so codes read from error responses
+// compare equal to the constants above
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ResultCode(u32);
+
+impl ResultCode {
+	pub fn new(code: u32) -> Self { Self(code & !ERROR_MASK) }
+
+	pub fn code(&self) -> u32 { self.0 }
+
+	// The code as sent in error responses, with the error bit set
+	pub fn error_code(&self) -> u32 { self.0 | ERROR_MASK }
+
+	pub fn name(&self) -> String { result_code_to_name(self.0) }
+}
+
+impl From<u32> for ResultCode {
+	fn from(code: u32) -> Self { Self::new(code) }
+}
+
+impl std::fmt::Display for ResultCode {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { write!(f, "{} (0x{:08X})", self.name(), self.0) }
+}
+
 pub static RESULT_NAMES: Lazy<HashMap<u32, &'static str>> = Lazy::new(|| {
 	let mut m = HashMap::new();
 	// Core
@@ -80,7 +114,7 @@ pub static RESULT_NAMES: Lazy<HashMap<u32, &'static str>> = Lazy::new(|| {
 
 pub fn result_code_to_name(code: u32) -> String {
 	RESULT_NAMES
-		.get(&code)
+		.get(&(code & !ERROR_MASK))
 		.copied()
 		.unwrap_or("Invalid Result Code")
 		.to_string()
@@ -0,0 +1,171 @@
+// Readable text and JSON forms of RMC messages, for logs and support tickets.
+// Names are only known for verbose messages, the protocol registry fills them in
+// for packed ones and also decodes the parameters
+use crate::result_codes::result_code_to_name;
+use crate::rmc_message::RmcMessage;
+use crate::types::rv_type::hex;
+use crate::NexResult;
+use std::fmt::Write;
+
+type Names<'a> = (Option<&'a str>, Option<&'a str>);
+
+impl RmcMessage {
+	// One JSON object on a single line, so messages can be stored as JSON lines.
+	// The parameters are hex encoded
+	pub fn to_json(&self) -> String { self.json_with((None, None)) }
+
+	pub(crate) fn json_with(&self, names: Names) -> String {
+		let view = self.json_view_with(names);
+		#[cfg(feature = "serde")]
+		return serde_json::to_string(&view).unwrap_or_default();
+		#[cfg(not(feature = "serde"))]
+		return view.to_json();
+	}
+
+	// The fields written by to_json, for serializing the message in another format
+	pub fn json_view(&self) -> RmcMessageJson<'_> { self.json_view_with((None, None)) }
+
+	pub(crate) fn json_view_with<'a>(&'a self, names: Names<'a>) -> RmcMessageJson<'a> {
+		let (protocol_name, method_name) = self.names(names);
+		let is_error = !self.is_request() && !self.is_success();
+		RmcMessageJson {
+			request: self.is_request(),
+			verbose: self.verbose_mode,
+			hpp: self.is_hpp,
+			protocol_id: self.protocol_id,
+			protocol_name,
+			method_id: self.method_id,
+			method_name,
+			call_id: self.call_id,
+			success: (!self.is_request()).then_some(self.is_success()),
+			error_code: is_error.then_some(self.error_code()),
+			result: is_error.then(|| result_code_to_name(self.error_code())),
+			parameters: hex(&self.parameters),
+		}
+	}
+
+	pub(crate) fn format_with(&self, names: Names, parameters: Option<NexResult<Vec<(String, String)>>>) -> String {
+		let (protocol_name, method_name) = self.names(names);
+		let mut text = String::from("RMCMessage{\n");
+		let _ = writeln!(text, "\t{}: {},", if self.is_request() { "Request" } else { "Response" }, if self.verbose_mode { "verbose" } else if self.is_hpp { "HPP" } else { "packed" });
+		let _ = writeln!(text, "\tProtocol: {},", self.named(protocol_name, self.protocol_id));
+		let _ = writeln!(text, "\tMethod: {},", self.named(method_name, self.method_id));
+		let _ = writeln!(text, "\tCallID: {},", self.call_id);
+		if !self.is_request() && !self.is_success() {
+			let _ = writeln!(text, "\tResult: {} (0x{:08X}),", result_code_to_name(self.error_code()), self.error_code());
+			text.push('}');
+			return text;
+		}
+
+		match parameters {
+			Some(Ok(fields)) if fields.is_empty() => text.push_str("\tParameters: {},\n"),
+			Some(Ok(fields)) => {
+				text.push_str("\tParameters: {\n");
+				for (name, value) in fields { let _ = writeln!(text, "\t\t{name}: {value},"); }
+				text.push_str("\t},\n");
+			}
+			Some(Err(err)) => {
+				let _ = writeln!(text, "\tParameters ({} bytes, not decoded: {err}):", self.parameters.len());
+				text.push_str(&hex_dump(&self.parameters, 2));
+			}
+			None => {
+				let _ = writeln!(text, "\tParameters ({} bytes):", self.parameters.len());
+				text.push_str(&hex_dump(&self.parameters, 2));
+			}
+		}
+		text.push('}');
+		text
+	}
+
+	// Verbose messages are only identified by name, their IDs are not sent
+	fn named(&self, name: Option<&str>, id: impl std::fmt::Display) -> String {
+		match name {
+			Some(name) if self.verbose_mode => name.to_string(),
+			Some(name) => format!("{name} ({id})"),
+			None => id.to_string(),
+		}
+	}
+
+	fn names<'a>(&'a self, names: Names<'a>) -> Names<'a> {
+		let own = |name: &'a String| (self.verbose_mode && !name.is_empty()).then_some(name.as_str());
+		(names.0.or_else(|| own(&self.protocol_name)), names.1.or_else(|| own(&self.method_name)))
+	}
+}
+
+// An RMC message as written by RmcMessage::to_json. Fields which do not apply
+// to the message, such as the error code of a request, are left out
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RmcMessageJson<'a> {
+	pub request: bool,
+	pub verbose: bool,
+	pub hpp: bool,
+	pub protocol_id: u16,
+	#[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+	pub protocol_name: Option<&'a str>,
+	pub method_id: u32,
+	#[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+	pub method_name: Option<&'a str>,
+	pub call_id: u32,
+	#[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+	pub success: Option<bool>,
+	#[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+	pub error_code: Option<u32>,
+	#[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+	pub result: Option<String>,
+	// Hex encoded
+	pub parameters: String,
+}
+
+// Without the serde feature the JSON is written by hand, in the same field order
+#[cfg(not(feature = "serde"))]
+impl RmcMessageJson<'_> {
+	fn to_json(&self) -> String {
+		let mut json = format!("{{\"request\":{},\"verbose\":{},\"hpp\":{},\"protocol_id\":{},", self.request, self.verbose, self.hpp, self.protocol_id);
+		if let Some(name) = self.protocol_name { let _ = write!(json, "\"protocol_name\":{},", json_string(name)); }
+		let _ = write!(json, "\"method_id\":{},", self.method_id);
+		if let Some(name) = self.method_name { let _ = write!(json, "\"method_name\":{},", json_string(name)); }
+		let _ = write!(json, "\"call_id\":{},", self.call_id);
+		if let Some(success) = self.success { let _ = write!(json, "\"success\":{success},"); }
+		if let Some(error_code) = self.error_code { let _ = write!(json, "\"error_code\":{error_code},"); }
+		if let Some(result) = &self.result { let _ = write!(json, "\"result\":{},", json_string(result)); }
+		let _ = write!(json, "\"parameters\":\"{}\"}}", self.parameters);
+		json
+	}
+}
+
+// Shows the names when known, and the protocol and method IDs. Packed messages
+// without a registry only have the IDs
+impl std::fmt::Display for RmcMessage {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str(&self.format_with((None, None), None)) }
+}
+
+// 16 bytes per line, with their offset and the printable ASCII characters
+fn hex_dump(data: &[u8], indentation_level: usize) -> String {
+	let indentation = "\t".repeat(indentation_level);
+	let mut dump = String::new();
+	for (i, line) in data.chunks(16).enumerate() {
+		let bytes: Vec<String> = line.iter().map(|byte| format!("{byte:02x}")).collect();
+		let ascii: String = line.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+		let _ = writeln!(dump, "{indentation}{:04x}  {:<47}  |{ascii}|", i * 16, bytes.join(" "));
+	}
+	dump
+}
+
+#[cfg(not(feature = "serde"))]
+fn json_string(value: &str) -> String {
+	let mut json = String::from("\"");
+	for c in value.chars() {
+		match c {
+			'"' => json.push_str("\\\""),
+			'\\' => json.push_str("\\\\"),
+			'\n' => json.push_str("\\n"),
+			'\r' => json.push_str("\\r"),
+			'\t' => json.push_str("\\t"),
+			c if (c as u32) < 0x20 => { let _ = write!(json, "\\u{:04x}", c as u32); }
+			c => json.push(c),
+		}
+	}
+	json.push('"');
+	json
+}
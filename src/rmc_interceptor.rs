@@ -0,0 +1,108 @@
+use crate::error::NexError;
+use crate::prudp::endpoint::PrudpEndpoint;
+use crate::result_codes::CORE_EXCEPTION;
+use crate::rmc_message::RmcMessage;
+use crate::service_protocol::RmcContext;
+use crate::trace::event;
+use crate::NexResult;
+use std::any::Any;
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+pub type InterceptorFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// Behavior around every RMC request an endpoint receives, such as
+// authentication checks, timing or audit logging. before runs in the order the
+// interceptors were added and after in the reverse order. Requests left to the
+// data handlers only go through before, unless it stops them
+pub trait RmcInterceptor: Send + Sync {
+	// Runs before the request is handled. An error stops the request there and is
+	// sent back as the response, a NexError::ResultCode with its result code. The
+	// after of this interceptor and of the ones before it still run
+	fn before<'a>(&'a self, ctx: &'a RmcContext<'a>) -> InterceptorFuture<'a, NexResult<()>> {
+		let _ = ctx;
+		Box::pin(async { Ok(()) })
+	}
+
+	// Runs once the response is known, and may replace it. elapsed is the time
+	// since the first interceptor started
+	fn after<'a>(&'a self, ctx: &'a RmcContext<'a>, response: &'a mut NexResult<RmcMessage>, elapsed: Duration) -> InterceptorFuture<'a, ()> {
+		let _ = (ctx, response, elapsed);
+		Box::pin(async {})
+	}
+}
+
+impl PrudpEndpoint {
+	pub fn add_rmc_interceptor(&self, interceptor: Arc<dyn RmcInterceptor>) {
+		self.rmc_interceptors.lock().unwrap().push(interceptor);
+	}
+
+	// Runs the handler between the interceptors. A handler which panics fails with
+	// Core::Exception instead of taking the connection down with it
+	pub(crate) async fn intercept(&self, ctx: &RmcContext<'_>, handler: impl Future<Output = NexResult<RmcMessage>> + Send) -> NexResult<RmcMessage> {
+		let interceptors = self.rmc_interceptors.lock().unwrap().clone();
+		let started_at = self.now();
+		let (entered, stopped) = run_before(&interceptors, ctx).await;
+		let mut response = match stopped {
+			Some(err) => Err(err),
+			None => CatchPanic(Box::pin(handler)).await,
+		};
+		self.run_after(&interceptors[..entered], ctx, &mut response, started_at).await;
+		response
+	}
+
+	// Runs before of the interceptors for a request left to the data handlers.
+	// Returns the response if one of them stopped the request, after running the
+	// after of those which ran. Otherwise the data handlers answer the request and
+	// after does not run, since there is no response to pass it
+	pub(crate) async fn intercept_before(&self, ctx: &RmcContext<'_>) -> Option<NexResult<RmcMessage>> {
+		let interceptors = self.rmc_interceptors.lock().unwrap().clone();
+		let started_at = self.now();
+		let (entered, stopped) = run_before(&interceptors, ctx).await;
+		let mut response = Err(stopped?);
+		self.run_after(&interceptors[..entered], ctx, &mut response, started_at).await;
+		Some(response)
+	}
+
+	async fn run_after(&self, interceptors: &[Arc<dyn RmcInterceptor>], ctx: &RmcContext<'_>, response: &mut NexResult<RmcMessage>, started_at: Instant) {
+		for interceptor in interceptors.iter().rev() { interceptor.after(ctx, response, self.now().saturating_duration_since(started_at)).await; }
+	}
+}
+
+// Runs before of each interceptor until one fails. Returns how many ran, with
+// the error of the one which failed
+async fn run_before(interceptors: &[Arc<dyn RmcInterceptor>], ctx: &RmcContext<'_>) -> (usize, Option<NexError>) {
+	for (index, interceptor) in interceptors.iter().enumerate() {
+		if let Err(err) = interceptor.before(ctx).await { return (index + 1, Some(err)); }
+	}
+	(interceptors.len(), None)
+}
+
+struct CatchPanic<F>(Pin<Box<F>>);
+
+impl<F: Future<Output = NexResult<RmcMessage>>> Future for CatchPanic<F> {
+	type Output = NexResult<RmcMessage>;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		match catch_unwind(AssertUnwindSafe(|| self.0.as_mut().poll(cx))) {
+			Ok(poll) => poll,
+			Err(panic) => {
+				let message = panic_message(&*panic);
+				event!(error, panic = %message, "RMC method panicked");
+				Poll::Ready(Err(NexError::ResultCode { code: CORE_EXCEPTION, message: format!("RMC method panicked: {message}") }))
+			}
+		}
+	}
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+	match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+		(Some(message), _) => message.to_string(),
+		(_, Some(message)) => message.clone(),
+		_ => "unknown panic".into(),
+	}
+}
@@ -1,27 +1,156 @@
-use crate::io::{ByteStreamIn, ByteStreamOut};
+use crate::error::NexError;
+use crate::io::{ByteStreamIn, ByteStreamOut, ByteStreamSettings, LibraryVersions};
+use crate::result_codes::{ResultCode, CORE_UNKNOWN};
+use crate::types::class_version_container::ClassVersionContainer;
 use crate::NexResult;
 
-#[derive(Debug, Clone, Default)]
+// An RMC request or response. Packed messages identify the protocol and method
+// by ID, verbose messages (used by Quazal titles) by name
+#[derive(Debug, Clone)]
 pub struct RmcMessage {
-	pub is_request: bool,
-	pub is_success: bool,
+	// Picks the format used by to_bytes. Set on decoded messages from the
+	// use_verbose_rmc setting of the endpoint or client
+	pub verbose_mode: bool,
+	// Only set by the constructors and decoding, and by sending a message as a
+	// call, so every response is either successful or carries an error code
+	pub(crate) is_request: bool,
+	// Only set by success, error and decoding, so a response can not be both
+	// successful and carry an error code
+	is_success: bool,
 	pub is_hpp: bool,
 	pub protocol_id: u16,
+	pub protocol_name: String,
 	pub call_id: u32,
 	pub method_id: u32,
-	pub error_code: u32,
+	// Without the * which marks verbose responses, it is added when encoding
+	pub method_name: String,
+	error_code: u32,
+	// Only sent with verbose requests. An empty container is sent if not set
+	pub version_container: Option<ClassVersionContainer>,
 	pub parameters: Vec<u8>,
 }
 
+// An empty packed request, so a message is never a response without being
+// successful or carrying an error code
+impl Default for RmcMessage {
+	fn default() -> Self {
+		Self {
+			verbose_mode: false,
+			is_request: true,
+			is_success: false,
+			is_hpp: false,
+			protocol_id: 0,
+			protocol_name: String::new(),
+			call_id: 0,
+			method_id: 0,
+			method_name: String::new(),
+			error_code: 0,
+			version_container: None,
+			parameters: Vec::new(),
+		}
+	}
+}
+
+// How RMC messages are decoded. Endpoints and clients build this from their own
+// settings, so that parameters are read the way the title expects
+#[derive(Debug, Clone, Default)]
+pub struct RmcSettings {
+	pub verbose: bool,
+	// HPP responses have no protocol ID
+	pub is_hpp: bool,
+	pub library_versions: Option<LibraryVersions>,
+	pub byte_stream_settings: Option<ByteStreamSettings>,
+}
+
 impl RmcMessage {
-	pub fn from_bytes_packed(mut data: Vec<u8>) -> NexResult<Self> {
-		let mut s = ByteStreamIn::new(data, None, None);
-		let _length = s.read_u32_le()?;
-		let protocol = s.read_u8()?;
+	// nex-go's NewRMCSuccess. A successful response to a request, in the format
+	// of the request and with its call, protocol and method
+	pub fn success(request: &RmcMessage, parameters: Vec<u8>) -> Self {
+		Self { is_success: true, parameters, ..Self::response_to(request) }
+	}
+
+	// nex-go's NewRMCError. An error response to a request, with the error bit set
+	// on the result code. 0 is not an error, so it is sent as Core::Unknown
+	pub fn error(request: &RmcMessage, result_code: impl Into<ResultCode>) -> Self {
+		let result_code = match result_code.into() {
+			result_code if result_code.code() == 0 => ResultCode::new(CORE_UNKNOWN),
+			result_code => result_code,
+		};
+		Self { error_code: result_code.error_code(), ..Self::response_to(request) }
+	}
+
+	// A packed request, which is given its call ID when it is sent
+	pub fn request(protocol_id: u16, method_id: u32, parameters: Vec<u8>) -> Self {
+		Self { protocol_id, method_id, parameters, ..Default::default() }
+	}
+
+	fn response_to(request: &RmcMessage) -> Self {
+		Self {
+			is_request: false,
+			verbose_mode: request.verbose_mode,
+			is_hpp: request.is_hpp,
+			protocol_id: request.protocol_id,
+			protocol_name: request.protocol_name.clone(),
+			call_id: request.call_id,
+			method_id: request.method_id,
+			method_name: request.method_name.clone(),
+			..Default::default()
+		}
+	}
+
+	pub fn is_request(&self) -> bool { self.is_request }
+
+	pub fn is_success(&self) -> bool { self.is_success }
+
+	// The result code of an error response, with the error bit set. 0 for
+	// anything else
+	pub fn error_code(&self) -> u32 { self.error_code }
+
+	// The result code of an error response
+	pub fn result_code(&self) -> Option<ResultCode> {
+		(!self.is_request && !self.is_success).then(|| ResultCode::new(self.error_code))
+	}
+
+	// Decodes a whole message. The length prefix must match the bytes which
+	// follow it exactly, so truncated messages and trailing data are rejected
+	pub fn from_bytes(data: Vec<u8>, settings: &RmcSettings) -> NexResult<Self> {
+		let mut s = ByteStreamIn::new(data, settings.library_versions.clone(), settings.byte_stream_settings.clone());
+		let declared = s.read_u32_le()? as usize;
+		if declared != s.remaining() { return Err(NexError::RmcLength { declared, available: s.remaining() }); }
+		let mut msg = if settings.verbose { Self::read_verbose(&mut s)? } else { Self::read_packed(&mut s, settings.is_hpp)? };
+		msg.verbose_mode = settings.verbose;
+		msg.is_hpp = settings.is_hpp;
+		Ok(msg)
+	}
+
+	pub fn from_bytes_packed(data: Vec<u8>) -> NexResult<Self> { Self::from_bytes(data, &RmcSettings::default()) }
+
+	pub fn from_bytes_verbose(data: Vec<u8>) -> NexResult<Self> { Self::from_bytes(data, &RmcSettings { verbose: true, ..Default::default() }) }
+
+	// Checks a message which is still being reassembled from fragments has not
+	// grown past its length prefix
+	pub(crate) fn check_partial(data: &[u8]) -> NexResult<()> {
+		let Some(prefix) = data.first_chunk::<4>() else { return Ok(()) };
+		let declared = u32::from_le_bytes(*prefix) as usize;
+		if data.len() - 4 > declared { return Err(NexError::RmcLength { declared, available: data.len() - 4 }); }
+		Ok(())
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		if self.verbose_mode { self.to_bytes_verbose() } else { self.to_bytes_packed() }
+	}
+
+	fn read_packed(s: &mut ByteStreamIn, is_hpp: bool) -> NexResult<Self> {
 		let mut msg = RmcMessage::default();
-		msg.protocol_id = (protocol & !0x80) as u16;
-		if msg.protocol_id == 0x7F { msg.protocol_id = s.read_u16_le()?; }
-		if protocol & 0x80 != 0 {
+		// HPP responses have no protocol ID and start with the success flag, which
+		// never has the request bit set
+		let has_protocol = !is_hpp || s.bytes().get(s.position()).is_some_and(|&byte| byte & 0x80 != 0);
+		let protocol = if has_protocol { Some(s.read_u8()?) } else { None };
+		if let Some(protocol) = protocol {
+			msg.protocol_id = (protocol & !0x80) as u16;
+			if msg.protocol_id == 0x7F { msg.protocol_id = s.read_u16_le()?; }
+		}
+		if protocol.is_some_and(|protocol| protocol & 0x80 != 0) {
 			msg.is_request = true;
 			msg.call_id = s.read_u32_le()?;
 			msg.method_id = s.read_u32_le()?;
@@ -44,7 +173,7 @@ impl RmcMessage {
 	pub fn to_bytes_packed(&self) -> Vec<u8> {
 		let mut inner = ByteStreamOut::new(None, None);
 		let flag = if self.is_request { 0x80u16 } else { 0 };
-		if !self.is_hpp || (self.is_hpp && self.is_request) {
+		if !self.is_hpp || self.is_request {
 			if self.protocol_id < 0x80 { inner.write_u8((self.protocol_id as u8) | (flag as u8)); }
 			else { inner.write_u8(0x7F | (flag as u8)); inner.write_u16_le(self.protocol_id); }
 		}
@@ -68,6 +197,210 @@ impl RmcMessage {
 		msg.write(inner.bytes());
 		msg.bytes().to_vec()
 	}
+
+	fn read_verbose(s: &mut ByteStreamIn) -> NexResult<Self> {
+		let mut msg = RmcMessage { protocol_name: s.read_string()?, ..Default::default() };
+		msg.is_request = s.read_bool()?;
+		if msg.is_request {
+			msg.call_id = s.read_u32_le()?;
+			msg.method_name = s.read_string()?;
+			msg.version_container = Some(ClassVersionContainer::read_from(s)?);
+			msg.parameters = s.read_remaining();
+		} else {
+			msg.is_success = s.read_bool()?;
+			if msg.is_success {
+				msg.call_id = s.read_u32_le()?;
+				let method_name = s.read_string()?;
+				msg.method_name = method_name.strip_suffix('*').unwrap_or(&method_name).to_string();
+				msg.parameters = s.read_remaining();
+			} else {
+				msg.error_code = s.read_u32_le()?;
+				msg.call_id = s.read_u32_le()?;
+			}
+		}
+		Ok(msg)
+	}
+
+	pub fn to_bytes_verbose(&self) -> Vec<u8> {
+		let mut inner = ByteStreamOut::new(None, None);
+		inner.write_string(&self.protocol_name);
+		inner.write_bool(self.is_request);
+		if self.is_request {
+			inner.write_u32_le(self.call_id);
+			inner.write_string(&self.method_name);
+			match &self.version_container {
+				Some(version_container) => version_container.write_to(&mut inner),
+				None => inner.write_u32_le(0),
+			}
+			inner.write(&self.parameters);
+		} else {
+			inner.write_bool(self.is_success);
+			if self.is_success {
+				inner.write_u32_le(self.call_id);
+				inner.write_string(&format!("{}*", self.method_name));
+				inner.write(&self.parameters);
+			} else {
+				inner.write_u32_le(self.error_code);
+				inner.write_u32_le(self.call_id);
+			}
+		}
+		let mut msg = ByteStreamOut::new(None, None);
+		msg.write_u32_le(inner.bytes().len() as u32);
+		msg.write(inner.bytes());
+		msg.bytes().to_vec()
+	}
 }
 
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::result_codes::{CORE_NOT_IMPLEMENTED, ERROR_MASK};
+
+	fn request() -> RmcMessage {
+		let mut request = RmcMessage::request(0x0A, 1, vec![1, 2, 3]);
+		request.call_id = 7;
+		request
+	}
+
+	#[test]
+	fn default_is_an_empty_request() {
+		let message = RmcMessage::default();
+		assert!(message.is_request() && !message.is_success());
+		assert_eq!(message.result_code(), None);
+	}
+
+	#[test]
+	fn success_responses_have_no_result_code() {
+		let response = RmcMessage::success(&request(), vec![4]);
+		assert!(!response.is_request() && response.is_success());
+		assert_eq!((response.call_id, response.protocol_id, response.method_id), (7, 0x0A, 1));
+		assert_eq!((response.error_code(), response.result_code()), (0, None));
+	}
+
+	#[test]
+	fn error_responses_carry_the_result_code() {
+		let response = RmcMessage::error(&request(), CORE_NOT_IMPLEMENTED);
+		assert!(!response.is_request() && !response.is_success());
+		assert_eq!(response.error_code(), CORE_NOT_IMPLEMENTED | ERROR_MASK);
+		assert_eq!(response.result_code(), Some(ResultCode::new(CORE_NOT_IMPLEMENTED)));
+		// With or without the error bit
+		assert_eq!(RmcMessage::error(&request(), CORE_NOT_IMPLEMENTED | ERROR_MASK).error_code(), response.error_code());
+	}
 
+	#[test]
+	fn error_responses_never_carry_result_code_zero() {
+		let response = RmcMessage::error(&request(), 0);
+		assert_eq!(response.result_code(), Some(ResultCode::new(CORE_UNKNOWN)));
+	}
+
+	#[test]
+	fn packed_responses_round_trip() {
+		let success = RmcMessage::success(&request(), vec![4, 5]);
+		let decoded = RmcMessage::from_bytes_packed(success.to_bytes()).unwrap();
+		assert!(!decoded.is_request() && decoded.is_success());
+		assert_eq!((decoded.call_id, decoded.protocol_id, decoded.method_id, decoded.parameters), (7, 0x0A, 1, vec![4, 5]));
+
+		let error = RmcMessage::error(&request(), CORE_NOT_IMPLEMENTED);
+		let decoded = RmcMessage::from_bytes_packed(error.to_bytes()).unwrap();
+		assert!(!decoded.is_request() && !decoded.is_success());
+		assert_eq!((decoded.call_id, decoded.protocol_id, decoded.result_code()), (7, 0x0A, Some(ResultCode::new(CORE_NOT_IMPLEMENTED))));
+	}
+
+	#[test]
+	fn packed_requests_round_trip_with_long_protocol_ids() {
+		let mut request = RmcMessage::request(0x1234, 5, vec![6]);
+		request.call_id = 8;
+		let decoded = RmcMessage::from_bytes_packed(request.to_bytes()).unwrap();
+		assert!(decoded.is_request());
+		assert_eq!((decoded.call_id, decoded.protocol_id, decoded.method_id, decoded.parameters), (8, 0x1234, 5, vec![6]));
+	}
+
+	#[test]
+	fn truncated_and_oversized_messages_are_rejected() {
+		let mut data = request().to_bytes();
+		data.push(0);
+		let Err(NexError::RmcLength { declared, available }) = RmcMessage::from_bytes_packed(data.clone()) else { panic!("oversized message was decoded") };
+		assert_eq!((declared, available), (12, 13));
+
+		data.truncate(data.len() - 2);
+		let Err(NexError::RmcLength { declared, available }) = RmcMessage::from_bytes_packed(data) else { panic!("truncated message was decoded") };
+		assert_eq!((declared, available), (12, 11));
+	}
+
+	#[test]
+	fn partial_messages_may_not_grow_past_their_prefix() {
+		let data = request().to_bytes();
+		assert!(RmcMessage::check_partial(&data[..2]).is_ok());
+		assert!(RmcMessage::check_partial(&data[..8]).is_ok());
+		assert!(RmcMessage::check_partial(&data).is_ok());
+		let mut grown = data.clone();
+		grown.push(0);
+		let Err(NexError::RmcLength { declared, available }) = RmcMessage::check_partial(&grown) else { panic!("message grew past its prefix") };
+		assert_eq!((declared, available), (12, 13));
+	}
+
+	#[test]
+	fn hpp_responses_are_decoded_without_the_protocol() {
+		let settings = RmcSettings { is_hpp: true, ..Default::default() };
+		let mut request = request();
+		request.is_hpp = true;
+
+		let success = RmcMessage::success(&request, vec![4]);
+		let data = success.to_bytes();
+		// Length, success flag, call ID, method ID and parameters
+		assert_eq!(data.len(), 4 + 1 + 4 + 4 + 1);
+		let decoded = RmcMessage::from_bytes(data, &settings).unwrap();
+		assert!(decoded.is_hpp && !decoded.is_request() && decoded.is_success());
+		assert_eq!((decoded.protocol_id, decoded.call_id, decoded.method_id, decoded.parameters), (0, 7, 1, vec![4]));
+
+		let error = RmcMessage::from_bytes(RmcMessage::error(&request, CORE_NOT_IMPLEMENTED).to_bytes(), &settings).unwrap();
+		assert!(!error.is_request() && !error.is_success());
+		assert_eq!((error.call_id, error.result_code()), (7, Some(ResultCode::new(CORE_NOT_IMPLEMENTED))));
+
+		// Requests still start with the protocol
+		let decoded = RmcMessage::from_bytes(request.to_bytes(), &settings).unwrap();
+		assert!(decoded.is_request());
+		assert_eq!((decoded.protocol_id, decoded.call_id, decoded.method_id), (0x0A, 7, 1));
+	}
+
+	fn verbose_request() -> RmcMessage {
+		let mut request = request();
+		request.verbose_mode = true;
+		request.protocol_name = "TicketGranting".into();
+		request.method_name = "Login".into();
+		request
+	}
+
+	#[test]
+	fn verbose_requests_round_trip() {
+		// An empty version container is sent when none is set
+		let decoded = RmcMessage::from_bytes_verbose(verbose_request().to_bytes()).unwrap();
+		assert!(decoded.verbose_mode && decoded.is_request());
+		assert_eq!((decoded.protocol_name.as_str(), decoded.method_name.as_str(), decoded.call_id, decoded.parameters.as_slice()), ("TicketGranting", "Login", 7, [1, 2, 3].as_slice()));
+		assert_eq!(decoded.version_container, Some(ClassVersionContainer::default()));
+
+		let mut request = verbose_request();
+		let mut version_container = ClassVersionContainer::default();
+		version_container.class_versions.insert("Data".into(), 1);
+		request.version_container = Some(version_container.clone());
+		let decoded = RmcMessage::from_bytes_verbose(request.to_bytes()).unwrap();
+		assert_eq!(decoded.version_container, Some(version_container));
+		assert_eq!(decoded.parameters, [1, 2, 3]);
+	}
+
+	#[test]
+	fn verbose_responses_round_trip_without_the_star() {
+		let success = RmcMessage::success(&verbose_request(), vec![4]);
+		let data = success.to_bytes();
+		assert!(data.windows(6).any(|window| window == b"Login*"));
+		let decoded = RmcMessage::from_bytes_verbose(data).unwrap();
+		assert!(!decoded.is_request() && decoded.is_success());
+		assert_eq!((decoded.protocol_name.as_str(), decoded.method_name.as_str(), decoded.call_id, decoded.parameters.as_slice()), ("TicketGranting", "Login", 7, [4].as_slice()));
+		// Encoding again adds a single star
+		assert_eq!(decoded.to_bytes(), success.to_bytes());
+
+		let error = RmcMessage::from_bytes_verbose(RmcMessage::error(&verbose_request(), CORE_NOT_IMPLEMENTED).to_bytes()).unwrap();
+		assert!(!error.is_request() && !error.is_success());
+		assert_eq!((error.protocol_name.as_str(), error.call_id, error.result_code()), ("TicketGranting", 7, Some(ResultCode::new(CORE_NOT_IMPLEMENTED))));
+	}
+}
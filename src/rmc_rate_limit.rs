@@ -0,0 +1,251 @@
+use crate::error::NexError;
+use crate::prudp::connection::PrudpConnection;
+use crate::prudp::endpoint::PrudpEndpoint;
+use crate::prudp::packet::PrudpPacket;
+use crate::result_codes::RENDEZ_VOUS_LIMIT_EXCEEDED;
+use crate::rmc_message::RmcMessage;
+use crate::trace::event;
+use crate::types::pid::Pid;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+// Buckets are only pruned once there are this many, and then every time their
+// number has doubled
+const MIN_PRUNE_SIZE: usize = 1024;
+
+// Whose calls share a bucket. Connections without a PID, such as those to the
+// authentication server, are limited per connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitScope {
+	Pid,
+	Connection,
+}
+
+// What happens to a call over the limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitAction {
+	// The call fails with this result code
+	Reject(u32),
+	Disconnect,
+}
+
+// A token bucket limit on RMC calls. Every call takes a token and tokens are
+// added back at per_second, up to burst. Calls without a token are not handled
+#[derive(Debug, Clone)]
+pub struct RmcRateLimit {
+	pub burst: u32,
+	pub per_second: f64,
+	pub scope: RateLimitScope,
+	pub action: RateLimitAction,
+}
+
+impl RmcRateLimit {
+	// Limited per PID, rejecting calls over the limit with RendezVous::LimitExceeded
+	pub fn new(burst: u32, per_second: f64) -> Self {
+		Self { burst, per_second, scope: RateLimitScope::Pid, action: RateLimitAction::Reject(RENDEZ_VOUS_LIMIT_EXCEEDED) }
+	}
+}
+
+// A limit applies to one method, or to every method of a protocol when the
+// method ID is None
+type LimitKey = (u16, Option<u32>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Subject {
+	Pid(Pid),
+	Connection(u32),
+}
+
+#[derive(Debug)]
+struct Bucket {
+	tokens: f64,
+	updated: Instant,
+}
+
+impl Bucket {
+	fn refill(&mut self, limit: &RmcRateLimit, now: Instant) {
+		let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+		self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+		self.updated = now;
+	}
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+	limits: HashMap<LimitKey, RmcRateLimit>,
+	buckets: HashMap<(LimitKey, Subject), Bucket>,
+	prune_size: usize,
+}
+
+impl RateLimiter {
+	// Takes a token from the bucket of every limit on the method and its protocol.
+	// Returns the action of the first limit which had none left, in which case no
+	// token is taken from any of them
+	fn check(&mut self, protocol_id: u16, method_id: u32, connection: &PrudpConnection, now: Instant) -> Option<RateLimitAction> {
+		let pid = connection.pid();
+		let mut matching = Vec::with_capacity(2);
+		for key in [(protocol_id, Some(method_id)), (protocol_id, None)] {
+			let Some(limit) = self.limits.get(&key) else { continue };
+			let subject = match limit.scope {
+				RateLimitScope::Pid if pid != Pid(0) => Subject::Pid(pid),
+				_ => Subject::Connection(connection.id),
+			};
+			let bucket = self.buckets.entry((key, subject)).or_insert_with(|| Bucket { tokens: limit.burst as f64, updated: now });
+			bucket.refill(limit, now);
+			matching.push(((key, subject), bucket.tokens >= 1.0, limit.action));
+		}
+		let rejected = matching.iter().find(|(_, has_token, _)| !has_token).map(|&(_, _, action)| action);
+		if rejected.is_none() {
+			for (bucket, _, _) in &matching { self.buckets.get_mut(bucket).unwrap().tokens -= 1.0; }
+		}
+		// Rejected calls create buckets too, so pruning runs whatever the outcome
+		self.prune(now);
+		rejected
+	}
+
+	// Buckets which have filled up again are the same as new ones
+	fn prune(&mut self, now: Instant) {
+		if self.buckets.len() < self.prune_size.max(MIN_PRUNE_SIZE) { return; }
+		let limits = &self.limits;
+		self.buckets.retain(|(key, _), bucket| {
+			let Some(limit) = limits.get(key) else { return false };
+			bucket.refill(limit, now);
+			bucket.tokens < limit.burst as f64
+		});
+		self.prune_size = self.buckets.len() * 2;
+	}
+
+	pub(crate) fn remove_connection(&mut self, connection_id: u32) {
+		self.buckets.retain(|(_, subject), _| *subject != Subject::Connection(connection_id));
+	}
+}
+
+impl PrudpEndpoint {
+	// Limits calls to a method, or to every method of a protocol when method_id is
+	// None. Both limits apply to methods which have their own
+	pub fn set_rmc_rate_limit(&self, protocol_id: u16, method_id: Option<u32>, limit: RmcRateLimit) {
+		let mut limiter = self.rate_limiter.lock().unwrap();
+		limiter.buckets.retain(|(key, _), _| *key != (protocol_id, method_id));
+		limiter.limits.insert((protocol_id, method_id), limit);
+	}
+
+	pub fn remove_rmc_rate_limit(&self, protocol_id: u16, method_id: Option<u32>) {
+		let mut limiter = self.rate_limiter.lock().unwrap();
+		limiter.buckets.retain(|(key, _), _| *key != (protocol_id, method_id));
+		limiter.limits.remove(&(protocol_id, method_id));
+	}
+
+	// Returns false if the request is over a limit, after rejecting it or
+	// disconnecting the client
+	pub(crate) async fn enforce_rate_limits(&self, packet: &PrudpPacket, request: &RmcMessage, connection: &Arc<PrudpConnection>) -> bool {
+		let action = self.rate_limiter.lock().unwrap().check(request.protocol_id, request.method_id, connection, self.now());
+		match action {
+			None => true,
+			Some(RateLimitAction::Reject(code)) => {
+				event!(debug, "RMC call over the rate limit");
+				let message = format!("Method {} of protocol {} was called too often", request.method_id, request.protocol_id);
+				self.send_rmc_response(packet, request, Err(NexError::ResultCode { code, message })).await;
+				false
+			}
+			Some(RateLimitAction::Disconnect) => {
+				event!(warn, "Disconnecting client over the RMC rate limit");
+				if let Some(server) = self.server() { let _ = self.send_disconnect(&server, connection).await; }
+				self.cleanup_connection(connection).await;
+				false
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::prudp::stream_settings::StreamSettings;
+	use crate::prudp::virtual_port::VirtualPort;
+	use crate::socket_connection::SocketConnection;
+	use crate::transport::channel::ChannelTransport;
+	use std::net::SocketAddr;
+	use std::sync::Weak;
+	use std::time::Duration;
+
+	const PROTOCOL: u16 = 0x70;
+
+	fn connection(id: u32, pid: u64) -> PrudpConnection {
+		let address = SocketAddr::from(([127, 0, 0, 1], 10000 + id as u16));
+		let (transport, _) = ChannelTransport::pair(address, address);
+		let connection = PrudpConnection::new(SocketConnection::datagram(address, Arc::new(transport)), Weak::new(), id, VirtualPort::new(10, 15), 1, StreamSettings::new(), Instant::now());
+		connection.set_pid(Pid(pid));
+		connection
+	}
+
+	fn limiter(limits: &[(Option<u32>, RmcRateLimit)]) -> RateLimiter {
+		let mut limiter = RateLimiter::default();
+		for (method_id, limit) in limits { limiter.limits.insert((PROTOCOL, *method_id), limit.clone()); }
+		limiter
+	}
+
+	const REJECTED: Option<RateLimitAction> = Some(RateLimitAction::Reject(RENDEZ_VOUS_LIMIT_EXCEEDED));
+
+	#[test]
+	fn tokens_are_refilled_over_time() {
+		let mut limiter = limiter(&[(Some(1), RmcRateLimit::new(2, 1.0))]);
+		let connection = connection(1, 0);
+		let now = Instant::now();
+		assert_eq!(limiter.check(PROTOCOL, 1, &connection, now), None);
+		assert_eq!(limiter.check(PROTOCOL, 1, &connection, now), None);
+		assert_eq!(limiter.check(PROTOCOL, 1, &connection, now), REJECTED);
+		assert_eq!(limiter.check(PROTOCOL, 1, &connection, now + Duration::from_millis(500)), REJECTED);
+		assert_eq!(limiter.check(PROTOCOL, 1, &connection, now + Duration::from_secs(1)), None);
+		// Refilling stops at the burst
+		let later = now + Duration::from_secs(60);
+		assert_eq!((0..3).map(|_| limiter.check(PROTOCOL, 1, &connection, later)).collect::<Vec<_>>(), [None, None, REJECTED]);
+	}
+
+	#[test]
+	fn protocol_rejection_does_not_spend_the_method_token() {
+		let mut limiter = limiter(&[(Some(1), RmcRateLimit::new(2, 0.0)), (None, RmcRateLimit::new(1, 0.0))]);
+		let connection = connection(1, 0);
+		let now = Instant::now();
+		// Method 2 spends the only protocol token
+		assert_eq!(limiter.check(PROTOCOL, 2, &connection, now), None);
+		for _ in 0..5 { assert_eq!(limiter.check(PROTOCOL, 1, &connection, now), REJECTED); }
+		assert_eq!(limiter.buckets[&((PROTOCOL, Some(1)), Subject::Connection(1))].tokens, 2.0);
+	}
+
+	#[test]
+	fn pid_scope_is_shared_between_connections_of_a_user() {
+		let mut limiter = limiter(&[(Some(1), RmcRateLimit::new(1, 0.0))]);
+		let now = Instant::now();
+		assert_eq!(limiter.check(PROTOCOL, 1, &connection(1, 1000), now), None);
+		assert_eq!(limiter.check(PROTOCOL, 1, &connection(2, 1000), now), REJECTED);
+		assert_eq!(limiter.check(PROTOCOL, 1, &connection(3, 1001), now), None);
+		// Connections without a PID are limited on their own
+		assert_eq!(limiter.check(PROTOCOL, 1, &connection(4, 0), now), None);
+		assert_eq!(limiter.check(PROTOCOL, 1, &connection(5, 0), now), None);
+	}
+
+	#[test]
+	fn connection_scope_is_not_shared_between_connections_of_a_user() {
+		let limit = RmcRateLimit { scope: RateLimitScope::Connection, ..RmcRateLimit::new(1, 0.0) };
+		let mut limiter = limiter(&[(Some(1), limit)]);
+		let now = Instant::now();
+		let first = connection(1, 1000);
+		assert_eq!(limiter.check(PROTOCOL, 1, &first, now), None);
+		assert_eq!(limiter.check(PROTOCOL, 1, &first, now), REJECTED);
+		assert_eq!(limiter.check(PROTOCOL, 1, &connection(2, 1000), now), None);
+	}
+
+	#[test]
+	fn buckets_of_rejected_calls_are_pruned() {
+		// A method which is never allowed, so every call is rejected and its bucket
+		// is as full as it gets
+		let limit = RmcRateLimit { scope: RateLimitScope::Connection, ..RmcRateLimit::new(0, 0.0) };
+		let mut limiter = limiter(&[(Some(1), limit)]);
+		let now = Instant::now();
+		for id in 0..MIN_PRUNE_SIZE as u32 {
+			assert_eq!(limiter.check(PROTOCOL, 1, &connection(id, 0), now), REJECTED);
+		}
+		assert!(limiter.buckets.is_empty());
+	}
+}
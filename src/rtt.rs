@@ -1,4 +1,44 @@
-#[derive(Debug, Default)]
-pub struct Rtt;
+use std::time::Duration;
 
+const ALPHA: f64 = 1.0 / 8.0;
+const BETA: f64 = 1.0 / 4.0;
+const K: f64 = 4.0;
 
+// Implementation of rdv::RTT. Tracks the smoothed round trip time of reliable
+// packets using the RFC 6298 calculation. Values are stored in nanoseconds
+#[derive(Debug, Default, Clone)]
+pub struct Rtt {
+	last_rtt: f64,
+	average: f64,
+	variance: f64,
+	initialized: bool,
+}
+
+impl Rtt {
+	pub fn new() -> Self { Self::default() }
+
+	pub fn adjust(&mut self, next: Duration) {
+		let next = next.as_nanos() as f64;
+		if self.initialized {
+			self.variance = (1.0 - BETA) * self.variance + BETA * (self.variance - next).abs();
+			self.average = (1.0 - ALPHA) * self.average + ALPHA * next;
+		} else {
+			self.last_rtt = next;
+			self.variance = next / 2.0;
+			self.average = next + K * self.variance;
+			self.initialized = true;
+		}
+	}
+
+	pub fn smoothed_avg(&self) -> f64 { self.average / 16.0 }
+
+	pub fn smoothed_dev(&self) -> f64 { self.variance / 8.0 }
+
+	pub fn initialized(&self) -> bool { self.initialized }
+
+	pub fn average(&self) -> Duration { Duration::from_nanos(self.average as u64) }
+
+	pub fn variance(&self) -> Duration { Duration::from_nanos(self.variance as u64) }
+
+	pub fn last(&self) -> Duration { Duration::from_nanos(self.last_rtt as u64) }
+}
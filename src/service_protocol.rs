@@ -1,4 +1,161 @@
-#[derive(Debug, Default)]
-pub struct ServiceProtocol;
+use crate::constants::{PrudpPacketFlags, PrudpPacketType};
+use crate::error::NexError;
+use crate::io::{ByteStreamIn, ByteStreamOut};
+use crate::prudp::connection::PrudpConnection;
+use crate::prudp::endpoint::PrudpEndpoint;
+use crate::prudp::packet::PrudpPacket;
+use crate::result_codes::{CORE_INVALID_ARGUMENT, CORE_NOT_IMPLEMENTED, CORE_UNKNOWN};
+use crate::rmc_message::RmcMessage;
+use crate::trace::event;
+use crate::types::pid::Pid;
+use crate::NexResult;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
 
+pub type ProtocolFuture<'a> = Pin<Box<dyn Future<Output = NexResult<RmcMessage>> + Send + 'a>>;
 
+// A NEX protocol, such as the ticket granting protocol. Once registered on an
+// endpoint, every RMC request for the protocol is handed to it and the response
+// it returns is sent back to the client
+pub trait ServiceProtocol: Send + Sync {
+	fn protocol_id(&self) -> u16;
+
+	// Verbose RMC requests name the protocol instead of using its ID. Protocols
+	// which are only used with packed RMC can leave this empty
+	fn protocol_name(&self) -> &str { "" }
+
+	// The ID of a method named by a verbose RMC request
+	fn method_id(&self, method_name: &str) -> Option<u32> {
+		let _ = method_name;
+		None
+	}
+
+	// Requests for methods the protocol does not have are answered with
+	// Core::NotImplemented, without calling handle
+	fn has_method(&self, method_id: u32) -> bool;
+
+	// Handles a request, usually returning RmcMessage::success. Only the
+	// parameters of a successful response, or the error code of a failed one, are
+	// used, the rest is filled in from the request. A NexError::ResultCode is sent
+	// to the client as an error response, any other error as Core::Unknown
+	fn handle<'a>(&'a self, request: &'a RmcMessage, connection: &'a Arc<PrudpConnection>) -> ProtocolFuture<'a>;
+}
+
+// The call being handled by a method of a #[nex_protocol] trait
+pub struct RmcContext<'a> {
+	pub request: &'a RmcMessage,
+	pub connection: &'a Arc<PrudpConnection>,
+}
+
+impl<'a> RmcContext<'a> {
+	pub fn new(request: &'a RmcMessage, connection: &'a Arc<PrudpConnection>) -> Self { Self { request, connection } }
+
+	pub fn pid(&self) -> Pid { self.connection.pid() }
+
+	// Streams using the settings of the connection's endpoint, so PIDs and string
+	// lengths have the size the client expects
+	pub fn byte_stream_in(&self, data: Vec<u8>) -> ByteStreamIn {
+		match self.connection.endpoint() {
+			Some(endpoint) => endpoint.byte_stream_in(data),
+			None => ByteStreamIn::new(data, None, None),
+		}
+	}
+
+	pub fn byte_stream_out(&self) -> ByteStreamOut {
+		match self.connection.endpoint() {
+			Some(endpoint) => endpoint.byte_stream_out(),
+			None => ByteStreamOut::new(None, None),
+		}
+	}
+
+	// Used by the generated code when the request parameters can not be read
+	pub fn invalid_argument(&self, err: NexError) -> NexError {
+		NexError::ResultCode { code: CORE_INVALID_ARGUMENT, message: format!("Invalid parameters for method {} of protocol {}: {err}", self.request.method_id, self.request.protocol_id) }
+	}
+}
+
+impl PrudpEndpoint {
+	// Registers a protocol, replacing any protocol already registered with its ID
+	pub fn register_service_protocol(&self, protocol: Arc<dyn ServiceProtocol>) {
+		self.service_protocols.lock().unwrap().insert(protocol.protocol_id(), protocol);
+	}
+
+	fn find_service_protocol(&self, request: &RmcMessage) -> Option<Arc<dyn ServiceProtocol>> {
+		let protocols = self.service_protocols.lock().unwrap();
+		if request.verbose_mode {
+			protocols.values().find(|protocol| protocol.protocol_name() == request.protocol_name).cloned()
+		} else {
+			protocols.get(&request.protocol_id).cloned()
+		}
+	}
+
+	// Answers an RMC request using the registered protocols, after checking the
+	// rate limits and running the interceptors around it. Requests for other
+	// protocols are left to the data handlers once the rate limits and
+	// interceptors let them through, or answered with Core::NotImplemented when
+	// there are none. Returns true if the request was answered or rejected here,
+	// in which case the data handlers must not see it
+	pub(crate) async fn dispatch_rmc(&self, packet: &PrudpPacket) -> bool {
+		let (Some(request), Some(connection)) = (&packet.rmc_message, &packet.sender) else { return false };
+		if !request.is_request() { return false; }
+
+		let mut request = request.clone();
+		let protocol = self.find_service_protocol(&request);
+		let mut known = protocol.as_ref().is_some_and(|protocol| protocol.has_method(request.method_id));
+		if let (Some(protocol), true) = (&protocol, request.verbose_mode) {
+			let method_id = protocol.method_id(&request.method_name);
+			if let Some(method_id) = method_id {
+				request.method_id = method_id;
+				request.protocol_id = protocol.protocol_id();
+			}
+			known = method_id.is_some_and(|method_id| protocol.has_method(method_id));
+		}
+
+		// Requests over a limit are answered or the client is disconnected, so the
+		// data handlers must not see them either
+		if !self.enforce_rate_limits(packet, &request, connection).await { return true; }
+
+		if protocol.is_none() && self.has_data_handlers() {
+			let Some(response) = self.intercept_before(&RmcContext::new(&request, connection)).await else { return false };
+			self.send_rmc_response(packet, &request, response).await;
+			return true;
+		}
+
+		let ctx = RmcContext::new(&request, connection);
+		let handler = async {
+			match &protocol {
+				Some(protocol) if known => protocol.handle(&request, connection).await,
+				_ => Err(not_implemented(&request)),
+			}
+		};
+		let response = self.intercept(&ctx, handler).await;
+		self.send_rmc_response(packet, &request, response).await;
+		true
+	}
+
+	pub(crate) async fn send_rmc_response(&self, packet: &PrudpPacket, request: &RmcMessage, response: NexResult<RmcMessage>) {
+		let message = match response {
+			Ok(message) if message.error_code() != 0 => RmcMessage::error(request, message.error_code()),
+			Ok(message) => RmcMessage::success(request, message.parameters),
+			Err(NexError::ResultCode { code, .. }) => RmcMessage::error(request, code),
+			Err(err) => {
+				event!(warn, error = %err, "RMC method failed");
+				self.emit_error(err).await;
+				RmcMessage::error(request, CORE_UNKNOWN)
+			}
+		};
+
+		let mut reply = packet.reply(PrudpPacketType::Data);
+		reply.add_flag(PrudpPacketFlags::RELIABLE | PrudpPacketFlags::NEEDS_ACK | PrudpPacketFlags::HAS_SIZE);
+		reply.header.substream_id = packet.header.substream_id;
+		reply.payload = message.to_bytes();
+		reply.sender = packet.sender.clone();
+		if let Err(err) = self.send(reply).await { self.emit_error(err).await; }
+	}
+}
+
+// The error requests for unknown protocols and methods are answered with
+pub fn not_implemented(request: &RmcMessage) -> NexError {
+	NexError::ResultCode { code: CORE_NOT_IMPLEMENTED, message: format!("Method {} of protocol {} is not implemented", request.method_id, request.protocol_id) }
+}
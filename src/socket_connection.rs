@@ -1,12 +1,89 @@
+use crate::transport::{Datagram, Transport};
 use crate::NexResult;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
 
-#[derive(Debug, Default)]
-pub struct SocketConnection;
+#[cfg(feature = "websocket")]
+use tokio::sync::mpsc::UnboundedSender;
+
+// The underlying transport a client talks to the server over
+#[derive(Debug, Clone)]
+pub enum SocketTransport {
+	// UDP or any other datagram transport
+	Datagram(Arc<dyn Transport>),
+	#[cfg(feature = "websocket")]
+	WebSocket(UnboundedSender<Vec<u8>>),
+}
+
+// Represents a single physical client. Every PRUDP virtual connection made by
+// the client shares the same SocketConnection
+#[derive(Debug, Clone)]
+pub struct SocketConnection {
+	address: SocketAddr,
+	transport: SocketTransport,
+}
 
 impl SocketConnection {
-	pub fn new() -> Self { Self }
-	pub fn send(&self, _buf: &[u8]) -> NexResult<()> { Ok(()) }
-	pub fn receive(&self) -> NexResult<Vec<u8>> { Ok(Vec::new()) }
+	pub fn udp(address: SocketAddr, socket: Arc<UdpSocket>) -> Self { Self::datagram(address, socket) }
+
+	pub fn datagram(address: SocketAddr, transport: Arc<dyn Transport>) -> Self {
+		Self { address, transport: SocketTransport::Datagram(transport) }
+	}
+
+	#[cfg(feature = "websocket")]
+	pub fn websocket(address: SocketAddr, sender: UnboundedSender<Vec<u8>>) -> Self {
+		Self { address, transport: SocketTransport::WebSocket(sender) }
+	}
+
+	pub fn address(&self) -> SocketAddr { self.address }
+
+	pub fn transport(&self) -> &SocketTransport { &self.transport }
+
+	pub fn is_websocket(&self) -> bool {
+		match self.transport {
+			SocketTransport::Datagram(_) => false,
+			#[cfg(feature = "websocket")]
+			SocketTransport::WebSocket(_) => true,
+		}
+	}
+
+	pub async fn send(&self, buf: &[u8]) -> NexResult<()> {
+		match &self.transport {
+			SocketTransport::Datagram(transport) => transport.send_to(buf, self.address).await?,
+			#[cfg(feature = "websocket")]
+			SocketTransport::WebSocket(sender) => {
+				// The writer task has exited, meaning the socket is already closed
+				sender.send(buf.to_vec()).map_err(|_| std::io::Error::from(std::io::ErrorKind::NotConnected))?;
+			}
+		}
+		Ok(())
+	}
 }
 
+// Datagrams collected to be sent at once, such as everything a timer tick
+// produces. Datagrams for the same transport are written with one send_batch call
+#[derive(Debug, Default)]
+pub(crate) struct SendBatch {
+	datagrams: Vec<(SocketConnection, Vec<u8>)>,
+}
+
+impl SendBatch {
+	pub(crate) fn push(&mut self, socket: &SocketConnection, data: Vec<u8>) { self.datagrams.push((socket.clone(), data)); }
 
+	// Like retransmissions, sends which fail are left for the next attempt
+	pub(crate) async fn flush(self) {
+		let mut batches: Vec<(Arc<dyn Transport>, Vec<Datagram>)> = Vec::new();
+		for (socket, data) in self.datagrams {
+			match &socket.transport {
+				SocketTransport::Datagram(transport) => match batches.iter_mut().find(|(batch_transport, _)| Arc::ptr_eq(batch_transport, transport)) {
+					Some((_, datagrams)) => datagrams.push((data, socket.address)),
+					None => batches.push((transport.clone(), vec![(data, socket.address)])),
+				},
+				#[cfg(feature = "websocket")]
+				SocketTransport::WebSocket(_) => { let _ = socket.send(&data).await; }
+			}
+		}
+		for (transport, datagrams) in batches { let _ = transport.send_batch(&datagrams).await; }
+	}
+}
@@ -1,9 +1,68 @@
+use crate::timeout::Timeout;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// A reliable packet waiting for an acknowledgement. The encoded bytes are kept
+// so retransmissions are byte-for-byte identical to the original send
+#[derive(Debug, Clone)]
+pub struct PendingPacket {
+	pub sequence_id: u16,
+	pub data: Vec<u8>,
+	pub send_count: u32,
+	pub sent_at: Instant,
+	pub timeout: Timeout,
+}
+
+impl PendingPacket {
+	pub fn expires_at(&self) -> Instant { self.sent_at + Duration::from_millis(self.timeout.0) }
+}
+
 #[derive(Debug, Default)]
-pub struct TimeoutManager;
+pub struct TimeoutTick {
+	pub resend: Vec<Vec<u8>>,
+	pub expired: bool,
+}
+
+// Implementation of rdv::TimeoutManager. Tracks the reliable packets of one
+// substream and decides which ones are due for retransmission on each tick
+#[derive(Debug, Default)]
+pub struct TimeoutManager {
+	packets: HashMap<u16, PendingPacket>,
+}
 
 impl TimeoutManager {
 	pub fn new() -> Self { Self::default() }
-	pub fn tick(&mut self) {}
-}
 
+	pub fn schedule_packet_timeout(&mut self, packet: PendingPacket) {
+		self.packets.insert(packet.sequence_id, packet);
+	}
+
+	pub fn acknowledge_packet(&mut self, sequence_id: u16) -> Option<PendingPacket> {
+		self.packets.remove(&sequence_id)
+	}
+
+	pub fn pending_sequence_ids(&self) -> Vec<u16> { self.packets.keys().copied().collect() }
 
+	pub fn is_empty(&self) -> bool { self.packets.is_empty() }
+
+	// Resends every expired packet. Once a packet has been sent max_retransmissions
+	// times without being acknowledged the connection is considered dead
+	pub fn tick(&mut self, now: Instant, max_retransmissions: u32, compute_rto: impl Fn(u32) -> Duration) -> TimeoutTick {
+		let mut tick = TimeoutTick::default();
+		for packet in self.packets.values_mut() {
+			if now < packet.expires_at() { continue; }
+			// This is `<` instead of `<=` for accuracy with observed behavior
+			if packet.send_count >= max_retransmissions {
+				tick.expired = true;
+				continue;
+			}
+			packet.send_count += 1;
+			packet.sent_at = now;
+			packet.timeout = Timeout(compute_rto(packet.send_count).as_millis() as u64);
+			tick.resend.push(packet.data.clone());
+		}
+		tick
+	}
+
+	pub fn stop(&mut self) { self.packets.clear(); }
+}
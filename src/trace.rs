@@ -0,0 +1,67 @@
+// Optional tracing instrumentation. Without the tracing feature spans are zero
+// sized and events expand to nothing, so none of this is compiled in
+use crate::rmc_message::RmcMessage;
+use crate::types::pid::Pid;
+use std::future::Future;
+use std::net::SocketAddr;
+
+// Emits a tracing event at the given level, such as event!(debug, "...")
+#[cfg(feature = "tracing")]
+macro_rules! event {
+	($level:ident, $($arg:tt)+) => { tracing::$level!($($arg)+) };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! event {
+	($level:ident, $($arg:tt)+) => {{}};
+}
+
+pub(crate) use event;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Span {
+	#[cfg(feature = "tracing")]
+	inner: tracing::Span,
+}
+
+impl Span {
+	// The PID is recorded once the client has authenticated
+	#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+	pub(crate) fn connection(address: SocketAddr, id: u32) -> Self {
+		Self {
+			#[cfg(feature = "tracing")]
+			inner: tracing::info_span!("prudp_connection", %address, connection_id = id, pid = tracing::field::Empty),
+		}
+	}
+
+	// Created inside the span of the connection the message arrived on
+	#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+	pub(crate) fn rmc(message: &RmcMessage) -> Self {
+		Self {
+			#[cfg(feature = "tracing")]
+			inner: tracing::info_span!("rmc", protocol_id = message.protocol_id, method_id = message.method_id, call_id = message.call_id),
+		}
+	}
+
+	#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+	pub(crate) fn record_pid(&self, pid: Pid) {
+		#[cfg(feature = "tracing")]
+		self.inner.record("pid", pid.0);
+	}
+
+	pub(crate) fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+		#[cfg(feature = "tracing")]
+		return self.inner.in_scope(f);
+		#[cfg(not(feature = "tracing"))]
+		f()
+	}
+
+	// Guards from Span::enter cannot be held across an await, so futures carry
+	// the span with them instead
+	pub(crate) fn instrument<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+		#[cfg(feature = "tracing")]
+		return tracing::Instrument::instrument(future, self.inner.clone());
+		#[cfg(not(feature = "tracing"))]
+		future
+	}
+}
@@ -0,0 +1,123 @@
+use crate::transport::{Datagram, Transport, TransportFuture};
+use crate::error::NexError;
+use crate::NexResult;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+// How many sends can be queued for the writer before senders wait for it
+const QUEUE_SIZE: usize = 1024;
+
+// Queues outgoing datagrams for a writer task, which sends everything queued at
+// the same time with one send_batch call. ACKs for a batch of received packets
+// and the retransmissions of a timer tick are then written together. Errors
+// from the inner transport happen after the send has returned, so the first one
+// is kept and returned by the next send instead
+#[derive(Debug)]
+pub struct BatchingTransport {
+	inner: Arc<dyn Transport>,
+	queue: mpsc::Sender<Vec<Datagram>>,
+	failure: Arc<Mutex<Option<NexError>>>,
+}
+
+impl BatchingTransport {
+	// Must be called inside a tokio runtime. The writer stops once the batching
+	// transport is dropped, after sending whatever is still queued
+	pub fn new(inner: Arc<dyn Transport>, batch_size: usize) -> Self {
+		let batch_size = batch_size.max(1);
+		let (queue, mut queued) = mpsc::channel::<Vec<Datagram>>(QUEUE_SIZE);
+		let failure = Arc::new(Mutex::new(None));
+		let writer = inner.clone();
+		let writer_failure = failure.clone();
+		tokio::spawn(async move {
+			while let Some(mut batch) = queued.recv().await {
+				while batch.len() < batch_size {
+					let Ok(more) = queued.try_recv() else { break };
+					batch.extend(more);
+				}
+				for chunk in batch.chunks(batch_size) {
+					if let Err(err) = writer.send_batch(chunk).await { writer_failure.lock().unwrap().get_or_insert(err); }
+				}
+			}
+		});
+		Self { inner, queue, failure }
+	}
+
+	// Waits while the queue is full, so a writer which cannot keep up slows the
+	// senders down instead of using unbounded memory
+	async fn enqueue(&self, datagrams: Vec<Datagram>) -> NexResult<()> {
+		self.queue.send(datagrams).await.map_err(|_| std::io::Error::from(std::io::ErrorKind::NotConnected))?;
+		match self.failure.lock().unwrap().take() {
+			Some(err) => Err(err),
+			None => Ok(()),
+		}
+	}
+}
+
+impl Transport for BatchingTransport {
+	fn send_to<'a>(&'a self, data: &'a [u8], address: SocketAddr) -> TransportFuture<'a, NexResult<()>> {
+		Box::pin(self.enqueue(vec![(data.to_vec(), address)]))
+	}
+
+	fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, NexResult<(usize, SocketAddr)>> { self.inner.recv_from(buf) }
+
+	fn local_addr(&self) -> NexResult<SocketAddr> { self.inner.local_addr() }
+
+	fn recv_batch<'a>(&'a self, bufs: &'a mut [Vec<u8>], received: &'a mut Vec<(usize, SocketAddr)>) -> TransportFuture<'a, NexResult<()>> { self.inner.recv_batch(bufs, received) }
+
+	// The datagrams are kept together, so they are written in one batch
+	fn send_batch<'a>(&'a self, datagrams: &'a [Datagram]) -> TransportFuture<'a, NexResult<()>> {
+		Box::pin(self.enqueue(datagrams.to_vec()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Records every datagram sent, except to the unreachable address
+	#[derive(Debug, Default)]
+	struct Recording {
+		sent: Mutex<Vec<SocketAddr>>,
+	}
+
+	const UNREACHABLE: SocketAddr = SocketAddr::V4(std::net::SocketAddrV4::new(std::net::Ipv4Addr::new(10, 0, 0, 9), 1));
+
+	impl Transport for Recording {
+		fn send_to<'a>(&'a self, _data: &'a [u8], address: SocketAddr) -> TransportFuture<'a, NexResult<()>> {
+			Box::pin(async move {
+				if address == UNREACHABLE { return Err(std::io::Error::from(std::io::ErrorKind::HostUnreachable).into()); }
+				self.sent.lock().unwrap().push(address);
+				Ok(())
+			})
+		}
+
+		fn recv_from<'a>(&'a self, _buf: &'a mut [u8]) -> TransportFuture<'a, NexResult<(usize, SocketAddr)>> { Box::pin(std::future::pending()) }
+
+		fn local_addr(&self) -> NexResult<SocketAddr> { Ok("127.0.0.1:1".parse().unwrap()) }
+	}
+
+	fn datagrams() -> Vec<Datagram> {
+		["10.0.0.1:1".parse().unwrap(), UNREACHABLE, "10.0.0.2:1".parse().unwrap()].into_iter().map(|address| (vec![0], address)).collect()
+	}
+
+	#[tokio::test]
+	async fn failing_datagram_does_not_stop_the_batch() {
+		let transport = Recording::default();
+		assert!(transport.send_batch(&datagrams()).await.is_err());
+		assert_eq!(transport.sent.lock().unwrap().len(), 2);
+	}
+
+	#[tokio::test]
+	async fn writer_failure_is_reported_by_the_next_send() {
+		let inner = Arc::new(Recording::default());
+		let transport = BatchingTransport::new(inner.clone(), 8);
+		transport.send_batch(&datagrams()).await.unwrap();
+		while inner.sent.lock().unwrap().len() < 2 { tokio::task::yield_now().await; }
+		tokio::task::yield_now().await;
+
+		assert!(transport.send_to(&[0], "10.0.0.1:1".parse().unwrap()).await.is_err());
+		// The failure is only reported once
+		assert!(transport.send_to(&[0], "10.0.0.1:1".parse().unwrap()).await.is_ok());
+	}
+}
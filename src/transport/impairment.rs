@@ -0,0 +1,286 @@
+use crate::clock::{Clock, SystemClock};
+use crate::transport::{Datagram, Transport, TransportFuture};
+use crate::NexResult;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+// How badly an impaired transport treats outgoing datagrams. Rates are
+// probabilities between 0 and 1, with anything outside clamped and NaN taken as
+// 0. They are drawn from a generator seeded with seed so the same sequence of
+// sends is impaired the same way every run
+#[derive(Debug, Clone, Default)]
+pub struct ImpairmentSettings {
+	pub seed: u64,
+	pub drop_rate: f64,
+	pub duplicate_rate: f64,
+	// Every datagram is delayed by a random time in this range
+	pub min_delay: Duration,
+	pub max_delay: Duration,
+	// Reordered datagrams are held back by reorder_delay on top of their normal
+	// delay, so the ones sent after them overtake them
+	pub reorder_rate: f64,
+	pub reorder_delay: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImpairmentStats {
+	pub sent: u64,
+	pub dropped: u64,
+	pub duplicated: u64,
+	pub reordered: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+	sent: AtomicU64,
+	dropped: AtomicU64,
+	duplicated: AtomicU64,
+	reordered: AtomicU64,
+}
+
+// Delayed datagrams, sent in the order of their deadline and then of their
+// send. The sequence number keeps datagrams with the same deadline in order
+#[derive(Debug)]
+struct Delayed {
+	inner: Arc<dyn Transport>,
+	clock: Arc<dyn Clock>,
+	pending: Mutex<BinaryHeap<Reverse<(Instant, u64, Datagram)>>>,
+	sequence: AtomicU64,
+	added: Notify,
+}
+
+impl Delayed {
+	fn push(&self, deadline: Instant, datagram: Datagram) {
+		let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+		self.pending.lock().unwrap().push(Reverse((deadline, sequence, datagram)));
+		self.added.notify_one();
+	}
+
+	// Sends every datagram whose deadline the clock has reached, then sleeps on
+	// the clock until the next one is due or an earlier one is added
+	async fn deliver(&self) {
+		loop {
+			let added = self.added.notified();
+			tokio::pin!(added);
+			added.as_mut().enable();
+			let next = self.pending.lock().unwrap().peek().map(|Reverse((deadline, _, _))| *deadline);
+			match next {
+				None => added.await,
+				Some(deadline) if deadline <= self.clock.now() => {
+					let Some(Reverse((_, _, (data, address)))) = self.pending.lock().unwrap().pop() else { continue };
+					let _ = self.inner.send_to(&data, address).await;
+				}
+				Some(deadline) => {
+					tokio::select! {
+						_ = self.clock.sleep_until(deadline) => {}
+						_ = added => {}
+					}
+				}
+			}
+		}
+	}
+}
+
+// Wraps another transport and drops, duplicates, delays and reorders the
+// datagrams sent through it. Only outgoing datagrams are impaired, so both ends
+// of a link must be wrapped to impair both directions. Delays follow the clock,
+// so a ManualClock releases delayed datagrams when it is advanced
+#[derive(Debug)]
+pub struct ImpairedTransport {
+	settings: ImpairmentSettings,
+	rng: Mutex<StdRng>,
+	counters: Counters,
+	delayed: Arc<Delayed>,
+	// Started by the first delayed datagram, and stopped when the transport is
+	// dropped. Datagrams still delayed then are lost
+	delivery: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl ImpairedTransport {
+	pub fn new(inner: Arc<dyn Transport>, settings: ImpairmentSettings) -> Self { Self::with_clock(inner, settings, Arc::new(SystemClock)) }
+
+	pub fn with_clock(inner: Arc<dyn Transport>, mut settings: ImpairmentSettings, clock: Arc<dyn Clock>) -> Self {
+		for rate in [&mut settings.drop_rate, &mut settings.duplicate_rate, &mut settings.reorder_rate] { *rate = probability(*rate); }
+		let rng = Mutex::new(StdRng::seed_from_u64(settings.seed));
+		let delayed = Arc::new(Delayed { inner, clock, pending: Mutex::new(BinaryHeap::new()), sequence: AtomicU64::new(0), added: Notify::new() });
+		Self { settings, rng, counters: Counters::default(), delayed, delivery: Mutex::new(None) }
+	}
+
+	pub fn stats(&self) -> ImpairmentStats {
+		ImpairmentStats {
+			sent: self.counters.sent.load(Ordering::Relaxed),
+			dropped: self.counters.dropped.load(Ordering::Relaxed),
+			duplicated: self.counters.duplicated.load(Ordering::Relaxed),
+			reordered: self.counters.reordered.load(Ordering::Relaxed),
+		}
+	}
+
+	// Decides the fate of one datagram, returning the delay of every copy to send.
+	// All random draws happen here, in send order, to keep runs reproducible
+	fn plan(&self) -> Vec<Duration> {
+		let settings = &self.settings;
+		let mut rng = self.rng.lock().unwrap();
+		self.counters.sent.fetch_add(1, Ordering::Relaxed);
+		if rng.gen_bool(settings.drop_rate) {
+			self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+			return Vec::new();
+		}
+
+		let copies = if rng.gen_bool(settings.duplicate_rate) {
+			self.counters.duplicated.fetch_add(1, Ordering::Relaxed);
+			2
+		} else {
+			1
+		};
+
+		(0..copies).map(|_| {
+			let mut delay = if settings.max_delay > settings.min_delay { rng.gen_range(settings.min_delay..=settings.max_delay) } else { settings.min_delay };
+			if rng.gen_bool(settings.reorder_rate) {
+				self.counters.reordered.fetch_add(1, Ordering::Relaxed);
+				delay += settings.reorder_delay;
+			}
+			delay
+		}).collect()
+	}
+}
+
+// gen_bool panics on anything which is not between 0 and 1, including NaN
+fn probability(rate: f64) -> f64 {
+	if rate.is_nan() { 0.0 } else { rate.clamp(0.0, 1.0) }
+}
+
+impl Transport for ImpairedTransport {
+	fn send_to<'a>(&'a self, data: &'a [u8], address: SocketAddr) -> TransportFuture<'a, NexResult<()>> {
+		Box::pin(async move {
+			for delay in self.plan() {
+				if delay.is_zero() {
+					self.delayed.inner.send_to(data, address).await?;
+					continue;
+				}
+				self.delivery.lock().unwrap().get_or_insert_with(|| {
+					let delayed = self.delayed.clone();
+					tokio::spawn(async move { delayed.deliver().await })
+				});
+				self.delayed.push(self.delayed.clock.now() + delay, (data.to_vec(), address));
+			}
+			Ok(())
+		})
+	}
+
+	fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, NexResult<(usize, SocketAddr)>> { self.delayed.inner.recv_from(buf) }
+
+	fn recv_batch<'a>(&'a self, bufs: &'a mut [Vec<u8>], received: &'a mut Vec<(usize, SocketAddr)>) -> TransportFuture<'a, NexResult<()>> { self.delayed.inner.recv_batch(bufs, received) }
+
+	fn local_addr(&self) -> NexResult<SocketAddr> { self.delayed.inner.local_addr() }
+}
+
+impl Drop for ImpairedTransport {
+	fn drop(&mut self) {
+		if let Some(delivery) = self.delivery.lock().unwrap().take() { delivery.abort(); }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::clock::ManualClock;
+
+	// Records the first byte of every datagram sent
+	#[derive(Debug, Default)]
+	struct Recording {
+		sent: Mutex<Vec<u8>>,
+	}
+
+	impl Transport for Recording {
+		fn send_to<'a>(&'a self, data: &'a [u8], _address: SocketAddr) -> TransportFuture<'a, NexResult<()>> {
+			Box::pin(async move {
+				self.sent.lock().unwrap().push(data[0]);
+				Ok(())
+			})
+		}
+
+		fn recv_from<'a>(&'a self, _buf: &'a mut [u8]) -> TransportFuture<'a, NexResult<(usize, SocketAddr)>> { Box::pin(std::future::pending()) }
+
+		fn local_addr(&self) -> NexResult<SocketAddr> { Ok("127.0.0.1:1".parse().unwrap()) }
+	}
+
+	const ADDRESS: SocketAddr = SocketAddr::V4(std::net::SocketAddrV4::new(std::net::Ipv4Addr::new(10, 0, 0, 1), 1));
+
+	fn settings(seed: u64) -> ImpairmentSettings {
+		ImpairmentSettings {
+			seed,
+			drop_rate: 0.2,
+			duplicate_rate: 0.2,
+			min_delay: Duration::from_millis(10),
+			max_delay: Duration::from_millis(50),
+			reorder_rate: 0.2,
+			reorder_delay: Duration::from_millis(100),
+		}
+	}
+
+	// Sends 100 datagrams numbered in send order, and returns the order they
+	// arrived in once the clock has passed every delay
+	async fn run(settings: ImpairmentSettings) -> (Vec<u8>, ImpairmentStats) {
+		let inner = Arc::new(Recording::default());
+		let clock = Arc::new(ManualClock::new());
+		let transport = ImpairedTransport::with_clock(inner.clone(), settings, clock.clone());
+		for i in 0..100 { transport.send_to(&[i], ADDRESS).await.unwrap(); }
+		tokio::task::yield_now().await;
+		assert!(inner.sent.lock().unwrap().is_empty());
+
+		clock.advance(Duration::from_secs(1));
+		let stats = transport.stats();
+		let expected = (stats.sent - stats.dropped + stats.duplicated) as usize;
+		while inner.sent.lock().unwrap().len() < expected { tokio::task::yield_now().await; }
+		let sent = inner.sent.lock().unwrap().clone();
+		(sent, stats)
+	}
+
+	#[tokio::test]
+	async fn same_seed_impairs_the_same_way() {
+		let (sent, stats) = run(settings(1)).await;
+		assert!(stats.dropped > 0 && stats.duplicated > 0 && stats.reordered > 0, "{stats:?}");
+		let mut sorted = sent.clone();
+		sorted.sort();
+		assert_ne!(sent, sorted);
+		assert_eq!(run(settings(1)).await, (sent.clone(), stats));
+		assert_ne!(run(settings(2)).await.0, sent);
+	}
+
+	#[tokio::test]
+	async fn delayed_datagrams_wait_for_the_clock() {
+		let inner = Arc::new(Recording::default());
+		let clock = Arc::new(ManualClock::new());
+		let settings = ImpairmentSettings { min_delay: Duration::from_millis(10), max_delay: Duration::from_millis(10), ..Default::default() };
+		let transport = ImpairedTransport::with_clock(inner.clone(), settings, clock.clone());
+		transport.send_to(&[1], ADDRESS).await.unwrap();
+		clock.advance(Duration::from_millis(5));
+		transport.send_to(&[2], ADDRESS).await.unwrap();
+		clock.advance(Duration::from_millis(5));
+		while inner.sent.lock().unwrap().is_empty() { tokio::task::yield_now().await; }
+		for _ in 0..10 { tokio::task::yield_now().await; }
+		assert_eq!(*inner.sent.lock().unwrap(), [1]);
+
+		clock.advance(Duration::from_millis(5));
+		while inner.sent.lock().unwrap().len() < 2 { tokio::task::yield_now().await; }
+		assert_eq!(*inner.sent.lock().unwrap(), [1, 2]);
+	}
+
+	#[tokio::test]
+	async fn invalid_rates_are_clamped() {
+		let inner = Arc::new(Recording::default());
+		let settings = ImpairmentSettings { drop_rate: f64::NAN, duplicate_rate: 2.0, reorder_rate: -1.0, ..Default::default() };
+		let transport = ImpairedTransport::new(inner.clone(), settings);
+		for i in 0..10 { transport.send_to(&[i], ADDRESS).await.unwrap(); }
+		assert_eq!(transport.stats(), ImpairmentStats { sent: 10, dropped: 0, duplicated: 10, reordered: 0 });
+		assert_eq!(inner.sent.lock().unwrap().len(), 20);
+	}
+}
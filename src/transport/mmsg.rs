@@ -0,0 +1,95 @@
+// recvmmsg and sendmmsg for UDP sockets, so that a whole batch of datagrams
+// costs one syscall. Both are non-blocking and are retried by tokio when the
+// socket is not ready
+use crate::transport::Datagram;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::AsRawFd;
+use tokio::net::UdpSocket;
+
+pub(crate) fn recv(socket: &UdpSocket, bufs: &mut [Vec<u8>], received: &mut Vec<(usize, SocketAddr)>) -> io::Result<()> {
+	// SAFETY: sockaddr_storage and mmsghdr are plain C structs, for which all zero is valid
+	let mut addresses: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; bufs.len()];
+	let mut iovecs: Vec<libc::iovec> = bufs.iter_mut().map(|buf| libc::iovec { iov_base: buf.as_mut_ptr().cast(), iov_len: buf.len() }).collect();
+	let mut headers: Vec<libc::mmsghdr> = iovecs.iter_mut().zip(&mut addresses).map(|(iovec, address)| {
+		let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+		header.msg_hdr.msg_name = (address as *mut libc::sockaddr_storage).cast();
+		header.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+		header.msg_hdr.msg_iov = iovec;
+		header.msg_hdr.msg_iovlen = 1;
+		header
+	}).collect();
+
+	// SAFETY: every header points at a buffer and address which outlive the call
+	let count = unsafe { libc::recvmmsg(socket.as_raw_fd(), headers.as_mut_ptr(), headers.len() as libc::c_uint, libc::MSG_DONTWAIT, std::ptr::null_mut()) };
+	if count < 0 { return Err(io::Error::last_os_error()); }
+	for (header, address) in headers.iter().zip(&addresses).take(count as usize) {
+		// Like recv_from, datagrams larger than their buffer are truncated
+		received.push((header.msg_len as usize, to_socket_addr(address)?));
+	}
+	Ok(())
+}
+
+// Returns how many datagrams were sent, which may be fewer than given
+pub(crate) fn send(socket: &UdpSocket, datagrams: &[Datagram]) -> io::Result<usize> {
+	let mut addresses: Vec<(libc::sockaddr_storage, libc::socklen_t)> = datagrams.iter().map(|(_, address)| from_socket_addr(address)).collect();
+	let mut iovecs: Vec<libc::iovec> = datagrams.iter().map(|(data, _)| libc::iovec { iov_base: data.as_ptr() as *mut libc::c_void, iov_len: data.len() }).collect();
+	let mut headers: Vec<libc::mmsghdr> = iovecs.iter_mut().zip(&mut addresses).map(|(iovec, (address, length))| {
+		// SAFETY: mmsghdr is a plain C struct, for which all zero is valid
+		let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+		header.msg_hdr.msg_name = (address as *mut libc::sockaddr_storage).cast();
+		header.msg_hdr.msg_namelen = *length;
+		header.msg_hdr.msg_iov = iovec;
+		header.msg_hdr.msg_iovlen = 1;
+		header
+	}).collect();
+
+	// SAFETY: every header points at data and an address which outlive the call.
+	// The data is only read, despite iovec taking a mutable pointer
+	let count = unsafe { libc::sendmmsg(socket.as_raw_fd(), headers.as_mut_ptr(), headers.len() as libc::c_uint, libc::MSG_DONTWAIT) };
+	if count < 0 { return Err(io::Error::last_os_error()); }
+	Ok(count as usize)
+}
+
+fn to_socket_addr(address: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+	match address.ss_family as libc::c_int {
+		libc::AF_INET => {
+			// SAFETY: the family says the storage holds a sockaddr_in
+			let address = unsafe { &*(address as *const libc::sockaddr_storage).cast::<libc::sockaddr_in>() };
+			Ok(SocketAddrV4::new(Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)), u16::from_be(address.sin_port)).into())
+		}
+		libc::AF_INET6 => {
+			// SAFETY: the family says the storage holds a sockaddr_in6
+			let address = unsafe { &*(address as *const libc::sockaddr_storage).cast::<libc::sockaddr_in6>() };
+			Ok(SocketAddrV6::new(Ipv6Addr::from(address.sin6_addr.s6_addr), u16::from_be(address.sin6_port), address.sin6_flowinfo, address.sin6_scope_id).into())
+		}
+		family => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported address family {family}"))),
+	}
+}
+
+fn from_socket_addr(address: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+	// SAFETY: sockaddr_storage is a plain C struct, for which all zero is valid
+	let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+	let length = match address {
+		SocketAddr::V4(address) => {
+			// SAFETY: sockaddr_storage is large and aligned enough for any address
+			let target = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in>() };
+			target.sin_family = libc::AF_INET as libc::sa_family_t;
+			target.sin_port = address.port().to_be();
+			target.sin_addr.s_addr = u32::from(*address.ip()).to_be();
+			mem::size_of::<libc::sockaddr_in>()
+		}
+		SocketAddr::V6(address) => {
+			// SAFETY: sockaddr_storage is large and aligned enough for any address
+			let target = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in6>() };
+			target.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+			target.sin6_port = address.port().to_be();
+			target.sin6_addr.s6_addr = address.ip().octets();
+			target.sin6_flowinfo = address.flowinfo();
+			target.sin6_scope_id = address.scope_id();
+			mem::size_of::<libc::sockaddr_in6>()
+		}
+	};
+	(storage, length as libc::socklen_t)
+}
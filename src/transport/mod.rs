@@ -0,0 +1,100 @@
+use crate::NexResult;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+#[cfg(target_os = "linux")]
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+pub mod batching;
+pub mod channel;
+pub mod impairment;
+#[cfg(target_os = "linux")]
+mod mmsg;
+
+pub type TransportFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// A datagram and the address it is sent to or was received from
+pub type Datagram = (Vec<u8>, SocketAddr);
+
+// A datagram transport PRUDP packets are sent over. UDP sockets are the real
+// one, other implementations exist so servers and clients can be run without
+// touching the network
+pub trait Transport: fmt::Debug + Send + Sync {
+	fn send_to<'a>(&'a self, data: &'a [u8], address: SocketAddr) -> TransportFuture<'a, NexResult<()>>;
+
+	// Reads one datagram into buf, returning its size and sender. Datagrams larger
+	// than buf are truncated, the same as with UDP
+	fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, NexResult<(usize, SocketAddr)>>;
+
+	fn local_addr(&self) -> NexResult<SocketAddr>;
+
+	// Waits for at least one datagram, then reads as many as are ready into bufs.
+	// The size and sender of each are pushed to received, in the order of bufs.
+	// Transports without batched IO read a single datagram
+	fn recv_batch<'a>(&'a self, bufs: &'a mut [Vec<u8>], received: &'a mut Vec<(usize, SocketAddr)>) -> TransportFuture<'a, NexResult<()>> {
+		Box::pin(async move {
+			received.push(self.recv_from(&mut bufs[0]).await?);
+			Ok(())
+		})
+	}
+
+	// Sends several datagrams. One which fails, such as for an unreachable
+	// address, is skipped so the rest are still sent, and the first error is
+	// returned at the end. Transports without batched IO send them one at a time
+	fn send_batch<'a>(&'a self, datagrams: &'a [Datagram]) -> TransportFuture<'a, NexResult<()>> {
+		Box::pin(async move {
+			let mut first_error = None;
+			for (data, address) in datagrams {
+				if let Err(err) = self.send_to(data, *address).await { first_error.get_or_insert(err); }
+			}
+			first_error.map_or(Ok(()), Err)
+		})
+	}
+}
+
+impl Transport for UdpSocket {
+	fn send_to<'a>(&'a self, data: &'a [u8], address: SocketAddr) -> TransportFuture<'a, NexResult<()>> {
+		Box::pin(async move {
+			UdpSocket::send_to(self, data, address).await?;
+			Ok(())
+		})
+	}
+
+	fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, NexResult<(usize, SocketAddr)>> {
+		Box::pin(async move { Ok(UdpSocket::recv_from(self, buf).await?) })
+	}
+
+	fn local_addr(&self) -> NexResult<SocketAddr> { Ok(UdpSocket::local_addr(self)?) }
+
+	// One recvmmsg call reads every datagram which is ready, up to the number of buffers
+	#[cfg(target_os = "linux")]
+	fn recv_batch<'a>(&'a self, bufs: &'a mut [Vec<u8>], received: &'a mut Vec<(usize, SocketAddr)>) -> TransportFuture<'a, NexResult<()>> {
+		Box::pin(async move {
+			self.async_io(Interest::READABLE, || mmsg::recv(self, bufs, received)).await?;
+			Ok(())
+		})
+	}
+
+	// sendmmsg may stop part way through when the socket buffer fills up, in
+	// which case the rest are sent once it is writable again. It only fails when
+	// the first datagram left cannot be sent, so that one is skipped
+	#[cfg(target_os = "linux")]
+	fn send_batch<'a>(&'a self, datagrams: &'a [Datagram]) -> TransportFuture<'a, NexResult<()>> {
+		Box::pin(async move {
+			let mut sent = 0;
+			let mut first_error = None;
+			while sent < datagrams.len() {
+				match self.async_io(Interest::WRITABLE, || mmsg::send(self, &datagrams[sent..])).await {
+					Ok(count) => sent += count,
+					Err(err) => {
+						sent += 1;
+						first_error.get_or_insert(err);
+					}
+				}
+			}
+			first_error.map_or(Ok(()), |err| Err(err.into()))
+		})
+	}
+}
@@ -0,0 +1,18 @@
+use crate::io::{ByteStreamIn, ByteStreamOut};
+use crate::NexResult;
+
+// Implementation of the Buffer type. A byte array with a u32 length prefix
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Buffer(pub Vec<u8>);
+
+impl Buffer {
+	pub fn write_to(&self, out: &mut ByteStreamOut) {
+		out.write_u32_le(self.0.len() as u32);
+		out.write(&self.0);
+	}
+
+	pub fn read_from(input: &mut ByteStreamIn) -> NexResult<Self> {
+		let length = input.read_u32_le()?;
+		Ok(Buffer(input.read(length as u64)?))
+	}
+}
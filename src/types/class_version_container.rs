@@ -0,0 +1,30 @@
+use crate::io::{ByteStreamIn, ByteStreamOut};
+use crate::NexResult;
+use std::collections::BTreeMap;
+
+// Implementation of the ClassVersionContainer type. Sent with verbose RMC
+// requests, holding the version of every structure the request uses by class name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClassVersionContainer {
+	pub class_versions: BTreeMap<String, u16>,
+}
+
+impl ClassVersionContainer {
+	pub fn write_to(&self, out: &mut ByteStreamOut) {
+		out.write_u32_le(self.class_versions.len() as u32);
+		for (class_name, version) in &self.class_versions {
+			out.write_string(class_name);
+			out.write_u16_le(*version);
+		}
+	}
+
+	pub fn read_from(input: &mut ByteStreamIn) -> NexResult<Self> {
+		let count = input.read_u32_le()?;
+		let mut class_versions = BTreeMap::new();
+		for _ in 0..count {
+			let class_name = input.read_string()?;
+			class_versions.insert(class_name, input.read_u16_le()?);
+		}
+		Ok(Self { class_versions })
+	}
+}
@@ -0,0 +1,71 @@
+use crate::io::{ByteStreamIn, ByteStreamOut};
+use crate::NexResult;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Implementation of rdv::DateTime. A bit field holding a UTC date and time,
+// from the second in the lowest 6 bits up to the year in the highest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime(pub u64);
+
+impl DateTime {
+	pub fn make(year: u64, month: u64, day: u64, hour: u64, minute: u64, second: u64) -> Self {
+		DateTime(second | (minute << 6) | (hour << 12) | (day << 17) | (month << 22) | (year << 26))
+	}
+
+	pub fn from_timestamp(timestamp: SystemTime) -> Self {
+		let seconds = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+		let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+		let time = seconds % 86400;
+		Self::make(year as u64, month as u64, day as u64, time / 3600, time / 60 % 60, time % 60)
+	}
+
+	pub fn now() -> Self { Self::from_timestamp(SystemTime::now()) }
+
+	pub fn second(&self) -> u64 { self.0 & 63 }
+
+	pub fn minute(&self) -> u64 { (self.0 >> 6) & 63 }
+
+	pub fn hour(&self) -> u64 { (self.0 >> 12) & 31 }
+
+	pub fn day(&self) -> u64 { (self.0 >> 17) & 31 }
+
+	pub fn month(&self) -> u64 { (self.0 >> 22) & 15 }
+
+	pub fn year(&self) -> u64 { self.0 >> 26 }
+
+	// Converts the DateTime back into a timestamp. Dates before 1970 become the epoch
+	pub fn standard(&self) -> SystemTime {
+		let days = days_from_civil(self.year() as i64, self.month(), self.day());
+		let seconds = days * 86400 + (self.hour() * 3600 + self.minute() * 60 + self.second()) as i64;
+		UNIX_EPOCH + Duration::from_secs(seconds.max(0) as u64)
+	}
+
+	pub fn write_to(&self, out: &mut ByteStreamOut) { out.write_u64_le(self.0); }
+
+	pub fn read_from(input: &mut ByteStreamIn) -> NexResult<Self> { Ok(DateTime(input.read_u64_le()?)) }
+}
+
+// Conversions between days since the Unix epoch and proleptic Gregorian dates,
+// see https://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+	let z = days + 719468;
+	let era = z.div_euclid(146097);
+	let doe = z.rem_euclid(146097) as u64;
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+	let year = yoe as i64 + era * 400 + if month <= 2 { 1 } else { 0 };
+	(year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u64, day: u64) -> i64 {
+	let year = if month <= 2 { year - 1 } else { year };
+	let era = year.div_euclid(400);
+	let yoe = year.rem_euclid(400) as u64;
+	let mp = if month > 2 { month - 3 } else { month + 9 };
+	let doy = (153 * mp + 2) / 5 + day.saturating_sub(1);
+	let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+	era * 146097 + doe as i64 - 719468
+}
@@ -8,5 +8,11 @@ pub trait Writable {
 	fn write_to<W: std::io::Write>(&self, writer: &mut W) -> NexResult<()>;
 }
 
+pub mod buffer;
+pub mod class_version_container;
+pub mod datetime;
 pub mod pid;
+pub mod qresult;
+pub mod rv_connection_data;
+pub mod rv_type;
 pub mod station_url;
\ No newline at end of file
@@ -1,7 +1,7 @@
 use crate::NexResult;
 use crate::io::{ByteStreamIn, ByteStreamOut};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
 pub struct Pid(pub u64);
 
 impl Pid {
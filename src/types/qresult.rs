@@ -0,0 +1,22 @@
+use crate::io::{ByteStreamIn, ByteStreamOut};
+use crate::result_codes::ERROR_MASK;
+use crate::NexResult;
+
+// Implementation of the qresult type. A result code returned as an RMC response
+// value, which is an error when the highest bit is set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct QResult(pub u32);
+
+impl QResult {
+	pub fn success(code: u32) -> Self { QResult(code & !ERROR_MASK) }
+
+	pub fn error(code: u32) -> Self { QResult(code | ERROR_MASK) }
+
+	pub fn is_success(&self) -> bool { self.0 & ERROR_MASK == 0 }
+
+	pub fn is_error(&self) -> bool { !self.is_success() }
+
+	pub fn write_to(&self, out: &mut ByteStreamOut) { out.write_u32_le(self.0); }
+
+	pub fn read_from(input: &mut ByteStreamIn) -> NexResult<Self> { Ok(QResult(input.read_u32_le()?)) }
+}
@@ -0,0 +1,74 @@
+use crate::io::{ByteStreamIn, ByteStreamOut};
+use crate::types::datetime::DateTime;
+use crate::types::rv_type::RvType;
+use crate::types::station_url::StationUrl;
+use crate::NexResult;
+
+// Implementation of RVConnectionData. Tells a client where the secure server
+// is after logging in. The time is only sent by NEX 3.5 and later, which are
+// also the versions using structure headers, so it is written in version 1 of
+// the structure
+#[derive(Debug, Clone, Default)]
+pub struct RvConnectionData {
+	pub station_url: StationUrl,
+	pub special_protocols: Vec<u8>,
+	pub station_url_special_protocols: StationUrl,
+	pub time: DateTime,
+}
+
+impl RvConnectionData {
+	pub fn write_to(&self, out: &mut ByteStreamOut) {
+		if !out.use_structure_header() { return self.write_content(out, false); }
+		let mut content = out.copy_new();
+		self.write_content(&mut content, true);
+		out.write_u8(1);
+		out.write_u32_le(content.bytes().len() as u32);
+		out.write(content.bytes());
+	}
+
+	pub fn read_from(input: &mut ByteStreamIn) -> NexResult<Self> {
+		if !input.use_structure_header() { return Self::read_content(input, false); }
+		let version = input.read_u8()?;
+		let length = input.read_u32_le()? as usize;
+		let start = input.position();
+		let data = Self::read_content(input, version >= 1)?;
+		// Newer versions may add fields this one does not know about
+		let unread = (start + length).saturating_sub(input.position());
+		input.read(unread as u64)?;
+		Ok(data)
+	}
+
+	fn write_content(&self, out: &mut ByteStreamOut, with_time: bool) {
+		self.station_url.write_to(out);
+		self.special_protocols.write_rv(out);
+		self.station_url_special_protocols.write_to(out);
+		if with_time { self.time.write_to(out); }
+	}
+
+	fn read_content(input: &mut ByteStreamIn, with_time: bool) -> NexResult<Self> {
+		Ok(Self {
+			station_url: StationUrl::extract_from(input)?,
+			special_protocols: Vec::read_rv(input)?,
+			station_url_special_protocols: StationUrl::extract_from(input)?,
+			time: if with_time { DateTime::read_from(input)? } else { DateTime::default() },
+		})
+	}
+}
+
+impl RvType for RvConnectionData {
+	fn write_rv(&self, out: &mut ByteStreamOut) { self.write_to(out); }
+
+	fn read_rv(input: &mut ByteStreamIn) -> NexResult<Self> { Self::read_from(input) }
+
+	fn format_to_string(&self, indentation_level: usize) -> String {
+		let indentation = "\t".repeat(indentation_level + 1);
+		let fields = [
+			("StationURL", self.station_url.format_to_string(indentation_level + 1)),
+			("SpecialProtocols", self.special_protocols.format_to_string(indentation_level + 1)),
+			("StationURLSpecialProtocols", self.station_url_special_protocols.format_to_string(indentation_level + 1)),
+			("Time", self.time.format_to_string(indentation_level + 1)),
+		];
+		let values: String = fields.iter().map(|(name, value)| format!("{indentation}{name}: {value},\n")).collect();
+		format!("RVConnectionData{{\n{values}{}}}", "\t".repeat(indentation_level))
+	}
+}
@@ -0,0 +1,166 @@
+use crate::io::{ByteStreamIn, ByteStreamOut};
+use crate::types::buffer::Buffer;
+use crate::types::class_version_container::ClassVersionContainer;
+use crate::types::datetime::DateTime;
+use crate::types::pid::Pid;
+use crate::types::qresult::QResult;
+use crate::types::station_url::StationUrl;
+use crate::result_codes::result_code_to_name;
+use crate::NexResult;
+
+// A type which can be an RMC parameter or response value. The methods generated
+// by #[nex_protocol] read every parameter and write every response value with it,
+// so protocol specific structures only need to implement it to be used there
+pub trait RvType: Sized {
+	fn write_rv(&self, out: &mut ByteStreamOut);
+
+	fn read_rv(input: &mut ByteStreamIn) -> NexResult<Self>;
+
+	// Go's FormatToString. Nested values are indented with one more tab than the
+	// value holding them. Types which do not format themselves are shown as the
+	// hex of their encoding
+	fn format_to_string(&self, indentation_level: usize) -> String {
+		let _ = indentation_level;
+		let mut out = ByteStreamOut::new(None, None);
+		self.write_rv(&mut out);
+		format!("0x{}", hex(out.bytes()))
+	}
+
+	// The values of the fields which make up the value, one for most types. The
+	// protocol registry lists the response values of a tuple by their position
+	fn format_fields(&self, indentation_level: usize) -> Vec<String> { vec![self.format_to_string(indentation_level)] }
+}
+
+pub(crate) fn hex(data: &[u8]) -> String { data.iter().map(|byte| format!("{byte:02x}")).collect() }
+
+macro_rules! impl_rv_primitive {
+	($($ty:ty => $write:ident, $read:ident;)*) => {$(
+		impl RvType for $ty {
+			fn write_rv(&self, out: &mut ByteStreamOut) { out.$write(*self); }
+
+			fn read_rv(input: &mut ByteStreamIn) -> NexResult<Self> { input.$read() }
+
+			fn format_to_string(&self, _indentation_level: usize) -> String { self.to_string() }
+		}
+	)*};
+}
+
+impl_rv_primitive! {
+	u8 => write_u8, read_u8;
+	u16 => write_u16_le, read_u16_le;
+	u32 => write_u32_le, read_u32_le;
+	u64 => write_u64_le, read_u64_le;
+	i8 => write_i8, read_i8;
+	i16 => write_i16_le, read_i16_le;
+	i32 => write_i32_le, read_i32_le;
+	i64 => write_i64_le, read_i64_le;
+	f32 => write_f32_le, read_f32_le;
+	f64 => write_f64_le, read_f64_le;
+	bool => write_bool, read_bool;
+}
+
+impl RvType for String {
+	fn write_rv(&self, out: &mut ByteStreamOut) { out.write_string(self); }
+
+	fn read_rv(input: &mut ByteStreamIn) -> NexResult<Self> { input.read_string() }
+
+	fn format_to_string(&self, _indentation_level: usize) -> String { format!("{self:?}") }
+}
+
+// A List<T>, with a u32 count prefix
+impl<T: RvType> RvType for Vec<T> {
+	fn write_rv(&self, out: &mut ByteStreamOut) {
+		out.write_u32_le(self.len() as u32);
+		for value in self { value.write_rv(out); }
+	}
+
+	fn read_rv(input: &mut ByteStreamIn) -> NexResult<Self> {
+		let count = input.read_u32_le()?;
+		// The count is not trusted for the allocation, every value takes at least a byte
+		let mut values = Vec::with_capacity((count as usize).min(input.remaining()));
+		for _ in 0..count { values.push(T::read_rv(input)?); }
+		Ok(values)
+	}
+
+	fn format_to_string(&self, indentation_level: usize) -> String {
+		if self.is_empty() { return "[]".into(); }
+		let indentation = "\t".repeat(indentation_level + 1);
+		let values: String = self.iter().map(|value| format!("{indentation}{},\n", value.format_to_string(indentation_level + 1))).collect();
+		format!("[\n{values}{}]", "\t".repeat(indentation_level))
+	}
+}
+
+macro_rules! impl_rv_delegate {
+	($($ty:ty => $read:ident, |$value:ident| $format:expr;)*) => {$(
+		impl RvType for $ty {
+			fn write_rv(&self, out: &mut ByteStreamOut) { self.write_to(out); }
+
+			fn read_rv(input: &mut ByteStreamIn) -> NexResult<Self> { <$ty>::$read(input) }
+
+			fn format_to_string(&self, _indentation_level: usize) -> String {
+				let $value = self;
+				$format
+			}
+		}
+	)*};
+}
+
+impl_rv_delegate! {
+	Buffer => read_from, |buffer| format!("0x{}", hex(&buffer.0));
+	Pid => read_from, |pid| pid.0.to_string();
+	DateTime => read_from, |date| format!("{} ({:04}-{:02}-{:02} {:02}:{:02}:{:02})", date.0, date.year(), date.month(), date.day(), date.hour(), date.minute(), date.second());
+	QResult => read_from, |result| format!("0x{:08X} ({} {})", result.0, if result.is_success() { "success" } else { "error" }, result_code_to_name(result.0));
+	ClassVersionContainer => read_from, |container| format!("{:?}", container.class_versions);
+	StationUrl => extract_from, |url| format!("{url:?}");
+}
+
+// Methods without response values
+impl RvType for () {
+	fn write_rv(&self, _out: &mut ByteStreamOut) {}
+
+	fn read_rv(_input: &mut ByteStreamIn) -> NexResult<Self> { Ok(()) }
+
+	fn format_fields(&self, _indentation_level: usize) -> Vec<String> { Vec::new() }
+}
+
+// Methods with several response values return them as a tuple, written in order
+macro_rules! impl_rv_tuple {
+	($(($($name:ident),+))*) => {$(
+		impl<$($name: RvType),+> RvType for ($($name,)+) {
+			#[allow(non_snake_case)]
+			fn write_rv(&self, out: &mut ByteStreamOut) {
+				let ($($name,)+) = self;
+				$($name.write_rv(out);)+
+			}
+
+			fn read_rv(input: &mut ByteStreamIn) -> NexResult<Self> { Ok(($($name::read_rv(input)?,)+)) }
+
+			fn format_to_string(&self, indentation_level: usize) -> String {
+				let indentation = "\t".repeat(indentation_level + 1);
+				let values: String = self.format_fields(indentation_level + 1).iter().map(|value| format!("{indentation}{value},\n")).collect();
+				format!("(\n{values}{})", "\t".repeat(indentation_level))
+			}
+
+			#[allow(non_snake_case)]
+			fn format_fields(&self, indentation_level: usize) -> Vec<String> {
+				let ($($name,)+) = self;
+				vec![$($name.format_to_string(indentation_level)),+]
+			}
+		}
+	)*};
+}
+
+impl_rv_tuple! {
+	(A)
+	(A, B)
+	(A, B, C)
+	(A, B, C, D)
+	(A, B, C, D, E)
+	(A, B, C, D, E, F)
+	(A, B, C, D, E, F, G)
+	(A, B, C, D, E, F, G, H)
+	(A, B, C, D, E, F, G, H, I)
+	(A, B, C, D, E, F, G, H, I, J)
+	(A, B, C, D, E, F, G, H, I, J, K)
+	(A, B, C, D, E, F, G, H, I, J, K, L)
+}
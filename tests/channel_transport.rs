@@ -0,0 +1,589 @@
+// End to end tests over an in-process channel pair, with a manual clock on both
+// sides so timeouts do not depend on real time
+use nex::account::Account;
+use nex::clock::{Clock, ManualClock};
+use nex::constants::{PrudpPacketFlags, PrudpPacketType, StreamType};
+use nex::error::NexError;
+use nex::io::ByteStreamOut;
+use nex::kerberos::{derive_kerberos_key, KerberosTicket, KerberosTicketInternalData};
+use nex::prudp::client::PrudpClient;
+use nex::prudp::connection::{ConnectionState, PrudpConnection};
+use nex::prudp::endpoint::PrudpEndpoint;
+use nex::prudp::metrics::EndpointMetrics;
+use nex::prudp::notifications::NotificationDelivery;
+use nex::prudp::packet::{PrudpPacket, PRUDP_V0, PRUDP_V1};
+use nex::prudp::server::PrudpServer;
+use nex::prudp::virtual_port::VirtualPort;
+use nex::result_codes::{CORE_EXCEPTION, CORE_NOT_IMPLEMENTED, RENDEZ_VOUS_LIMIT_EXCEEDED, RENDEZ_VOUS_MAX_CONNECTIONS_REACHED};
+use nex::rmc_interceptor::{InterceptorFuture, RmcInterceptor};
+use nex::rmc_message::RmcMessage;
+use nex::rmc_rate_limit::{RateLimitAction, RmcRateLimit};
+use nex::service_protocol::{ProtocolFuture, RmcContext, ServiceProtocol};
+use nex::transport::channel::ChannelTransport;
+use nex::transport::{Datagram, Transport, TransportFuture};
+use nex::types::buffer::Buffer;
+use nex::types::datetime::DateTime;
+use nex::types::pid::Pid;
+use nex::NexResult;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+
+const ECHO_PROTOCOL_ID: u16 = 0x70;
+
+struct Echo;
+
+impl ServiceProtocol for Echo {
+	fn protocol_id(&self) -> u16 { ECHO_PROTOCOL_ID }
+
+	fn has_method(&self, method_id: u32) -> bool { method_id == 1 }
+
+	fn handle<'a>(&'a self, request: &'a RmcMessage, _connection: &'a Arc<PrudpConnection>) -> ProtocolFuture<'a> {
+		Box::pin(async move { Ok(RmcMessage::success(request, request.parameters.clone())) })
+	}
+}
+
+struct Setup {
+	server: Arc<PrudpServer>,
+	endpoint: Arc<PrudpEndpoint>,
+	client: Arc<PrudpClient>,
+	clock: Arc<ManualClock>,
+	server_addr: SocketAddr,
+}
+
+fn setup() -> Setup { setup_with(|_| {}) }
+
+fn setup_with(configure: impl FnOnce(&mut PrudpClient)) -> Setup {
+	let server_addr: SocketAddr = "10.0.0.1:60000".parse().unwrap();
+	let (server_end, client_end) = ChannelTransport::pair(server_addr, "10.0.0.2:50000".parse().unwrap());
+	let clock = Arc::new(ManualClock::new());
+	let (server, endpoint) = serve(Arc::new(server_end), &clock, |_| {});
+
+	let mut client = PrudpClient::new();
+	client.transport = Some(Arc::new(client_end));
+	client.clock = clock.clone();
+	configure(&mut client);
+	Setup { server, endpoint, client: Arc::new(client), clock, server_addr }
+}
+
+// A server with the echo protocol on stream ID 1, listening on transport
+fn serve(transport: Arc<dyn Transport>, clock: &Arc<ManualClock>, configure: impl FnOnce(&mut PrudpEndpoint)) -> (Arc<PrudpServer>, Arc<PrudpEndpoint>) {
+	let mut server = PrudpServer::new();
+	server.clock = clock.clone();
+	let server = Arc::new(server);
+	let mut endpoint = PrudpEndpoint::new(1);
+	configure(&mut endpoint);
+	let endpoint = Arc::new(endpoint);
+	endpoint.register_service_protocol(Arc::new(Echo));
+	server.bind_prudp_endpoint(endpoint.clone()).unwrap();
+	server.listen_transport(transport).unwrap();
+	(server, endpoint)
+}
+
+// Connects several client ends to one server transport. Datagrams from the
+// server are routed to the end with their destination address
+#[derive(Debug)]
+struct Switch {
+	local_addr: SocketAddr,
+	ends: HashMap<SocketAddr, Arc<ChannelTransport>>,
+	incoming: tokio::sync::Mutex<mpsc::UnboundedReceiver<Datagram>>,
+}
+
+impl Switch {
+	fn new(local_addr: SocketAddr, client_addrs: &[SocketAddr]) -> (Self, Vec<ChannelTransport>) {
+		let (sender, incoming) = mpsc::unbounded_channel();
+		let mut ends = HashMap::new();
+		let mut clients = Vec::new();
+		for &client_addr in client_addrs {
+			let (end, client) = ChannelTransport::pair(local_addr, client_addr);
+			let end = Arc::new(end);
+			ends.insert(client_addr, end.clone());
+			clients.push(client);
+			let sender = sender.clone();
+			tokio::spawn(async move {
+				let mut buf = vec![0; 64000];
+				while let Ok((read, from)) = end.recv_from(&mut buf).await {
+					if sender.send((buf[..read].to_vec(), from)).is_err() { break; }
+				}
+			});
+		}
+		(Self { local_addr, ends, incoming: tokio::sync::Mutex::new(incoming) }, clients)
+	}
+}
+
+impl Transport for Switch {
+	fn send_to<'a>(&'a self, data: &'a [u8], address: SocketAddr) -> TransportFuture<'a, NexResult<()>> {
+		Box::pin(async move {
+			if let Some(end) = self.ends.get(&address) { end.send_to(data, address).await?; }
+			Ok(())
+		})
+	}
+
+	fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, NexResult<(usize, SocketAddr)>> {
+		Box::pin(async move {
+			let (data, from) = self.incoming.lock().await.recv().await.ok_or_else(|| NexError::Connection("Switch closed".into()))?;
+			buf[..data.len()].copy_from_slice(&data);
+			Ok((data.len(), from))
+		})
+	}
+
+	fn local_addr(&self) -> NexResult<SocketAddr> { Ok(self.local_addr) }
+}
+
+fn client(transport: Arc<dyn Transport>, clock: &Arc<ManualClock>) -> Arc<PrudpClient> {
+	let mut client = PrudpClient::new();
+	client.transport = Some(transport);
+	client.clock = clock.clone();
+	Arc::new(client)
+}
+
+// Counts the requests left to the data handlers of the endpoint
+fn count_data(endpoint: &PrudpEndpoint) -> Arc<AtomicUsize> {
+	let handled = Arc::new(AtomicUsize::new(0));
+	endpoint.on_data({
+		let handled = handled.clone();
+		move |_packet| {
+			handled.fetch_add(1, Ordering::Relaxed);
+			async {}
+		}
+	});
+	handled
+}
+
+// Waits for the server tasks to get somewhere
+async fn until(condition: impl Fn() -> bool) {
+	tokio::time::timeout(Duration::from_secs(1), async {
+		while !condition() { tokio::task::yield_now().await; }
+	}).await.unwrap();
+}
+
+#[tokio::test]
+async fn handshake_and_rmc_round_trip() {
+	let Setup { server: _server, endpoint, client, server_addr, .. } = setup();
+	client.connect(server_addr).await.unwrap();
+	assert_eq!(client.connection().unwrap().connection_state(), ConnectionState::Connected);
+	assert_eq!(endpoint.connections.len(), 1);
+
+	for i in 0..10u32 {
+		let response = client.call(ECHO_PROTOCOL_ID, 1, i.to_le_bytes().to_vec()).await.unwrap();
+		assert!(response.is_success());
+		assert_eq!(response.method_id, 1);
+		assert_eq!(response.parameters, i.to_le_bytes());
+	}
+
+	let response = client.call(ECHO_PROTOCOL_ID, 2, Vec::new()).await.unwrap();
+	assert!(!response.is_success());
+	assert_eq!(response.result_code().unwrap().code(), CORE_NOT_IMPLEMENTED);
+
+	client.disconnect().await.unwrap();
+	assert!(endpoint.connections.is_empty());
+}
+
+#[tokio::test]
+async fn payloads_larger_than_a_fragment_are_reassembled() {
+	let Setup { server: _server, client, server_addr, .. } = setup();
+	client.connect(server_addr).await.unwrap();
+	let parameters: Vec<u8> = (0..4000u32).map(|i| i as u8).collect();
+	let response = client.call(ECHO_PROTOCOL_ID, 1, parameters.clone()).await.unwrap();
+	assert_eq!(response.parameters, parameters);
+}
+
+#[tokio::test]
+async fn silent_client_is_removed_by_the_heartbeat() {
+	let Setup { server, endpoint, client, clock, server_addr } = setup();
+	client.connect(server_addr).await.unwrap();
+	// The client stops answering pings
+	drop(client);
+
+	clock.advance(Duration::from_secs(11));
+	server.tick().await;
+	assert_eq!(endpoint.connections.len(), 1);
+	clock.advance(Duration::from_secs(11));
+	server.tick().await;
+	assert!(endpoint.connections.is_empty());
+}
+
+#[tokio::test]
+async fn shutdown_waits_for_the_disconnect_ack_until_the_deadline() {
+	let Setup { server, endpoint, client, clock, server_addr } = setup();
+	client.connect(server_addr).await.unwrap();
+	// Nothing acknowledges the DISCONNECT, so only the deadline ends the shutdown
+	drop(client);
+
+	let deadline = clock.now() + Duration::from_secs(5);
+	let shutdown = tokio::spawn({
+		let server = server.clone();
+		async move { server.shutdown(deadline).await }
+	});
+	until(|| endpoint.metrics().totals.outgoing.packets(PrudpPacketType::Disconnect) == 1).await;
+	assert!(!shutdown.is_finished());
+	assert_eq!(endpoint.connections.len(), 1);
+
+	clock.advance(Duration::from_secs(5));
+	tokio::time::timeout(Duration::from_secs(1), shutdown).await.unwrap().unwrap();
+	assert!(endpoint.connections.is_empty());
+}
+
+#[tokio::test]
+async fn bad_checksums_are_counted_by_the_endpoint() {
+	let Setup { server, endpoint, client, server_addr, .. } = setup();
+	let mut packet = PrudpPacket::new(PRUDP_V0, PrudpPacketType::Ping);
+	packet.header.source = VirtualPort::new(StreamType::OldRvSec as u8, 15);
+	packet.header.destination = VirtualPort::new(StreamType::OldRvSec as u8, 1);
+	packet.header.signature = vec![0; 4];
+	let mut data = packet.to_bytes(&server.settings);
+	*data.last_mut().unwrap() ^= 0xFF;
+	// The same packet for a stream ID with no endpoint cannot be attributed
+	let mut unbound = data.clone();
+	unbound[1] = VirtualPort::new(StreamType::OldRvSec as u8, 2).to_byte();
+
+	let transport = client.transport.clone().unwrap();
+	transport.send_to(&data, server_addr).await.unwrap();
+	transport.send_to(&unbound, server_addr).await.unwrap();
+	until(|| endpoint.metrics().totals.dropped_bad_checksum == 1 && server.metrics().dropped_malformed == 1).await;
+}
+
+#[tokio::test]
+async fn requests_answered_by_a_protocol_skip_the_data_handlers() {
+	let Setup { server: _server, endpoint, client, server_addr, .. } = setup();
+	let handled = count_data(&endpoint);
+	client.connect(server_addr).await.unwrap();
+
+	let response = client.call(ECHO_PROTOCOL_ID, 1, vec![1]).await.unwrap();
+	assert!(response.is_success());
+	assert_eq!(handled.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn call_timeout_follows_the_client_clock() {
+	let Setup { server: _server, endpoint, client, clock, server_addr } = setup_with(|client| client.call_timeout = Some(Duration::from_secs(30)));
+	// Requests for protocols which are not registered are left to this handler,
+	// which never answers them
+	let (arrived, mut arrival) = mpsc::unbounded_channel();
+	endpoint.on_data(move |_packet| {
+		let _ = arrived.send(());
+		async {}
+	});
+	client.connect(server_addr).await.unwrap();
+
+	let call = tokio::spawn({
+		let client = client.clone();
+		async move { client.call(ECHO_PROTOCOL_ID + 1, 1, Vec::new()).await }
+	});
+	tokio::time::timeout(Duration::from_secs(1), arrival.recv()).await.unwrap();
+	assert!(!call.is_finished());
+
+	clock.advance(Duration::from_secs(30));
+	let result = tokio::time::timeout(Duration::from_secs(1), call).await.unwrap().unwrap();
+	assert!(matches!(result, Err(NexError::Timeout(_))));
+}
+
+// Rejects every request
+struct Deny;
+
+impl RmcInterceptor for Deny {
+	fn before<'a>(&'a self, _ctx: &'a RmcContext<'a>) -> InterceptorFuture<'a, NexResult<()>> {
+		Box::pin(async { Err(NexError::ResultCode { code: CORE_EXCEPTION, message: "Denied".into() }) })
+	}
+}
+
+#[tokio::test]
+async fn interceptors_stop_requests_left_to_the_data_handlers() {
+	let Setup { server: _server, endpoint, client, server_addr, .. } = setup();
+	let handled = count_data(&endpoint);
+	endpoint.add_rmc_interceptor(Arc::new(Deny));
+	client.connect(server_addr).await.unwrap();
+
+	let response = client.call(ECHO_PROTOCOL_ID + 1, 1, Vec::new()).await.unwrap();
+	assert_eq!(response.result_code().unwrap().code(), CORE_EXCEPTION);
+	assert_eq!(handled.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn offline_notifications_are_capped_and_expire() {
+	let clock = Arc::new(ManualClock::new());
+	let mut server = PrudpServer::new();
+	server.clock = clock.clone();
+	let server = Arc::new(server);
+	let mut endpoint = PrudpEndpoint::new(1);
+	endpoint.offline_notification_limit = 2;
+	endpoint.offline_notification_total_limit = Some(3);
+	endpoint.offline_notification_ttl = Some(Duration::from_secs(60));
+	let endpoint = Arc::new(endpoint);
+	server.bind_prudp_endpoint(endpoint.clone()).unwrap();
+
+	let notification = RmcMessage::request(ECHO_PROTOCOL_ID, 1, Vec::new());
+	for pid in [1, 1, 1, 2] { assert_eq!(endpoint.notify(Pid(pid), &notification).await.unwrap(), NotificationDelivery::Buffered); }
+	assert_eq!(endpoint.notify(Pid(3), &notification).await.unwrap(), NotificationDelivery::Dropped);
+	assert_eq!((endpoint.buffered_notification_count(Pid(1)), endpoint.total_buffered_notification_count()), (2, 3));
+
+	clock.advance(Duration::from_secs(30));
+	assert_eq!(endpoint.notify(Pid(1), &notification).await.unwrap(), NotificationDelivery::Buffered);
+	clock.advance(Duration::from_secs(30));
+	server.tick().await;
+	assert_eq!((endpoint.buffered_notification_count(Pid(1)), endpoint.buffered_notification_count(Pid(2))), (1, 0));
+	assert_eq!(endpoint.total_buffered_notification_count(), 1);
+}
+
+#[tokio::test]
+async fn rate_limits_apply_to_requests_left_to_the_data_handlers() {
+	let Setup { server: _server, endpoint, client, server_addr, .. } = setup();
+	let handled = count_data(&endpoint);
+	endpoint.set_rmc_rate_limit(ECHO_PROTOCOL_ID + 1, Some(1), RmcRateLimit::new(0, 0.0));
+	endpoint.set_rmc_rate_limit(ECHO_PROTOCOL_ID + 1, Some(2), RmcRateLimit { action: RateLimitAction::Disconnect, ..RmcRateLimit::new(0, 0.0) });
+	client.connect(server_addr).await.unwrap();
+
+	let response = tokio::time::timeout(Duration::from_secs(1), client.call(ECHO_PROTOCOL_ID + 1, 1, Vec::new())).await.unwrap().unwrap();
+	assert_eq!(response.result_code().unwrap().code(), RENDEZ_VOUS_LIMIT_EXCEEDED);
+
+	// The DISCONNECT fails the pending call
+	let result = tokio::time::timeout(Duration::from_secs(1), client.call(ECHO_PROTOCOL_ID + 1, 2, Vec::new())).await.unwrap();
+	assert!(matches!(result, Err(NexError::Connection(_))));
+	until(|| endpoint.connections.is_empty()).await;
+	assert_eq!(handled.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn client_can_connect_again_after_disconnecting() {
+	let Setup { server: _server, endpoint, client, server_addr, .. } = setup();
+	for i in 0..2u32 {
+		client.connect(server_addr).await.unwrap();
+		let response = client.call(ECHO_PROTOCOL_ID, 1, i.to_le_bytes().to_vec()).await.unwrap();
+		assert_eq!(response.parameters, i.to_le_bytes());
+		client.disconnect().await.unwrap();
+		assert!(client.connection().is_none());
+		assert!(endpoint.connections.is_empty());
+	}
+}
+
+#[tokio::test]
+async fn client_can_connect_again_after_the_server_disconnects_it() {
+	let Setup { server: _server, endpoint, client, server_addr, .. } = setup();
+	endpoint.set_rmc_rate_limit(ECHO_PROTOCOL_ID, Some(2), RmcRateLimit { action: RateLimitAction::Disconnect, ..RmcRateLimit::new(0, 0.0) });
+	client.connect(server_addr).await.unwrap();
+	assert!(matches!(client.call(ECHO_PROTOCOL_ID, 2, Vec::new()).await, Err(NexError::Connection(_))));
+	assert!(client.connection().is_none());
+
+	client.connect(server_addr).await.unwrap();
+	assert!(client.call(ECHO_PROTOCOL_ID, 1, Vec::new()).await.unwrap().is_success());
+}
+
+// A handshake packet from the client port the PrudpClient uses
+fn handshake_packet(server: &PrudpServer, version: u8, packet_type: PrudpPacketType, connection_signature: &[u8]) -> Vec<u8> {
+	let mut packet = PrudpPacket::new(version, packet_type);
+	packet.add_flag(PrudpPacketFlags::NEEDS_ACK);
+	packet.header.source = VirtualPort::new(StreamType::RvSecure as u8, 15);
+	packet.header.destination = VirtualPort::new(StreamType::RvSecure as u8, 1);
+	packet.header.connection_signature = vec![0; if version == PRUDP_V0 { 4 } else { 16 }];
+	packet.header.signature = packet.calculate_signature(&server.settings, &[], connection_signature);
+	packet.to_bytes(&server.settings)
+}
+
+#[tokio::test]
+async fn v1_connections_are_only_created_by_a_signed_connect() {
+	let server_addr: SocketAddr = "10.0.0.1:60000".parse().unwrap();
+	let (server_end, client_end) = ChannelTransport::pair(server_addr, "10.0.0.2:50000".parse().unwrap());
+	let clock = Arc::new(ManualClock::new());
+	// Verified CONNECTs do not count against the half-open limits
+	let (server, endpoint) = serve(Arc::new(server_end), &clock, |endpoint| endpoint.connection_limits.max_half_open = Some(0));
+	let transport = Arc::new(client_end);
+
+	transport.send_to(&handshake_packet(&server, PRUDP_V1, PrudpPacketType::Syn, &[]), server_addr).await.unwrap();
+	let mut buf = vec![0; 1500];
+	let (read, _) = tokio::time::timeout(Duration::from_secs(1), transport.recv_from(&mut buf)).await.unwrap().unwrap();
+	let syn_ack = PrudpPacket::decode_all(&buf[..read], &server.settings, false).unwrap().remove(0);
+	assert!(syn_ack.is_type(PrudpPacketType::Syn) && syn_ack.is_ack());
+	assert!(endpoint.connections.is_empty());
+
+	// Signed with the wrong connection signature
+	transport.send_to(&handshake_packet(&server, PRUDP_V1, PrudpPacketType::Connect, &[0; 16]), server_addr).await.unwrap();
+	until(|| endpoint.metrics().totals.incoming.packets(PrudpPacketType::Connect) == 1).await;
+	assert_eq!(endpoint.metrics().totals.dropped_bad_signature, 1);
+	assert!(endpoint.connections.is_empty());
+
+	let client = client(transport, &clock);
+	client.connect(server_addr).await.unwrap();
+	assert_eq!(endpoint.connections.len(), 1);
+}
+
+#[tokio::test]
+async fn syns_over_the_rate_limit_are_dropped() {
+	let server_addr: SocketAddr = "10.0.0.1:60000".parse().unwrap();
+	let (server_end, client_end) = ChannelTransport::pair(server_addr, "10.0.0.2:50000".parse().unwrap());
+	let clock = Arc::new(ManualClock::new());
+	let (server, endpoint) = serve(Arc::new(server_end), &clock, |endpoint| endpoint.connection_limits.max_syns_per_second = Some(2));
+	let syns = |metrics: EndpointMetrics| (metrics.totals.incoming.packets(PrudpPacketType::Syn), metrics.totals.outgoing.packets(PrudpPacketType::Syn));
+
+	let syn = handshake_packet(&server, PRUDP_V1, PrudpPacketType::Syn, &[]);
+	for _ in 0..3 { client_end.send_to(&syn, server_addr).await.unwrap(); }
+	until(|| syns(endpoint.metrics()).0 == 3).await;
+	assert_eq!(syns(endpoint.metrics()), (3, 2));
+
+	// The limit is per second of the server clock
+	clock.advance(Duration::from_secs(1));
+	client_end.send_to(&syn, server_addr).await.unwrap();
+	until(|| syns(endpoint.metrics()).0 == 4).await;
+	assert_eq!(syns(endpoint.metrics()), (4, 3));
+}
+
+#[tokio::test]
+async fn connect_over_the_established_limit_is_refused() {
+	let server_addr: SocketAddr = "10.0.0.1:60000".parse().unwrap();
+	let (server_end, client_end) = ChannelTransport::pair(server_addr, "10.0.0.2:50000".parse().unwrap());
+	let clock = Arc::new(ManualClock::new());
+	let (_server, endpoint) = serve(Arc::new(server_end), &clock, |endpoint| endpoint.connection_limits.max_established = Some(0));
+	let (errors, mut error) = mpsc::unbounded_channel();
+	endpoint.on_error(move |err| {
+		let _ = errors.send(err);
+		async {}
+	});
+	let ended = Arc::new(AtomicUsize::new(0));
+	endpoint.on_connection_ended({
+		let ended = ended.clone();
+		move |_connection| {
+			ended.fetch_add(1, Ordering::Relaxed);
+			async {}
+		}
+	});
+
+	let client = client(Arc::new(client_end), &clock);
+	// The server answers the CONNECT with a DISCONNECT
+	assert!(matches!(client.connect(server_addr).await, Err(NexError::Connection(_))));
+	let err = tokio::time::timeout(Duration::from_secs(1), error.recv()).await.unwrap().unwrap();
+	assert!(matches!(err, NexError::ResultCode { code: RENDEZ_VOUS_MAX_CONNECTIONS_REACHED, .. }));
+	assert!(endpoint.connections.is_empty());
+	assert_eq!(ended.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn broadcasts_skip_connections_in_the_handshake() {
+	let server_addr: SocketAddr = "10.0.0.1:60000".parse().unwrap();
+	let client_addrs: [SocketAddr; 2] = ["10.0.0.2:50000".parse().unwrap(), "10.0.0.3:50000".parse().unwrap()];
+	let clock = Arc::new(ManualClock::new());
+	let (switch, mut ends) = Switch::new(server_addr, &client_addrs);
+	let (server, endpoint) = serve(Arc::new(switch), &clock, |_| {});
+	let half_open = Arc::new(ends.pop().unwrap());
+	let connected = client(Arc::new(ends.pop().unwrap()), &clock);
+	let (notifications, mut notification) = mpsc::unbounded_channel();
+	connected.on_notification(move |message| {
+		let _ = notifications.send(message);
+		async {}
+	});
+	connected.connect(server_addr).await.unwrap();
+
+	// The second client connects and leaves, then starts a PRUDPv0 handshake
+	// which it does not finish
+	let other = client(half_open.clone(), &clock);
+	other.connect(server_addr).await.unwrap();
+	other.disconnect().await.unwrap();
+	until(|| endpoint.connections.len() == 1).await;
+	half_open.send_to(&handshake_packet(&server, PRUDP_V0, PrudpPacketType::Syn, &[]), server_addr).await.unwrap();
+	until(|| endpoint.connections.len() == 2).await;
+
+	let summary = endpoint.broadcast(&RmcMessage::request(ECHO_PROTOCOL_ID, 3, vec![1])).await.unwrap();
+	assert!(summary.is_success());
+	assert_eq!((summary.sent, summary.skipped.len()), (1, 1));
+	assert_eq!(summary.skipped[0].address(), client_addrs[1]);
+	let message = tokio::time::timeout(Duration::from_secs(1), notification.recv()).await.unwrap().unwrap();
+	assert_eq!((message.method_id, message.parameters), (3, vec![1]));
+}
+
+#[tokio::test]
+async fn datagrams_from_one_address_are_handled_in_order() {
+	let server_addr: SocketAddr = "10.0.0.1:60000".parse().unwrap();
+	let client_addrs: [SocketAddr; 2] = ["10.0.0.2:50000".parse().unwrap(), "10.0.0.3:50000".parse().unwrap()];
+	let clock = Arc::new(ManualClock::new());
+	let (switch, mut ends) = Switch::new(server_addr, &client_addrs);
+	let (_server, endpoint) = serve(Arc::new(switch), &clock, |_| {});
+	let other = client(Arc::new(ends.pop().unwrap()), &clock);
+	let first = client(Arc::new(ends.pop().unwrap()), &clock);
+
+	// The handler holds on to the first request until it is released
+	let arrived = Arc::new(AtomicUsize::new(0));
+	let release = Arc::new(Notify::new());
+	let (handled, mut order) = mpsc::unbounded_channel();
+	endpoint.on_data({
+		let arrived = arrived.clone();
+		let release = release.clone();
+		move |packet| {
+			arrived.fetch_add(1, Ordering::SeqCst);
+			let release = release.clone();
+			let handled = handled.clone();
+			let parameters = packet.rmc_message.unwrap().parameters;
+			async move {
+				if parameters == [1] { release.notified().await; }
+				let _ = handled.send(parameters[0]);
+			}
+		}
+	});
+	first.connect(server_addr).await.unwrap();
+	other.connect(server_addr).await.unwrap();
+
+	for parameter in [1, 2] {
+		tokio::spawn({
+			let first = first.clone();
+			async move { first.call(ECHO_PROTOCOL_ID + 1, 1, vec![parameter]).await }
+		});
+	}
+	until(|| arrived.load(Ordering::SeqCst) == 1).await;
+	// Other clients are still served, while the second request waits
+	for i in 0..5u8 { assert!(other.call(ECHO_PROTOCOL_ID, 1, vec![i]).await.unwrap().is_success()); }
+	assert_eq!(arrived.load(Ordering::SeqCst), 1);
+
+	release.notify_one();
+	for parameter in [1, 2] { assert_eq!(tokio::time::timeout(Duration::from_secs(1), order.recv()).await.unwrap(), Some(parameter)); }
+}
+
+// A ticket for the secure server account used by secure_endpoint
+fn ticket(pid: Pid) -> KerberosTicket {
+	let session_key = vec![7; 32];
+	let internal_data = KerberosTicketInternalData { issued: DateTime::now(), source_pid: pid, session_key: session_key.clone() };
+	let server_key = derive_kerberos_key(Pid(2), b"password");
+	KerberosTicket { session_key, target_pid: Pid(2), internal_data: Buffer(internal_data.encrypt(&server_key, ByteStreamOut::new(None, None), 0)) }
+}
+
+fn secure_endpoint(endpoint: &mut PrudpEndpoint) {
+	endpoint.is_secure_endpoint = true;
+	endpoint.server_account = Some(Account { pid: Pid(2), username: "Quazal Rendez-Vous".into(), password: "password".into(), requires_token_auth: false });
+}
+
+#[tokio::test]
+async fn offline_notifications_are_delivered_in_order_on_the_next_secure_connect() {
+	let server_addr: SocketAddr = "10.0.0.1:60000".parse().unwrap();
+	let (server_end, client_end) = ChannelTransport::pair(server_addr, "10.0.0.2:50000".parse().unwrap());
+	let clock = Arc::new(ManualClock::new());
+	let (server, endpoint) = serve(Arc::new(server_end), &clock, |endpoint| {
+		secure_endpoint(endpoint);
+		endpoint.offline_notification_limit = 8;
+		endpoint.offline_notification_ttl = Some(Duration::from_secs(60));
+	});
+	let notification = |method_id| RmcMessage::request(ECHO_PROTOCOL_ID, method_id, Vec::new());
+
+	// The first one expires before the user connects, without a timer tick
+	assert_eq!(endpoint.notify(Pid(1000), &notification(1)).await.unwrap(), NotificationDelivery::Buffered);
+	clock.advance(Duration::from_secs(30));
+	for method_id in [2, 3] { assert_eq!(endpoint.notify(Pid(1000), &notification(method_id)).await.unwrap(), NotificationDelivery::Buffered); }
+	clock.advance(Duration::from_secs(30));
+
+	let client = client(Arc::new(client_end), &clock);
+	let (notifications, mut received) = mpsc::unbounded_channel();
+	client.on_notification(move |message| {
+		let _ = notifications.send(message.method_id);
+		async {}
+	});
+	client.connect_secure(server_addr, Pid(1000), &ticket(Pid(1000))).await.unwrap();
+	// Sent directly or kept until the older ones are out, but never before them
+	assert!(matches!(endpoint.notify(Pid(1000), &notification(4)).await.unwrap(), NotificationDelivery::Sent(1) | NotificationDelivery::Buffered));
+
+	// Packets which arrive while the client is still finishing the handshake are
+	// dropped by it, and get through when they are resent
+	clock.advance(Duration::from_secs(2));
+	server.tick().await;
+	let mut method_ids = Vec::new();
+	for _ in 0..3 { method_ids.push(tokio::time::timeout(Duration::from_secs(1), received.recv()).await.unwrap().unwrap()); }
+	assert_eq!(method_ids, [2, 3, 4]);
+	assert_eq!(endpoint.total_buffered_notification_count(), 0);
+}
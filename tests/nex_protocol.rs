@@ -0,0 +1,77 @@
+// Expands #[nex_protocol] on the example from its documentation, and calls the
+// generated protocol over an in-process channel pair
+#![cfg(feature = "macros")]
+
+use nex::io::{ByteStreamIn, ByteStreamOut, ByteStreamSettings};
+use nex::nex_protocol;
+use nex::prudp::client::PrudpClient;
+use nex::prudp::endpoint::PrudpEndpoint;
+use nex::prudp::server::PrudpServer;
+use nex::service_protocol::RmcContext;
+use nex::transport::channel::ChannelTransport;
+use nex::types::buffer::Buffer;
+use nex::types::datetime::DateTime;
+use nex::types::pid::Pid;
+use nex::types::qresult::QResult;
+use nex::types::rv_connection_data::RvConnectionData;
+use nex::types::rv_type::RvType;
+use nex::NexResult;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+#[nex_protocol(id = 0x0A)]
+pub trait TicketGranting: Send + Sync {
+	#[method(1)]
+	async fn login(&self, ctx: &RmcContext<'_>, username: String) -> NexResult<(QResult, Pid, Buffer, RvConnectionData, String)>;
+}
+
+struct Server;
+
+impl TicketGranting for Server {
+	async fn login(&self, _ctx: &RmcContext<'_>, username: String) -> NexResult<(QResult, Pid, Buffer, RvConnectionData, String)> {
+		let connection_data = RvConnectionData { special_protocols: vec![1, 2], time: DateTime::make(2024, 1, 2, 3, 4, 5), ..Default::default() };
+		Ok((QResult(0x10001), Pid(1000), Buffer(vec![0xAA]), connection_data, format!("hello {username}")))
+	}
+}
+
+#[tokio::test]
+async fn generated_protocol_answers_with_rv_connection_data() {
+	let server_addr: SocketAddr = "10.0.0.1:60000".parse().unwrap();
+	let (server_end, client_end) = ChannelTransport::pair(server_addr, "10.0.0.2:50000".parse().unwrap());
+	let server = Arc::new(PrudpServer::new());
+	let endpoint = Arc::new(PrudpEndpoint::new(1));
+	endpoint.register_service_protocol(Arc::new(TicketGrantingProtocol::new(Server)));
+	server.bind_prudp_endpoint(endpoint).unwrap();
+	server.listen_transport(Arc::new(server_end)).unwrap();
+
+	let mut client = PrudpClient::new();
+	client.transport = Some(Arc::new(client_end));
+	let client = Arc::new(client);
+	client.connect(server_addr).await.unwrap();
+
+	let mut parameters = ByteStreamOut::new(None, None);
+	"player".to_string().write_rv(&mut parameters);
+	let response = client.call(TicketGrantingProtocol::<Server>::PROTOCOL_ID, TicketGrantingProtocol::<Server>::METHOD_LOGIN, parameters.bytes().to_vec()).await.unwrap();
+	assert!(response.is_success());
+
+	let mut input = ByteStreamIn::new(response.parameters, None, None);
+	let (result, pid, buffer, connection_data, message) = <(QResult, Pid, Buffer, RvConnectionData, String)>::read_rv(&mut input).unwrap();
+	assert_eq!((result.0, pid.0, buffer.0), (0x10001, 1000, vec![0xAA]));
+	assert_eq!(connection_data.special_protocols, [1, 2]);
+	assert_eq!(message, "hello player");
+	assert_eq!(input.remaining(), 0);
+}
+
+#[test]
+fn rv_connection_data_sends_the_time_with_a_structure_header() {
+	let settings = ByteStreamSettings { use_structure_header: true, ..Default::default() };
+	let connection_data = RvConnectionData { special_protocols: vec![3], time: DateTime::make(2024, 1, 2, 3, 4, 5), ..Default::default() };
+	let mut out = ByteStreamOut::new(None, Some(settings.clone()));
+	connection_data.write_rv(&mut out);
+	assert_eq!(out.bytes()[0], 1);
+
+	let mut input = ByteStreamIn::new(out.bytes().to_vec(), None, Some(settings));
+	let read = RvConnectionData::read_rv(&mut input).unwrap();
+	assert_eq!((read.special_protocols, read.time), (vec![3], connection_data.time));
+	assert_eq!(input.remaining(), 0);
+}
@@ -0,0 +1,85 @@
+// The readable form of RMC messages, with and without a protocol registry
+use nex::protocol_registry::{MethodInfo, ProtocolInfo, ProtocolRegistry};
+use nex::rmc_format::RmcMessageJson;
+use nex::rmc_message::RmcMessage;
+
+#[test]
+fn packed_messages_show_their_ids_and_a_hex_dump() {
+	let mut request = RmcMessage::request(0x0A, 1, b"0123456789abcdef\x00\x01hi".to_vec());
+	request.call_id = 7;
+	assert_eq!(request.to_string(), "\
+RMCMessage{
+	Request: packed,
+	Protocol: 10,
+	Method: 1,
+	CallID: 7,
+	Parameters (20 bytes):
+		0000  30 31 32 33 34 35 36 37 38 39 61 62 63 64 65 66  |0123456789abcdef|
+		0010  00 01 68 69                                      |..hi|
+}");
+}
+
+#[test]
+fn verbose_messages_show_only_their_names() {
+	let mut request = RmcMessage::request(0, 0, Vec::new());
+	request.verbose_mode = true;
+	request.protocol_name = "TicketGranting".into();
+	request.method_name = "Login".into();
+	assert_eq!(request.to_string(), "\
+RMCMessage{
+	Request: verbose,
+	Protocol: TicketGranting,
+	Method: Login,
+	CallID: 0,
+	Parameters (0 bytes):
+}");
+}
+
+#[test]
+fn error_responses_show_the_result_instead_of_parameters() {
+	let mut request = RmcMessage::request(0x0A, 1, Vec::new());
+	request.call_id = 7;
+	let response = RmcMessage::error(&request, 0x00010002);
+	assert_eq!(response.to_string(), "\
+RMCMessage{
+	Response: packed,
+	Protocol: 10,
+	Method: 1,
+	CallID: 7,
+	Result: Core::NotImplemented (0x80010002),
+}");
+}
+
+#[test]
+fn registry_names_and_decodes_the_parameters() {
+	let mut registry = ProtocolRegistry::new();
+	registry.register(ProtocolInfo::new(0x0A, "TicketGranting").method(MethodInfo::new(1, "Login").request::<(String, u32)>(&["username", "count"])));
+	let mut request = RmcMessage::request(0x0A, 1, vec![7, 0, b'p', b'l', b'a', b'y', b'e', b'r', 0, 3, 0, 0, 0]);
+	request.call_id = 7;
+	assert_eq!(registry.format_message(&request), "\
+RMCMessage{
+	Request: packed,
+	Protocol: TicketGranting (10),
+	Method: Login (1),
+	CallID: 7,
+	Parameters: {
+		username: \"player\",
+		count: 3,
+	},
+}");
+	assert_eq!(registry.format_json(&request), r#"{"request":true,"verbose":false,"hpp":false,"protocol_id":10,"protocol_name":"TicketGranting","method_id":1,"method_name":"Login","call_id":7,"parameters":"0700706c617965720003000000"}"#);
+
+	// Parameters which do not match the method are dumped instead
+	let truncated = RmcMessage::request(0x0A, 1, vec![7, 0, b'p']);
+	let text = registry.format_message(&truncated);
+	assert!(text.contains("\tParameters (3 bytes, not decoded: "), "{text}");
+	assert!(text.ends_with("\t\t0000  07 00 70                                         |..p|\n}"), "{text}");
+}
+
+#[test]
+fn json_view_has_the_fields_of_the_json_line() {
+	let request = RmcMessage::request(0x0A, 1, vec![0xDE, 0xAD]);
+	let view: RmcMessageJson = request.json_view();
+	assert_eq!((view.request, view.protocol_id, view.method_id, view.parameters.as_str()), (true, 0x0A, 1, "dead"));
+	assert_eq!((view.success, view.error_code, view.result), (None, None, None));
+}
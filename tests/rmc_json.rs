@@ -0,0 +1,22 @@
+// RmcMessage::to_json writes the same JSON lines with and without the serde
+// feature, so this runs under both
+use nex::rmc_message::RmcMessage;
+
+#[test]
+fn requests_and_error_responses_are_written_as_json_lines() {
+	let mut request = RmcMessage::request(0x0A, 1, vec![0xDE, 0xAD]);
+	request.call_id = 7;
+	assert_eq!(request.to_json(), r#"{"request":true,"verbose":false,"hpp":false,"protocol_id":10,"method_id":1,"call_id":7,"parameters":"dead"}"#);
+
+	let response = RmcMessage::error(&request, 0x00010002);
+	assert_eq!(response.to_json(), r#"{"request":false,"verbose":false,"hpp":false,"protocol_id":10,"method_id":1,"call_id":7,"success":false,"error_code":2147549186,"result":"Core::NotImplemented","parameters":""}"#);
+}
+
+#[test]
+fn verbose_names_are_escaped() {
+	let mut request = RmcMessage::request(0, 0, Vec::new());
+	request.verbose_mode = true;
+	request.protocol_name = "Quote\"d".into();
+	request.method_name = "Tab\t".into();
+	assert_eq!(request.to_json(), r#"{"request":true,"verbose":true,"hpp":false,"protocol_id":0,"protocol_name":"Quote\"d","method_id":0,"method_name":"Tab\t","call_id":0,"parameters":""}"#);
+}
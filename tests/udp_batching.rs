@@ -0,0 +1,56 @@
+// Batched sends over real UDP sockets on the loopback interface, where
+// send_batch uses sendmmsg
+#![cfg(target_os = "linux")]
+
+use nex::transport::batching::BatchingTransport;
+use nex::transport::{Datagram, Transport};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+// An IPv4 socket cannot send to an IPv6 address, so sendmmsg fails on it
+const UNSENDABLE: &str = "[::1]:1";
+
+async fn bind() -> UdpSocket { UdpSocket::bind("127.0.0.1:0").await.unwrap() }
+
+// The first byte of every datagram read, in the order they arrived
+async fn receive(socket: &UdpSocket, count: usize) -> Vec<u8> {
+	let mut bufs = vec![vec![0; 64]; 4];
+	let mut received = Vec::new();
+	let mut first_bytes = Vec::new();
+	while first_bytes.len() < count {
+		received.clear();
+		tokio::time::timeout(Duration::from_secs(1), socket.recv_batch(&mut bufs, &mut received)).await.unwrap().unwrap();
+		first_bytes.extend(bufs.iter().zip(&received).map(|(buf, _)| buf[0]));
+	}
+	first_bytes
+}
+
+fn datagrams(receiver: SocketAddr) -> Vec<Datagram> {
+	vec![(vec![1], receiver), (vec![2], UNSENDABLE.parse().unwrap()), (vec![3], receiver)]
+}
+
+#[tokio::test]
+async fn failing_datagram_is_skipped_by_sendmmsg() {
+	let (sender, receiver) = (bind().await, bind().await);
+	assert!(sender.send_batch(&datagrams(receiver.local_addr().unwrap())).await.is_err());
+	assert_eq!(receive(&receiver, 2).await, [1, 3]);
+}
+
+#[tokio::test]
+async fn batching_writer_failure_is_reported_by_the_next_send() {
+	let receiver = bind().await;
+	let receiver_addr = receiver.local_addr().unwrap();
+	let transport = BatchingTransport::new(Arc::new(bind().await), 8);
+	// Queued for the writer, which only fails after this returns
+	transport.send_batch(&datagrams(receiver_addr)).await.unwrap();
+	assert_eq!(receive(&receiver, 2).await, [1, 3]);
+	tokio::task::yield_now().await;
+
+	assert!(transport.send_to(&[4], receiver_addr).await.is_err());
+	assert_eq!(receive(&receiver, 1).await, [4]);
+	// The failure is only reported once
+	transport.send_to(&[5], receiver_addr).await.unwrap();
+	assert_eq!(receive(&receiver, 1).await, [5]);
+}
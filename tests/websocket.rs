@@ -0,0 +1,42 @@
+// PRUDPLite over a real WebSocket on the loopback interface
+#![cfg(feature = "websocket")]
+
+use futures_util::{SinkExt, StreamExt};
+use nex::constants::{PrudpPacketFlags, PrudpPacketType, StreamType};
+use nex::prudp::endpoint::PrudpEndpoint;
+use nex::prudp::packet::{PrudpPacket, PRUDP_LITE};
+use nex::prudp::server::PrudpServer;
+use nex::prudp::virtual_port::VirtualPort;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message;
+
+#[tokio::test]
+async fn lite_handshake_over_websocket() {
+	let server = Arc::new(PrudpServer::new());
+	let endpoint = Arc::new(PrudpEndpoint::new(1));
+	server.bind_prudp_endpoint(endpoint.clone()).unwrap();
+	// The server listens on every interface, so a free port is looked up first
+	let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+	server.listen_websocket(port).await.unwrap();
+	let (mut websocket, _) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{port}")).await.unwrap();
+
+	let mut syn = PrudpPacket::new(PRUDP_LITE, PrudpPacketType::Syn);
+	syn.add_flag(PrudpPacketFlags::NEEDS_ACK);
+	syn.header.source = VirtualPort::new(StreamType::RvSecure as u8, 15);
+	syn.header.destination = VirtualPort::new(StreamType::RvSecure as u8, 1);
+	websocket.send(Message::Binary(syn.to_bytes(&server.settings))).await.unwrap();
+
+	let message = tokio::time::timeout(Duration::from_secs(1), websocket.next()).await.unwrap().unwrap().unwrap();
+	let Message::Binary(data) = message else { panic!("expected a binary message, got {message:?}") };
+	let syn_ack = PrudpPacket::decode_all(&data, &server.settings, true).unwrap().remove(0);
+	assert!(syn_ack.is_type(PrudpPacketType::Syn) && syn_ack.is_ack());
+	assert_eq!(syn_ack.header.destination, VirtualPort::new(StreamType::RvSecure as u8, 15));
+	assert_eq!(endpoint.connections.len(), 1);
+
+	// Closing the socket removes its connections
+	websocket.close(None).await.unwrap();
+	tokio::time::timeout(Duration::from_secs(1), async {
+		while !endpoint.connections.is_empty() { tokio::task::yield_now().await; }
+	}).await.unwrap();
+}